@@ -0,0 +1,173 @@
+use std::net::SocketAddr;
+use std::time::{Duration, SystemTime};
+
+use bevy_renet::renet::transport::{generate_random_bytes, ConnectToken, NETCODE_KEY_BYTES};
+
+use crate::identity::Identity;
+use crate::replicate::PROTOCOL_ID;
+
+/// The private key as 64 hex digits. Setting it makes the server only accept clients with a connect
+/// token signed by it. Only works over UDP, as WebSocket clients can not present a token.
+pub const PRIVATE_KEY_VAR: &str = "MP_PRIVATE_KEY";
+/// Path to a connect token written by `mp token`
+pub const CONNECT_TOKEN_VAR: &str = "MP_CONNECT_TOKEN";
+
+pub const TOKEN_EXPIRE_SECONDS: u64 = 300;
+/// Seconds without packets before netcode drops the connection
+pub const CONNECTION_TIMEOUT_SECONDS: i32 = 15;
+
+pub type PrivateKey = [u8; NETCODE_KEY_BYTES];
+
+pub fn generate_private_key() -> PrivateKey {
+    generate_random_bytes()
+}
+
+pub fn private_key_to_hex(key: &PrivateKey) -> String {
+    key.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn parse_private_key(hex: &str) -> Result<PrivateKey, String> {
+    let hex = hex.trim();
+    if hex.len() != 2 * NETCODE_KEY_BYTES || !hex.is_ascii() {
+        return Err(format!(
+            "Expected {} hex digits, got '{hex}'",
+            2 * NETCODE_KEY_BYTES
+        ));
+    }
+
+    let mut key = [0; NETCODE_KEY_BYTES];
+    for (byte, digits) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte =
+            u8::from_str_radix(digits, 16).map_err(|e| format!("Invalid hex '{digits}': {e}"))?;
+    }
+
+    Ok(key)
+}
+
+/// The private key from [`PRIVATE_KEY_VAR`], if secure mode is enabled
+pub fn private_key() -> Option<PrivateKey> {
+    let hex = std::env::var(PRIVATE_KEY_VAR).ok()?;
+
+    Some(parse_private_key(&hex).unwrap_or_else(|e| panic!("Invalid {PRIVATE_KEY_VAR}: {e}")))
+}
+
+pub fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+}
+
+/// Issues a token that lets `client_id` connect to any of `server_addresses` as `identity`.
+/// The servers must use the same private key.
+pub fn issue_connect_token(
+    private_key: &PrivateKey,
+    client_id: u64,
+    identity: &Identity,
+    server_addresses: Vec<SocketAddr>,
+    current_time: Duration,
+) -> Result<ConnectToken, String> {
+    let user_data = identity.to_user_data()?;
+
+    ConnectToken::generate(
+        current_time,
+        PROTOCOL_ID,
+        TOKEN_EXPIRE_SECONDS,
+        client_id,
+        CONNECTION_TIMEOUT_SECONDS,
+        server_addresses,
+        Some(&user_data),
+        private_key,
+    )
+    .map_err(|e| format!("Could not generate connect token: {e:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::Instant;
+
+    use bevy::prelude::*;
+    use bevy_renet::renet::transport::{
+        ClientAuthentication, NetcodeClientTransport, NetcodeServerTransport, ServerAuthentication,
+        ServerConfig,
+    };
+    use bevy_renet::renet::{ClientId, ConnectionConfig, RenetClient, RenetServer};
+    use bevy_renet::transport::{NetcodeClientPlugin, NetcodeServerPlugin};
+    use bevy_renet::{RenetClientPlugin, RenetServerPlugin};
+
+    use super::*;
+
+    fn identity() -> Identity {
+        Identity {
+            name: "Alice".to_string(),
+            color: [1.0, 0.5, 0.0],
+            loadout: default(),
+        }
+    }
+
+    #[test]
+    fn private_key_hex() {
+        let key = generate_private_key();
+        assert_eq!(parse_private_key(&private_key_to_hex(&key)), Ok(key));
+
+        assert!(parse_private_key("abc").is_err());
+        assert!(parse_private_key(&"zz".repeat(NETCODE_KEY_BYTES)).is_err());
+    }
+
+    #[test]
+    fn secure_connection() {
+        let private_key = generate_private_key();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let server_addr = socket.local_addr().unwrap();
+
+        let server_transport = NetcodeServerTransport::new(
+            ServerConfig {
+                current_time: now(),
+                max_clients: 4,
+                protocol_id: PROTOCOL_ID,
+                public_addresses: vec![server_addr],
+                authentication: ServerAuthentication::Secure { private_key },
+            },
+            socket,
+        )
+        .unwrap();
+        let mut server = App::new();
+        server
+            .add_plugins((MinimalPlugins, RenetServerPlugin, NetcodeServerPlugin))
+            .insert_resource(RenetServer::new(ConnectionConfig::default()))
+            .insert_resource(server_transport);
+
+        let token =
+            issue_connect_token(&private_key, 42, &identity(), vec![server_addr], now()).unwrap();
+        let client_transport = NetcodeClientTransport::new(
+            now(),
+            ClientAuthentication::Secure {
+                connect_token: token,
+            },
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+        )
+        .unwrap();
+        let mut client = App::new();
+        client
+            .add_plugins((MinimalPlugins, RenetClientPlugin, NetcodeClientPlugin))
+            .insert_resource(RenetClient::new(ConnectionConfig::default()))
+            .insert_resource(client_transport);
+
+        let start = Instant::now();
+        while !client.world.resource::<RenetClient>().is_connected() {
+            assert!(start.elapsed() < Duration::from_secs(2), "Timed out");
+            client.update();
+            server.update();
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        let user_data = server
+            .world
+            .resource::<NetcodeServerTransport>()
+            .user_data(ClientId::from_raw(42))
+            .unwrap();
+        assert_eq!(Identity::from_user_data(&user_data), Some(identity()));
+    }
+}
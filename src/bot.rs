@@ -0,0 +1,167 @@
+use std::sync::{Arc, Mutex};
+
+use bevy::prelude::*;
+use clap::ValueEnum;
+use leafwing_input_manager::axislike::DualAxisData;
+use leafwing_input_manager::prelude::*;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::player::{Action, Control};
+use crate::prediction::{resimulating, CommitActions, PredictionStats};
+use crate::replicate::schedule::NetworkPreUpdate;
+use crate::replicate::NetworkTick;
+use crate::transport::{ClientConnection, ConnectionState};
+
+/// Ticks between the decisions of a bot
+const DECISION_TICKS: u64 = 30;
+/// Wandering bots turn back when they get this far from the center
+const ARENA_SIZE: f32 = 5.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Behavior {
+    /// Walk in random directions and shoot at random spots
+    Wander,
+    /// Walk in a square and shoot at the center once per lap
+    Square,
+}
+
+/// What a bot shares with the thread that reports on it
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct BotStatus {
+    pub state: Option<ConnectionState>,
+    /// Round trip time in seconds
+    pub rtt: f64,
+    pub prediction: PredictionStats,
+}
+
+/// Plays the controlled player instead of the keyboard and mouse
+pub struct BotPlugin {
+    pub behavior: Behavior,
+    pub seed: u64,
+    pub status: Arc<Mutex<BotStatus>>,
+}
+
+#[derive(Resource)]
+struct Bot {
+    behavior: Behavior,
+    rng: StdRng,
+    moves: Vec<Action>,
+    target: Vec2,
+    shoot: bool,
+}
+
+#[derive(Resource, Deref)]
+struct SharedStatus(Arc<Mutex<BotStatus>>);
+
+impl Plugin for BotPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Bot {
+            behavior: self.behavior,
+            rng: StdRng::seed_from_u64(self.seed),
+            moves: vec![],
+            target: Vec2::ZERO,
+            shoot: false,
+        })
+        .insert_resource(SharedStatus(self.status.clone()))
+        .add_systems(
+            NetworkPreUpdate,
+            (ignore_input_map, drive_bot)
+                .chain()
+                .run_if(not(resimulating))
+                .before(CommitActions),
+        )
+        .add_systems(Last, share_status);
+    }
+}
+
+/// Without an input map the keyboard can not overwrite what the bot does
+fn ignore_input_map(
+    mut commands: Commands,
+    players: Query<Entity, (With<Control>, With<InputMap<Action>>)>,
+) {
+    for entity in &players {
+        commands.entity(entity).remove::<InputMap<Action>>();
+    }
+}
+
+fn drive_bot(
+    mut bot: ResMut<Bot>,
+    mut players: Query<(&Transform, &mut ActionState<Action>), With<Control>>,
+    tick: Res<NetworkTick>,
+) {
+    let Ok((transform, mut actions)) = players.get_single_mut() else {
+        return;
+    };
+
+    let decide = tick.0.is_multiple_of(DECISION_TICKS);
+    if decide {
+        bot.decide(tick.0 / DECISION_TICKS, transform.translation.xy());
+    }
+
+    actions.release_all();
+    for action in &bot.moves {
+        actions.press(action.clone());
+    }
+    if decide && bot.shoot {
+        actions.press(Action::Shoot);
+    }
+
+    let target = Some(DualAxisData::from_xy(bot.target));
+    actions.action_data_mut(Action::Main).axis_pair = target;
+    actions.action_data_mut(Action::Shoot).axis_pair = target;
+}
+
+impl Bot {
+    fn decide(&mut self, decision: u64, position: Vec2) {
+        match self.behavior {
+            Behavior::Wander => {
+                let rng = &mut self.rng;
+                self.moves = [Action::Up, Action::Down, Action::Left, Action::Right]
+                    .into_iter()
+                    .filter(|_| rng.gen_bool(0.3))
+                    .collect();
+
+                if position.x.abs() > ARENA_SIZE || position.y.abs() > ARENA_SIZE {
+                    self.moves = vec![
+                        if position.x > 0.0 {
+                            Action::Left
+                        } else {
+                            Action::Right
+                        },
+                        if position.y > 0.0 {
+                            Action::Down
+                        } else {
+                            Action::Up
+                        },
+                    ];
+                }
+
+                self.target = Vec2::new(
+                    rng.gen_range(-ARENA_SIZE..ARENA_SIZE),
+                    rng.gen_range(-ARENA_SIZE..ARENA_SIZE),
+                );
+                self.shoot = rng.gen_bool(0.5);
+            }
+            Behavior::Square => {
+                let sides = [Action::Up, Action::Right, Action::Down, Action::Left];
+                let side = (decision % 4) as usize;
+                self.moves = vec![sides[side].clone()];
+                self.target = Vec2::ZERO;
+                self.shoot = side == 0;
+            }
+        }
+    }
+}
+
+fn share_status(
+    status: Res<SharedStatus>,
+    connection: Option<Res<ClientConnection>>,
+    prediction: Res<PredictionStats>,
+) {
+    *status.lock().unwrap() = BotStatus {
+        state: connection.as_ref().map(|connection| connection.state),
+        rtt: connection.map_or(0.0, |connection| connection.stats.rtt),
+        prediction: *prediction,
+    };
+}
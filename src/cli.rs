@@ -0,0 +1,450 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy::window::WindowResolution;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use crate::bot::Behavior;
+use crate::game::DEFAULT_TICK_RATE;
+use crate::transport::conditioner::LinkConditionerConfig;
+
+#[derive(Debug, Parser)]
+#[command(about = "Making a game in Rust with Bevy")]
+pub struct Cli {
+    /// Hosts a server with two clients if left out
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    /// Run a server and start clients on this machine that connect to it
+    Host(HostArgs),
+    /// Run only the server
+    Server(ServerArgs),
+    /// Connect to a server
+    Client(ClientArgs),
+    /// Run headless clients that play on their own, to load test a server
+    Bots(BotsArgs),
+    /// Play back a match recorded with `--record`
+    Replay(ReplayArgs),
+    /// Print a new private key for secure mode
+    Keygen,
+    /// Write a connect token signed with the key in `MP_PRIVATE_KEY`
+    Token(TokenArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct HostArgs {
+    /// How many clients to start
+    #[arg(long, default_value_t = 2)]
+    pub clients: i32,
+    #[command(flatten)]
+    pub server: ServerArgs,
+}
+
+impl Default for HostArgs {
+    /// What running `mp` without a subcommand does
+    fn default() -> Self {
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            args: HostArgs,
+        }
+
+        Defaults::parse_from(["mp"]).args
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ServerArgs {
+    /// Address to listen on
+    #[arg(long, default_value = "0.0.0.0")]
+    pub bind: IpAddr,
+    #[arg(long, default_value_t = 5000)]
+    pub port: u16,
+    /// Address that clients connect to, can be given more than once. Defaults to localhost.
+    #[arg(long = "public-addr")]
+    pub public_addresses: Vec<SocketAddr>,
+    #[arg(long, default_value_t = 64)]
+    pub max_clients: usize,
+    /// Run without a window, for machines with no display or GPU
+    #[arg(long)]
+    pub headless: bool,
+    /// Record the match to this replay file
+    #[arg(long)]
+    pub record: Option<PathBuf>,
+    /// Client that may use the debug controls too, can be given more than once
+    #[arg(long = "debug-client")]
+    pub debug_clients: Vec<u64>,
+    #[command(flatten)]
+    pub game: GameArgs,
+    #[command(flatten)]
+    pub conditioner: ConditionerArgs,
+    #[command(flatten)]
+    pub window: WindowArgs,
+}
+
+impl ServerArgs {
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.bind, self.port)
+    }
+
+    pub fn public_addresses(&self) -> Vec<SocketAddr> {
+        match self.public_addresses.is_empty() {
+            true => vec![SocketAddr::from(([127, 0, 0, 1], self.port))],
+            false => self.public_addresses.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ClientArgs {
+    /// Which of the client windows this is, from right to left
+    #[arg(default_value_t = 1)]
+    pub index: i32,
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    pub server_addr: SocketAddr,
+    /// Exit when the server shuts down, like the clients started by `host` do
+    #[arg(long)]
+    pub exit_with_server: bool,
+    #[command(flatten)]
+    pub game: GameArgs,
+    #[command(flatten)]
+    pub conditioner: ConditionerArgs,
+    #[command(flatten)]
+    pub window: WindowArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct BotsArgs {
+    #[arg(default_value_t = 8)]
+    pub count: u32,
+    /// Spread the bots over this many processes
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u32).range(1..))]
+    pub processes: u32,
+    /// Number of the first bot, to tell the bots of several processes apart
+    #[arg(long, default_value_t = 1)]
+    pub first_index: u32,
+    #[arg(long, value_enum, default_value_t = Behavior::Wander)]
+    pub behavior: Behavior,
+    /// Seconds between reports on every bot
+    #[arg(long, default_value_t = 5.0)]
+    pub report_interval: f32,
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    pub server_addr: SocketAddr,
+    #[command(flatten)]
+    pub game: GameArgs,
+}
+
+impl BotsArgs {
+    /// The arguments for each of the processes to run its share of the bots in
+    pub fn split(&self) -> Vec<BotsArgs> {
+        let mut first_index = self.first_index;
+
+        (0..self.processes)
+            .map(|process| {
+                let count =
+                    self.count / self.processes + u32::from(process < self.count % self.processes);
+                let args = BotsArgs {
+                    count,
+                    processes: 1,
+                    first_index,
+                    ..self.clone()
+                };
+                first_index += count;
+                args
+            })
+            .filter(|args| args.count > 0)
+            .collect()
+    }
+
+    pub fn to_args(&self) -> Vec<String> {
+        vec![
+            "bots".to_string(),
+            format!("{}", self.count),
+            format!("--processes={}", self.processes),
+            format!("--first-index={}", self.first_index),
+            format!(
+                "--behavior={}",
+                self.behavior.to_possible_value().unwrap().get_name()
+            ),
+            format!("--report-interval={}", self.report_interval),
+            format!("--server-addr={}", self.server_addr),
+            format!("--tick-rate={}", self.game.tick_rate),
+        ]
+        .into_iter()
+        .chain(
+            self.game
+                .debug_controls
+                .then(|| "--debug-controls".to_string()),
+        )
+        .collect()
+    }
+}
+
+/// Space pauses, the arrow keys seek and change the speed and home starts over
+#[derive(Debug, Clone, Args)]
+pub struct ReplayArgs {
+    pub file: PathBuf,
+    #[command(flatten)]
+    pub window: WindowArgs,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct GameArgs {
+    /// Simulation ticks per second, which has to be the same on the server and all clients
+    #[arg(
+        long,
+        default_value_t = DEFAULT_TICK_RATE,
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub tick_rate: u32,
+    /// Pause, step and rewind every peer with F5, F6 and F7, for debugging
+    #[arg(long)]
+    pub debug_controls: bool,
+}
+
+/// Sends the connection through a UDP proxy that simulates a bad network. Netcode only accepts it
+/// if the server lists the proxy among its public addresses, which a server running the proxy does.
+#[derive(Debug, Clone, Args)]
+pub struct ConditionerArgs {
+    /// How bad the link is, e.g. `latency=100,jitter=20,loss=0.05,duplicate=0.01,reorder=0.01`
+    #[arg(long)]
+    pub link_conditioner: Option<LinkConditionerConfig>,
+    /// Address the proxy listens on
+    #[arg(long, default_value = "127.0.0.1:5001")]
+    pub conditioner_addr: SocketAddr,
+}
+
+/// The server window is centered on the monitor at half its size, and the client windows line the
+/// top edge at a quarter of its size
+#[derive(Debug, Clone, Args)]
+pub struct WindowArgs {
+    #[arg(long, default_value_t = 2560.0)]
+    pub monitor_width: f32,
+    #[arg(long, default_value_t = 1440.0)]
+    pub monitor_height: f32,
+    /// Position of the monitor's top left corner, to put the windows on another screen
+    #[arg(long, default_value_t = 0)]
+    pub monitor_x: i32,
+    #[arg(long, default_value_t = 0)]
+    pub monitor_y: i32,
+}
+
+impl WindowArgs {
+    pub fn server_window(&self) -> (WindowPosition, WindowResolution) {
+        let width = self.monitor_width / 2.0;
+        let height = self.monitor_height / 2.0;
+        let position = IVec2::new(
+            self.monitor_x + (self.monitor_width - width) as i32 / 2,
+            self.monitor_y + (self.monitor_height - height) as i32 / 2,
+        );
+
+        self.window(position, width, height)
+    }
+
+    pub fn client_window(&self, index: i32) -> (WindowPosition, WindowResolution) {
+        let width = self.monitor_width / 4.0;
+        let height = self.monitor_height / 4.0;
+        let position = IVec2::new(
+            self.monitor_x + self.monitor_width as i32 / 2 - width as i32 * (index - 1),
+            self.monitor_y,
+        );
+
+        self.window(position, width, height)
+    }
+
+    fn window(
+        &self,
+        position: IVec2,
+        width: f32,
+        height: f32,
+    ) -> (WindowPosition, WindowResolution) {
+        (
+            WindowPosition::At(position),
+            WindowResolution::new(width, height).with_scale_factor_override(1.0),
+        )
+    }
+
+    /// The flags that recreate these settings, to pass them on to clients
+    pub fn to_args(&self) -> Vec<String> {
+        vec![
+            format!("--monitor-width={}", self.monitor_width),
+            format!("--monitor-height={}", self.monitor_height),
+            format!("--monitor-x={}", self.monitor_x),
+            format!("--monitor-y={}", self.monitor_y),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TokenArgs {
+    pub client_id: u64,
+    pub name: String,
+    /// The avatar color as r,g,b between 0 and 1
+    #[arg(long, value_parser = parse_color)]
+    pub color: [f32; 3],
+    /// Where to write the token
+    pub file: PathBuf,
+    /// The server the token is for
+    #[arg(long, default_value = "127.0.0.1:5000")]
+    pub server_addr: SocketAddr,
+}
+
+fn parse_color(s: &str) -> Result<[f32; 3], String> {
+    let channels: Vec<f32> = s
+        .split(',')
+        .map(|c| c.trim().parse().map_err(|e| format!("{c}: {e}")))
+        .collect::<Result<_, _>>()?;
+
+    channels
+        .try_into()
+        .map_err(|channels: Vec<f32>| format!("Expected 3 channels, got {}", channels.len()))
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::CommandFactory;
+    use itertools::Itertools;
+
+    use super::*;
+
+    #[test]
+    fn verify_cli() {
+        Cli::command().debug_assert();
+    }
+
+    #[test]
+    fn defaults() {
+        let host = HostArgs::default();
+        assert_eq!(host.clients, 2);
+        assert_eq!(host.server.bind_addr(), "0.0.0.0:5000".parse().unwrap());
+        assert_eq!(
+            host.server.public_addresses(),
+            ["127.0.0.1:5000".parse().unwrap()]
+        );
+        assert_eq!(host.server.game.tick_rate, DEFAULT_TICK_RATE);
+    }
+
+    #[test]
+    fn parse_flags() {
+        let cli = Cli::parse_from([
+            "mp",
+            "server",
+            "--port=6000",
+            "--public-addr=10.0.0.2:6000",
+            "--public-addr=127.0.0.1:6000",
+            "--max-clients=8",
+            "--tick-rate=30",
+        ]);
+        let Some(Command::Server(server)) = cli.command else {
+            panic!("Expected the server command, got {cli:?}");
+        };
+        assert_eq!(server.bind_addr(), "0.0.0.0:6000".parse().unwrap());
+        assert_eq!(server.public_addresses().len(), 2);
+        assert_eq!(server.max_clients, 8);
+        assert_eq!(server.game.tick_rate, 30);
+        assert!(Cli::try_parse_from(["mp", "server", "--tick-rate=0"]).is_err());
+        assert!(!server.headless);
+        assert_eq!(server.record, None);
+        assert!(!server.game.debug_controls);
+
+        let cli = Cli::parse_from(["mp", "host", "--record=match.replay"]);
+        let Some(Command::Host(host)) = cli.command else {
+            panic!("Expected the host command, got {cli:?}");
+        };
+        assert_eq!(host.server.record, Some(PathBuf::from("match.replay")));
+
+        let cli = Cli::parse_from([
+            "mp",
+            "server",
+            "--debug-controls",
+            "--debug-client=7",
+            "--debug-client=8",
+        ]);
+        let Some(Command::Server(server)) = cli.command else {
+            panic!("Expected the server command, got {cli:?}");
+        };
+        assert!(server.game.debug_controls);
+        assert_eq!(server.debug_clients, [7, 8]);
+
+        let cli = Cli::parse_from(["mp", "client", "3", "--server-addr=10.0.0.2:6000"]);
+        let Some(Command::Client(client)) = cli.command else {
+            panic!("Expected the client command, got {cli:?}");
+        };
+        assert_eq!(client.index, 3);
+        assert_eq!(client.server_addr, "10.0.0.2:6000".parse().unwrap());
+        assert_eq!(client.conditioner.link_conditioner, None);
+
+        let cli = Cli::parse_from([
+            "mp",
+            "client",
+            "--server-addr=10.0.0.2:6000",
+            "--link-conditioner=latency=100,loss=0.05",
+            "--conditioner-addr=127.0.0.1:7000",
+        ]);
+        let Some(Command::Client(client)) = cli.command else {
+            panic!("Expected the client command, got {cli:?}");
+        };
+        assert_eq!(client.server_addr, "10.0.0.2:6000".parse().unwrap());
+        assert_eq!(
+            client.conditioner.link_conditioner.unwrap().packet_loss,
+            0.05
+        );
+        assert_eq!(
+            client.conditioner.conditioner_addr,
+            "127.0.0.1:7000".parse().unwrap()
+        );
+        assert!(Cli::try_parse_from(["mp", "server", "--link-conditioner=loss=2"]).is_err());
+
+        let cli = Cli::parse_from(["mp", "token", "4", "Bob", "bob.token", "--color=1,0.5,0"]);
+        let Some(Command::Token(token)) = cli.command else {
+            panic!("Expected the token command, got {cli:?}");
+        };
+        assert_eq!(token.color, [1.0, 0.5, 0.0]);
+        assert!(Cli::try_parse_from(["mp", "token", "4", "Bob", "bob.token"]).is_err());
+        assert!(Cli::try_parse_from(["mp", "token", "4", "Bob", "t", "--color=1,0.5"]).is_err());
+    }
+
+    #[test]
+    fn split_bots() {
+        let Some(Command::Bots(bots)) =
+            Cli::parse_from(["mp", "bots", "10", "--processes=3", "--behavior=square"]).command
+        else {
+            unreachable!()
+        };
+
+        let split = bots.split();
+        assert_eq!(
+            split.iter().map(|b| (b.first_index, b.count)).collect_vec(),
+            [(1, 4), (5, 3), (8, 3)]
+        );
+
+        let args = ["mp".to_string()].into_iter().chain(split[1].to_args());
+        let Some(Command::Bots(again)) = Cli::parse_from(args).command else {
+            unreachable!()
+        };
+        assert_eq!(again.processes, 1);
+        assert_eq!(again.first_index, 5);
+        assert_eq!(again.behavior, Behavior::Square);
+    }
+
+    #[test]
+    fn window_args_round_trip() {
+        let cli = Cli::parse_from(["mp", "client", "--monitor-x=1920", "--monitor-width=1920"]);
+        let Some(Command::Client(client)) = cli.command else {
+            panic!("Expected the client command, got {cli:?}");
+        };
+
+        let args = ["mp".to_string(), "client".to_string()]
+            .into_iter()
+            .chain(client.window.to_args());
+        let Some(Command::Client(again)) = Cli::parse_from(args).command else {
+            unreachable!()
+        };
+        assert_eq!(again.window.monitor_x, 1920);
+        assert_eq!(again.window.monitor_width, 1920.0);
+    }
+}
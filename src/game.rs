@@ -1,43 +1,105 @@
 use bevy::prelude::*;
 use bevy::render::camera::ScalingMode;
-use bevy_renet::renet::ServerEvent;
 use bevy_xpbd_2d::components::Collider;
 use bevy_xpbd_2d::plugins::spatial_query::{RayCaster, RayHits};
 use bevy_xpbd_2d::plugins::{PhysicsDebugPlugin, PhysicsPlugins};
+use itertools::Itertools;
 use leafwing_input_manager::prelude::ActionState;
 use leafwing_input_manager::{Actionlike, InputManagerBundle};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 
-use crate::player::{Action, Player, PlayerPlugin};
+use crate::identity::Identity;
+use crate::player::{reload, Action, Ammo, Player, PlayerPlugin};
 use crate::prediction::{PredictionPlugin, Resimulating};
-use crate::replicate::schedule::{NetworkBlueprint, NetworkPreUpdate, NetworkUpdate};
-use crate::replicate::{is_server, AppExt, NetworkTick, Owner, Replicate, ReplicationPlugin};
+use crate::replicate::codec::Compression;
+use crate::replicate::debug::{DebugCommand, NetworkDebugPlugin};
+use crate::replicate::schedule::{
+    NetworkBlueprint, NetworkFixedTime, NetworkPreUpdate, NetworkUpdate, TickStrategy,
+};
+use crate::replicate::stats::NetworkStatsOverlayPlugin;
+use crate::replicate::{
+    is_server, AppExt, NetworkTick, Owner, Replicate, ReplicationOptions, ReplicationPlugin,
+    SendRate, SyncedServerTick,
+};
+use crate::shutdown::ShutdownPlugin;
+use crate::transport::ConnectionEvent;
 
-use self::movables::MovablePlugin;
+use self::movables::{handle_movement, MovablePlugin};
 
-pub const FIXED_TIMESTEP: f32 = 1.0 / 60.0;
+pub const DEFAULT_TICK_RATE: u32 = 60;
+/// Replication packets are sent at about this rate, whatever the tick rate
+pub const SEND_RATE: u32 = 20;
+/// Bytes per second of entity updates each client gets at most
+pub const BANDWIDTH_BUDGET: usize = 128 * 1024;
 
 mod movables;
 
-pub struct GamePlugin;
+pub struct GamePlugin {
+    pub tick_rate: u32,
+    /// Sprites, the camera and debug drawing. A headless server runs without them.
+    pub visuals: bool,
+    /// Pausing, stepping and rewinding every peer, see [`NetworkDebugPlugin`]
+    pub debug_controls: bool,
+}
+
+impl GamePlugin {
+    pub fn with_tick_rate(tick_rate: u32) -> Self {
+        Self {
+            tick_rate,
+            visuals: true,
+            debug_controls: false,
+        }
+    }
+
+    pub fn with_visuals(mut self, visuals: bool) -> Self {
+        self.visuals = visuals;
+        self
+    }
+
+    pub fn with_debug_controls(mut self, debug_controls: bool) -> Self {
+        self.debug_controls = debug_controls;
+        self
+    }
+}
+
+impl Default for GamePlugin {
+    fn default() -> Self {
+        Self::with_tick_rate(DEFAULT_TICK_RATE)
+    }
+}
 
 impl Plugin for GamePlugin {
     fn build(&self, app: &mut App) {
+        let send_interval = (self.tick_rate / SEND_RATE).max(1) as u64;
+        let packets_per_second = (self.tick_rate as u64 / send_interval).max(1);
+
         app.add_plugins((
             PhysicsPlugins::default(),
-            PhysicsDebugPlugin::default(),
-            ReplicationPlugin::with_step(FIXED_TIMESTEP),
+            ReplicationPlugin::with_step(1.0 / self.tick_rate as f32)
+                .with_send_interval(send_interval)
+                // About once per second
+                .with_checksum_interval(packets_per_second)
+                .with_bandwidth_budget(BANDWIDTH_BUDGET)
+                .with_compression(Compression::Lz4),
             PredictionPlugin::<Action>::default(),
-            PlayerPlugin,
+            PlayerPlugin {
+                visuals: self.visuals,
+            },
             MovablePlugin,
+            ShutdownPlugin,
         ))
-        .init_resource::<GizmoConfig>()
-        .replicate::<Block>()
-        .replicate::<Npc>()
+        .replicate_with_options::<Block>(
+            ReplicationOptions::default().with_send_rate(SendRate::SpawnOnly),
+        )
+        .replicate_with_options::<Npc>(
+            ReplicationOptions::default().with_send_rate(SendRate::SpawnOnly),
+        )
         .replicate::<Dir>()
         .replicate::<Bullet>()
         .replicate::<DieAfterTicks>()
-        .add_systems(Startup, spawn_camera)
+        .init_resource::<AvatarRng>()
         .add_systems(
             NetworkBlueprint,
             (block_blueprint, npc_blueprint, bullet_blueprint),
@@ -47,17 +109,46 @@ impl Plugin for GamePlugin {
             NetworkUpdate,
             (
                 //spawn_block,
-                spawn_bullet,
                 move_bullet,
                 bullets_hit_things,
                 despawn_bullets,
-                spawn_avatar.run_if(is_server),
-                spawn_npc.run_if(is_server.and_then(run_once())),
+                (
+                    // Shoots from where the player moved to, with what it reloaded
+                    spawn_bullet.after(handle_movement).after(reload),
+                    spawn_avatar.run_if(is_server),
+                    spawn_npc.run_if(is_server.and_then(run_once())),
+                )
+                    // Entities get their ids as they are spawned, so spawning in the same order
+                    // every run gives them the same ids, which replays of a session depend on
+                    .chain(),
             ),
         );
+
+        if self.debug_controls {
+            app.add_plugins(NetworkDebugPlugin::default());
+            if self.visuals {
+                app.add_systems(Update, debug_controls);
+            }
+        }
+
+        if self.visuals {
+            app.add_plugins((PhysicsDebugPlugin::default(), NetworkStatsOverlayPlugin))
+                .init_resource::<GizmoConfig>()
+                .add_systems(Startup, spawn_camera)
+                .add_systems(
+                    NetworkBlueprint,
+                    (block_visuals, npc_visuals, bullet_visuals),
+                );
+        }
     }
 }
 
+/// What a [`SpriteBundle`] adds on top of the transform, for entities whose blueprint already
+/// gave them one
+pub fn sprite(sprite: Sprite) -> (Sprite, Handle<Image>, VisibilityBundle) {
+    (sprite, default(), default())
+}
+
 #[derive(Component, Serialize, Deserialize)]
 struct DieAfterTicks(u32);
 
@@ -80,18 +171,20 @@ fn block_blueprint(mut commands: Commands, new_blocks: Query<(Entity, &Block), A
     for (entity, block) in &new_blocks {
         commands.entity(entity).insert((
             Name::new("Bullet"),
-            SpriteBundle {
-                sprite: Sprite {
-                    color: Color::GRAY,
-                    ..default()
-                },
-                transform: Transform::from_translation(block.pos),
-                ..default()
-            },
+            TransformBundle::from_transform(Transform::from_translation(block.pos)),
         ));
     }
 }
 
+fn block_visuals(mut commands: Commands, new_blocks: Query<Entity, Added<Block>>) {
+    for entity in &new_blocks {
+        commands.entity(entity).insert(sprite(Sprite {
+            color: Color::GRAY,
+            ..default()
+        }));
+    }
+}
+
 //fn spawn_block(mut commands: Commands, players: Query<&ActionState<Action>>) {
 //    for actions in &players {
 //        if actions.just_pressed(Action::Main) {
@@ -138,18 +231,11 @@ fn bullet_blueprint(mut commands: Commands, new_bullets: Query<(Entity, &Bullet)
         println!("blueprinted bullet");
         commands.entity(entity).insert((
             Name::new("Bullet"),
-            SpriteBundle {
-                sprite: Sprite {
-                    color: Color::RED,
-                    ..default()
-                },
-                transform: Transform {
-                    translation: bullet.pos,
-                    scale: Vec3::splat(0.2),
-                    ..default()
-                },
+            TransformBundle::from_transform(Transform {
+                translation: bullet.pos,
+                scale: Vec3::splat(0.2),
                 ..default()
-            },
+            }),
             RayCaster::new(Vec2::ZERO, bullet.dir.xy()),
             Collider::ball(0.1),
             DieAfterTicks(100),
@@ -157,14 +243,29 @@ fn bullet_blueprint(mut commands: Commands, new_bullets: Query<(Entity, &Bullet)
     }
 }
 
+fn bullet_visuals(mut commands: Commands, new_bullets: Query<Entity, Added<Bullet>>) {
+    for entity in &new_bullets {
+        commands.entity(entity).insert(sprite(Sprite {
+            color: Color::RED,
+            ..default()
+        }));
+    }
+}
+
 fn spawn_bullet(
     mut commands: Commands,
-    players: Query<(Entity, &Transform, &ActionState<Action>)>,
+    mut players: Query<(Entity, &Transform, &ActionState<Action>, Option<&mut Ammo>)>,
     is_resimulating: Option<Res<Resimulating>>,
 ) {
-    for (player, tf, actions) in &players {
+    for (player, tf, actions, mut ammo) in &mut players {
         if actions.just_pressed(Action::Shoot) {
+            if ammo.as_ref().is_some_and(|ammo| ammo.0 == 0) {
+                continue;
+            }
             if let Some(pos) = actions.axis_pair(Action::Shoot) {
+                if let Some(ammo) = &mut ammo {
+                    ammo.0 -= 1;
+                }
                 commands.spawn((
                     Replicate,
                     Bullet {
@@ -182,6 +283,35 @@ fn spawn_bullet(
     }
 }
 
+/// F5 pauses or resumes every peer, F6 steps a single tick and F7 rewinds one second
+fn debug_controls(
+    keys: Res<Input<KeyCode>>,
+    strategy: Res<TickStrategy>,
+    tick: Res<NetworkTick>,
+    fixed_time: Res<NetworkFixedTime>,
+    synced_server_tick: Option<Res<SyncedServerTick>>,
+    mut debug_commands: EventWriter<DebugCommand>,
+) {
+    if keys.just_pressed(KeyCode::F5) {
+        debug_commands.send(match *strategy {
+            TickStrategy::Automatic => DebugCommand::Pause,
+            TickStrategy::Manual => DebugCommand::Resume,
+        });
+    }
+    if keys.just_pressed(KeyCode::F6) {
+        debug_commands.send(DebugCommand::Step(1));
+    }
+    if keys.just_pressed(KeyCode::F7) {
+        let server_tick = synced_server_tick
+            .map(|synced| synced.tick)
+            .unwrap_or(*tick);
+        let ticks_per_second = (1.0 / fixed_time.0.duration().as_secs_f32()).round() as u64;
+        debug_commands.send(DebugCommand::Rewind(NetworkTick(
+            server_tick.0.saturating_sub(ticks_per_second),
+        )));
+    }
+}
+
 fn spawn_camera(mut commands: Commands) {
     commands.spawn(Camera2dBundle {
         projection: OrthographicProjection {
@@ -194,40 +324,73 @@ fn spawn_camera(mut commands: Commands) {
     });
 }
 
+/// Where new avatars get their color and position from
+#[derive(Resource)]
+pub struct AvatarRng(pub StdRng);
+
+impl Default for AvatarRng {
+    fn default() -> Self {
+        Self(StdRng::from_entropy())
+    }
+}
+
 fn spawn_avatar(
     mut commands: Commands,
-    mut events: EventReader<ServerEvent>,
+    mut events: EventReader<ConnectionEvent>,
     players: Query<(Entity, &Owner)>,
+    names: Query<&Player>,
+    mut rng: ResMut<AvatarRng>,
 ) {
+    let mut taken_names = names.iter().map(|player| player.name.clone()).collect_vec();
+
     for event in events.read() {
         match event {
-            ServerEvent::ClientConnected { client_id } => {
-                let color = Color::rgb(rand::random(), rand::random(), rand::random());
-                let pos = 4.0 * Vec2::new(rand::random(), rand::random());
+            ConnectionEvent::ClientConnected {
+                client_id,
+                user_data,
+            } => {
+                let sent = user_data.as_deref().map(Identity::from_user_data);
+                let mut identity = match sent {
+                    Some(Some(identity)) => identity.validate().map_err(|e| format!("{e:?}")),
+                    Some(None) => Err("Unreadable user data".to_string()),
+                    None => Err("No user data".to_string()),
+                }
+                .unwrap_or_else(|e| {
+                    println!("{client_id} gets a default identity: {e}");
+                    Identity {
+                        name: format!("{client_id}"),
+                        color: rng.0.gen(),
+                        loadout: default(),
+                    }
+                });
+                identity.make_unique(taken_names.iter().map(String::as_str));
+                taken_names.push(identity.name.clone());
+
+                let [r, g, b] = identity.color;
+                let pos = 4.0 * Vec2::new(rng.0.gen(), rng.0.gen());
 
                 let avatar = commands
                     .spawn((
                         Replicate,
                         Player {
-                            name: format!("{client_id}"),
-                            color,
-                            controller: Owner::Client(client_id.raw()),
+                            name: identity.name,
+                            color: Color::rgb(r, g, b),
+                            loadout: identity.loadout,
                         },
+                        Owner::Client(client_id.raw()),
+                        Ammo(Ammo::MAX),
                         Transform::from_translation(pos.extend(0.0)),
                     ))
                     .id();
 
                 println!("{client_id} connected! It's avatar is {avatar:?}");
             }
-            ServerEvent::ClientDisconnected {
-                client_id,
-                reason: _reason,
-            } => {
-                println!("{client_id} disconnected ({_reason})");
+            ConnectionEvent::ClientDisconnected { client_id, reason } => {
+                println!("{client_id} disconnected ({reason:?})");
 
                 for (entity, owner) in &players {
                     if *owner == Owner::Client(client_id.raw()) {
-                        commands.entity(entity).despawn();
+                        commands.entity(entity).despawn_recursive();
                     }
                 }
             }
@@ -279,18 +442,10 @@ fn npc_move(
     }
 }
 
-fn npc_blueprint(mut commands: Commands, npcs: Query<(Entity, &Transform, &Npc), Without<Sprite>>) {
-    for (entity, &transform, &Npc { color, .. }) in &npcs {
+fn npc_blueprint(mut commands: Commands, npcs: Query<Entity, (With<Npc>, Without<Collider>)>) {
+    for entity in &npcs {
         commands.entity(entity).insert((
-            SpriteBundle {
-                sprite: Sprite {
-                    color,
-                    custom_size: Some((1.0, 1.0).into()),
-                    ..default()
-                },
-                transform,
-                ..default()
-            },
+            GlobalTransform::default(),
             Collider::cuboid(1.0, 1.0),
             InputManagerBundle::<NpcAction>::default(),
             Name::new("Npc"),
@@ -298,6 +453,16 @@ fn npc_blueprint(mut commands: Commands, npcs: Query<(Entity, &Transform, &Npc),
     }
 }
 
+fn npc_visuals(mut commands: Commands, npcs: Query<(Entity, &Npc), Added<Npc>>) {
+    for (entity, &Npc { color, .. }) in &npcs {
+        commands.entity(entity).insert(sprite(Sprite {
+            color,
+            custom_size: Some((1.0, 1.0).into()),
+            ..default()
+        }));
+    }
+}
+
 fn spawn_npc(mut commands: Commands) {
     commands.spawn((
         Replicate,
@@ -309,3 +474,70 @@ fn spawn_npc(mut commands: Commands) {
         Transform::from_xyz(5.0, 0.0, -2.0),
     ));
 }
+
+#[cfg(test)]
+mod tests {
+    use bevy::input::InputPlugin;
+    use bevy_renet::renet::RenetServer;
+    use leafwing_input_manager::axislike::DualAxisData;
+
+    use crate::replicate::replication_connection_config;
+    use crate::test_utils::{count, session, tick};
+    use crate::transport::memory::{MemoryServerPlugin, MemoryServerTransport};
+
+    use super::*;
+
+    #[test]
+    fn headless_server() {
+        let mut server = App::new();
+        server
+            .add_plugins((
+                MinimalPlugins,
+                TransformPlugin,
+                HierarchyPlugin,
+                InputPlugin,
+                MemoryServerPlugin,
+                GamePlugin::default().with_visuals(false),
+            ))
+            .insert_resource(TickStrategy::Manual)
+            .insert_resource(RenetServer::new(replication_connection_config()))
+            .insert_resource(MemoryServerTransport::default());
+
+        server.world.spawn((
+            Replicate,
+            Transform::default(),
+            Player {
+                name: "Alice".to_string(),
+                color: Color::RED,
+                loadout: default(),
+            },
+            Owner::Client(1),
+        ));
+        for _ in 0..3 {
+            tick(&mut server);
+        }
+
+        assert_eq!(count::<(&Npc, &Collider, &GlobalTransform)>(&mut server), 1);
+        assert_eq!(count::<(&Player, &GlobalTransform, &Owner)>(&mut server), 1);
+        assert_eq!(count::<&Sprite>(&mut server), 0);
+    }
+
+    /// Walks a square and shoots at the middle at every corner
+    fn square(tick: usize) -> ActionState<Action> {
+        let side = tick / 40 % 4;
+        let mut actions = ActionState::default();
+        actions.press([Action::Up, Action::Right, Action::Down, Action::Left][side].clone());
+        if tick.is_multiple_of(40) {
+            actions.press(Action::Shoot);
+        }
+        let target = Some(DualAxisData::from_xy(Vec2::ZERO));
+        actions.action_data_mut(Action::Main).axis_pair = target;
+        actions.action_data_mut(Action::Shoot).axis_pair = target;
+        actions
+    }
+
+    #[test]
+    fn square_session() {
+        session::check("square", 320, square);
+    }
+}
@@ -2,6 +2,7 @@ use bevy::prelude::*;
 use leafwing_input_manager::prelude::*;
 
 use crate::player::{Action, Player};
+use crate::replicate::codec::{Angle2d, QuantizedVec2, Transform2d};
 use crate::replicate::schedule::{NetworkFixedTime, NetworkUpdate};
 use crate::replicate::AppExt;
 
@@ -13,19 +14,14 @@ impl Plugin for MovablePlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(InputManagerPlugin::<NpcAction>::default());
 
-        app.replicate_with::<Transform>(
-            |component| {
-                bincode::serialize(&(component.translation, component.rotation, component.scale))
-                    .unwrap()
-            },
-            |data| {
-                let (translation, rotation, scale) = bincode::deserialize(data).unwrap();
-                Transform {
-                    translation,
-                    rotation,
-                    scale,
-                }
+        // Owners get the transform of their own player too, although they predict it: the
+        // server's is what they correct their mispredictions with
+        app.replicate_codec(
+            Transform2d {
+                position: QuantizedVec2::new(Vec2::splat(-512.0), Vec2::splat(512.0), 1.0 / 512.0),
+                rotation: Angle2d::new(16),
             },
+            default(),
         )
         .add_systems(NetworkUpdate, handle_movement);
     }
@@ -0,0 +1,211 @@
+use bevy_renet::renet::transport::NETCODE_USER_DATA_BYTES;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_NAME_LEN: usize = 16;
+
+/// Words that may not appear in names, even with some letters swapped for look-alike digits
+const BANNED_WORDS: &[&str] = &["fuck", "shit", "cunt", "bitch", "asshole"];
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Hat {
+    #[default]
+    None,
+    Cap,
+    Crown,
+    Halo,
+}
+
+impl Hat {
+    pub const ALL: [Hat; 4] = [Hat::None, Hat::Cap, Hat::Crown, Hat::Halo];
+}
+
+/// Purely cosmetic choices, which have no effect on the game
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Loadout {
+    pub hat: Hat,
+}
+
+/// Who the client wants to be. Sent in the netcode user data, or signed into the connect token in
+/// secure mode.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Identity {
+    pub name: String,
+    pub color: [f32; 3],
+    pub loadout: Loadout,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidIdentity {
+    EmptyName,
+    NameTooLong,
+    InvalidCharacter(char),
+    Profanity,
+    InvalidColor,
+}
+
+impl Identity {
+    pub fn to_user_data(&self) -> Result<[u8; NETCODE_USER_DATA_BYTES], String> {
+        let bytes = bincode::serialize(self).unwrap();
+        if bytes.len() > NETCODE_USER_DATA_BYTES {
+            return Err(format!(
+                "The identity takes {} bytes, but only {NETCODE_USER_DATA_BYTES} fit in a token",
+                bytes.len()
+            ));
+        }
+
+        let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+        user_data[..bytes.len()].copy_from_slice(&bytes);
+        Ok(user_data)
+    }
+
+    pub fn from_user_data(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> Option<Self> {
+        bincode::deserialize(user_data).ok()
+    }
+
+    /// Checks what a client sent before anyone else gets to see it
+    pub fn validate(mut self) -> Result<Self, InvalidIdentity> {
+        self.name = self.name.trim().to_string();
+
+        if self.name.is_empty() {
+            return Err(InvalidIdentity::EmptyName);
+        }
+        if self.name.chars().count() > MAX_NAME_LEN {
+            return Err(InvalidIdentity::NameTooLong);
+        }
+        if let Some(c) = self
+            .name
+            .chars()
+            .find(|&c| !(c.is_alphanumeric() || matches!(c, ' ' | '_' | '-')))
+        {
+            return Err(InvalidIdentity::InvalidCharacter(c));
+        }
+        if is_profane(&self.name) {
+            return Err(InvalidIdentity::Profanity);
+        }
+        if !self.color.iter().all(|c| (0.0..=1.0).contains(c)) {
+            return Err(InvalidIdentity::InvalidColor);
+        }
+
+        Ok(self)
+    }
+
+    /// Adds a number to the name until nobody else has it
+    pub fn make_unique<'a>(&mut self, taken: impl IntoIterator<Item = &'a str> + Clone) {
+        let is_taken = |name: &str| {
+            taken
+                .clone()
+                .into_iter()
+                .any(|other| other.eq_ignore_ascii_case(name))
+        };
+
+        let base = self.name.clone();
+        let mut n = 2;
+        while is_taken(&self.name) {
+            // Keep the suffix even if it means cutting the name short
+            let suffix = format!(" {n}");
+            let keep = MAX_NAME_LEN.saturating_sub(suffix.len());
+            self.name = base.chars().take(keep).collect::<String>() + &suffix;
+            n += 1;
+        }
+    }
+}
+
+fn is_profane(name: &str) -> bool {
+    let normalized = name
+        .chars()
+        .filter_map(|c| match c.to_ascii_lowercase() {
+            '0' => Some('o'),
+            '1' | '!' => Some('i'),
+            '3' => Some('e'),
+            '4' | '@' => Some('a'),
+            '5' | '$' => Some('s'),
+            '7' => Some('t'),
+            c if c.is_alphabetic() => Some(c),
+            _ => None,
+        })
+        .collect::<String>();
+
+    BANNED_WORDS.iter().any(|word| normalized.contains(word))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity(name: &str) -> Identity {
+        Identity {
+            name: name.to_string(),
+            color: [1.0, 0.5, 0.0],
+            loadout: Loadout { hat: Hat::Crown },
+        }
+    }
+
+    #[test]
+    fn user_data() {
+        let user_data = identity("Alice").to_user_data().unwrap();
+        assert_eq!(
+            Identity::from_user_data(&user_data),
+            Some(identity("Alice"))
+        );
+
+        let too_long = identity(&"a".repeat(NETCODE_USER_DATA_BYTES));
+        assert!(too_long.to_user_data().is_err());
+    }
+
+    #[test]
+    fn validate() {
+        assert_eq!(identity("  Alice ").validate(), Ok(identity("Alice")));
+        assert_eq!(
+            identity("Bob_the-2nd").validate(),
+            Ok(identity("Bob_the-2nd"))
+        );
+
+        assert_eq!(identity("   ").validate(), Err(InvalidIdentity::EmptyName));
+        assert_eq!(
+            identity(&"a".repeat(MAX_NAME_LEN + 1)).validate(),
+            Err(InvalidIdentity::NameTooLong)
+        );
+        assert_eq!(
+            identity("Alice\n").validate(),
+            Ok(identity("Alice")),
+            "Surrounding whitespace is trimmed"
+        );
+        assert_eq!(
+            identity("Al\u{202e}ice").validate(),
+            Err(InvalidIdentity::InvalidCharacter('\u{202e}'))
+        );
+        assert_eq!(
+            Identity {
+                color: [f32::NAN, 0.0, 0.0],
+                ..identity("Alice")
+            }
+            .validate(),
+            Err(InvalidIdentity::InvalidColor)
+        );
+    }
+
+    #[test]
+    fn profanity() {
+        assert_eq!(
+            identity("shithead").validate(),
+            Err(InvalidIdentity::Profanity)
+        );
+        assert_eq!(
+            identity("SH1T head").validate(),
+            Err(InvalidIdentity::Profanity)
+        );
+        assert_eq!(identity("Shiitake").validate(), Ok(identity("Shiitake")));
+    }
+
+    #[test]
+    fn unique_names() {
+        let mut alice = identity("alice");
+        alice.make_unique(["Alice", "Alice 2", "Bob"]);
+        assert_eq!(alice.name, "alice 3");
+
+        let mut long = identity(&"a".repeat(MAX_NAME_LEN));
+        let taken = "a".repeat(MAX_NAME_LEN);
+        long.make_unique([taken.as_str()]);
+        assert_eq!(long.name, "a".repeat(MAX_NAME_LEN - 2) + " 2");
+    }
+}
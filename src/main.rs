@@ -2,60 +2,220 @@
 
 use std::fmt::Display;
 use std::net::{SocketAddr, UdpSocket};
+use std::path::PathBuf;
 use std::process::{Child, Stdio};
-use std::time::SystemTime;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use bevy::app::AppExit;
+use bevy::app::{AppExit, ScheduleRunnerPlugin};
 use bevy::audio::AudioPlugin;
+use bevy::input::InputPlugin;
 use bevy::prelude::*;
-use bevy::window::WindowResolution;
+use bevy::window::WindowCloseRequested;
 use bevy_inspector_egui::quick::WorldInspectorPlugin;
 use bevy_renet::renet::transport::{
-    ClientAuthentication, NetcodeClientTransport, NetcodeServerTransport, ServerAuthentication,
-    ServerConfig,
+    ClientAuthentication, ConnectToken, NetcodeClientTransport, NetcodeServerTransport,
+    ServerAuthentication, ServerConfig,
 };
-use bevy_renet::renet::{RenetClient, RenetServer};
-use owo_colors::OwoColorize;
+use bevy_renet::renet::{ClientId, RenetClient, RenetServer};
+use clap::Parser;
+use itertools::Itertools;
+use owo_colors::{AnsiColors, OwoColorize};
 
+use crate::auth::{
+    issue_connect_token, now, private_key, private_key_to_hex, CONNECT_TOKEN_VAR, PRIVATE_KEY_VAR,
+};
+use crate::bot::{BotPlugin, BotStatus};
+use crate::cli::{
+    BotsArgs, Cli, ClientArgs, Command, ConditionerArgs, HostArgs, ReplayArgs, ServerArgs,
+    TokenArgs,
+};
 use crate::game::GamePlugin;
-use crate::player::Player;
+use crate::identity::{Hat, Identity, Loadout};
+use crate::player::{Ammo, Player};
+use crate::replicate::debug::DebugControllers;
+use crate::replicate::replay::{Replay, ReplayPlugin, ReplayRecorder};
 use crate::replicate::{Owner, Replicate, PROTOCOL_ID};
+use crate::shutdown::{ServerShutdown, Shutdown};
+use crate::transport::conditioner::spawn_udp_conditioner;
+use crate::transport::netcode::NetcodeTransportPlugin;
+use crate::transport::websocket::{
+    WebSocketClientTransport, WebSocketServerTransport, WebSocketTransportPlugin,
+};
 
 use self::replicate::replication_connection_config;
 
+mod auth;
+mod bot;
+mod cli;
 mod game;
+mod identity;
 mod player;
 mod prediction;
 mod replicate;
+mod shutdown;
 #[cfg(test)]
 mod test_utils;
 pub mod transport;
 
+/// Starts the link conditioner if there is one, returning where to connect to go through it
+fn spawn_conditioner(args: &ConditionerArgs, upstream: SocketAddr) -> Option<SocketAddr> {
+    let config = args.link_conditioner?;
+    if use_websocket() {
+        panic!("The link conditioner only works over UDP, unset {TRANSPORT_VAR}");
+    }
+
+    let conditioned =
+        spawn_udp_conditioner(args.conditioner_addr, upstream, config, rand::random())
+            .unwrap_or_else(|e| {
+                panic!(
+                    "Could not start the link conditioner on {}: {e}",
+                    args.conditioner_addr
+                )
+            });
+    println!("Conditioning the link through {conditioned} to {upstream}: {config:?}");
+    Some(conditioned)
+}
+
+/// Set to `websocket` to connect over WebSockets instead of UDP. Clients started by the host inherit
+/// it.
+const TRANSPORT_VAR: &str = "MP_TRANSPORT";
+
+fn use_websocket() -> bool {
+    match std::env::var(TRANSPORT_VAR).as_deref() {
+        Ok("websocket") => true,
+        Ok("udp") | Err(_) => false,
+        Ok(other) => panic!("Invalid {TRANSPORT_VAR}: {other}, expected udp or websocket"),
+    }
+}
+
+/// WebSocket clients can not present a connect token, so they would get around secure mode
+fn refuse_websocket_in_secure_mode() {
+    if use_websocket() && private_key().is_some() {
+        panic!("{PRIVATE_KEY_VAR} only works over UDP, unset it or {TRANSPORT_VAR}");
+    }
+}
+
 fn main() {
-    match std::env::args().nth(1).as_deref() {
-        Some("client") => client(
-            std::env::args()
-                .nth(2)
-                .expect("Client needs a second argument")
-                .parse::<i32>()
-                .expect("Second argument must be a number"),
-        ),
-        Some("server") => {
-            server(vec![]);
+    match Cli::parse().command {
+        Some(Command::Client(args)) => client(args),
+        Some(Command::Bots(args)) => bots(args),
+        Some(Command::Server(args)) => server(args, vec![]),
+        Some(Command::Replay(args)) => replay(args),
+        Some(Command::Keygen) => {
+            println!("{}", private_key_to_hex(&auth::generate_private_key()));
         }
-        Some("host") | None => {
-            let client1 = start_client(1, "[C1]".green());
-            let client2 = start_client(2, "[C2]".yellow());
+        Some(Command::Token(args)) => write_connect_token(args),
+        Some(Command::Host(args)) => host(args),
+        None => host(HostArgs::default()),
+    }
+}
 
-            server(vec![client1, client2]);
-        }
-        _ => panic!("The first argument is nonsensical"),
+/// Colors of the prefixes on the output of child processes
+const PREFIX_COLORS: [AnsiColors; 6] = [
+    AnsiColors::Green,
+    AnsiColors::Yellow,
+    AnsiColors::Cyan,
+    AnsiColors::Magenta,
+    AnsiColors::Blue,
+    AnsiColors::Red,
+];
+
+fn host(args: HostArgs) {
+    refuse_websocket_in_secure_mode();
+    // The server runs the link conditioner, if any, for its clients
+    let (server_addr, token_addresses) = match args.server.conditioner.link_conditioner {
+        Some(_) => (
+            args.server.conditioner.conditioner_addr,
+            vec![args.server.conditioner.conditioner_addr],
+        ),
+        None => (
+            args.server.public_addresses()[0],
+            args.server.public_addresses(),
+        ),
+    };
+    // The clients only get a token, never the key
+    let private_key = private_key();
+
+    let clients = (1..=args.clients)
+        .map(|index| {
+            let color = PREFIX_COLORS[(index - 1) as usize % PREFIX_COLORS.len()];
+            let mut client_args = vec![
+                "client".to_string(),
+                format!("{index}"),
+                format!("--server-addr={server_addr}"),
+                "--exit-with-server".to_string(),
+                format!("--tick-rate={}", args.server.game.tick_rate),
+            ];
+            if args.server.game.debug_controls {
+                client_args.push("--debug-controls".to_string());
+            }
+            client_args.extend(args.server.window.to_args());
+
+            let token = private_key.map(|private_key| {
+                let token = issue_connect_token(
+                    &private_key,
+                    rand::random(),
+                    &client_identity(index),
+                    token_addresses.clone(),
+                    now(),
+                )
+                .unwrap_or_else(|e| panic!("{e}"));
+                let path = std::env::temp_dir().join(format!("mp-client-{index}.token"));
+                token
+                    .write(&mut std::fs::File::create(&path).unwrap())
+                    .unwrap();
+                path
+            });
+
+            start_client(client_args, token, format!("[C{index}]").color(color))
+        })
+        .collect();
+
+    server(args.server, clients);
+}
+
+fn write_connect_token(args: TokenArgs) {
+    let private_key = private_key().unwrap_or_else(|| panic!("{PRIVATE_KEY_VAR} is not set"));
+
+    let identity = Identity {
+        name: args.name.clone(),
+        color: args.color,
+        loadout: default(),
     }
+    .validate()
+    .unwrap_or_else(|e| panic!("Invalid identity: {e:?}"));
+    let token = issue_connect_token(
+        &private_key,
+        args.client_id,
+        &identity,
+        vec![args.server_addr],
+        now(),
+    )
+    .unwrap_or_else(|e| panic!("{e}"));
+
+    let mut file = std::fs::File::create(&args.file).unwrap();
+    token.write(&mut file).unwrap();
+    println!(
+        "Wrote a connect token for {} ({}) to {}",
+        args.client_id,
+        args.name,
+        args.file.display()
+    );
 }
 
-fn start_client(index: usize, prefix: impl Display) -> std::process::Child {
-    let mut child = std::process::Command::new(std::env::args().next().unwrap())
-        .args(["client", &format!("{index}")])
+fn start_client(
+    args: Vec<String>,
+    connect_token: Option<PathBuf>,
+    prefix: impl Display,
+) -> std::process::Child {
+    let mut command = std::process::Command::new(std::env::args().next().unwrap());
+    command.args(args).env_remove(PRIVATE_KEY_VAR);
+    if let Some(path) = connect_token {
+        command.env(CONNECT_TOKEN_VAR, path);
+    }
+    let mut child = command
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -64,36 +224,55 @@ fn start_client(index: usize, prefix: impl Display) -> std::process::Child {
 
     let prefix = format!("{{ print \"{} \" $0}}", prefix);
 
-    std::process::Command::new("awk")
-        .arg(prefix.clone())
-        .stdin(child.stdout.take().unwrap())
-        .spawn()
-        .unwrap();
-    std::process::Command::new("awk")
-        .arg(prefix)
-        .stdin(child.stderr.take().unwrap())
-        .spawn()
-        .unwrap();
+    let outputs: [Stdio; 2] = [
+        child.stdout.take().unwrap().into(),
+        child.stderr.take().unwrap().into(),
+    ];
+    for output in outputs {
+        let mut awk = std::process::Command::new("awk")
+            .arg(&prefix)
+            .stdin(output)
+            .spawn()
+            .unwrap();
+        // It exits once the client closes its output
+        std::thread::spawn(move || awk.wait());
+    }
 
     child
 }
 
-pub fn server(mut clients: Vec<Child>) {
+pub fn server(args: ServerArgs, mut clients: Vec<Child>) {
+    refuse_websocket_in_secure_mode();
     println!("Starting server!");
 
-    let monitor_width = 2560.0;
-    let monitor_height = 1440.0;
-    let window_width = monitor_width / 2.0;
-    let window_height = monitor_height / 2.0;
-    let position = WindowPosition::At(IVec2::new(
-        (monitor_width - window_width) as i32 / 2,
-        (monitor_height - window_height) as i32 / 2,
-    ));
-    let resolution =
-        WindowResolution::new(window_width, window_height).with_scale_factor_override(1.0);
+    let mut app = App::new();
 
-    App::new()
-        .add_plugins((
+    if args.headless {
+        let tick = Duration::from_secs_f64(1.0 / args.game.tick_rate as f64);
+        let interrupted = Arc::new(AtomicBool::new(false));
+        ctrlc::set_handler({
+            let interrupted = interrupted.clone();
+            move || interrupted.store(true, Ordering::Relaxed)
+        })
+        .unwrap();
+
+        app.add_plugins((
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(tick)),
+            TransformPlugin,
+            HierarchyPlugin,
+            InputPlugin,
+        ))
+        .add_systems(Update, move |mut shutdown: EventWriter<Shutdown>| {
+            if interrupted.swap(false, Ordering::Relaxed) {
+                shutdown.send(Shutdown {
+                    reason: "The server was stopped".to_string(),
+                });
+            }
+        });
+    } else {
+        let (position, resolution) = args.window.server_window();
+
+        app.add_plugins((
             DefaultPlugins
                 .set(WindowPlugin {
                     primary_window: Some(Window {
@@ -105,13 +284,13 @@ pub fn server(mut clients: Vec<Child>) {
                         focused: true,
                         ..default()
                     }),
+                    // Closing the window shuts the server down instead
+                    close_when_requested: false,
                     ..default()
                 })
                 .disable::<AudioPlugin>(/* Disabled due to audio bug with pipewire */),
             WorldInspectorPlugin::default(),
-            GamePlugin,
         ))
-        .add_systems(Startup, start_server_networking)
         .add_systems(Startup, |mut commands: Commands| {
             commands.spawn((
                 Replicate,
@@ -119,20 +298,24 @@ pub fn server(mut clients: Vec<Child>) {
                 Player {
                     name: "Host".to_string(),
                     color: Color::rgb(rand::random(), rand::random(), rand::random()),
-                    controller: Owner::Server,
+                    loadout: Loadout { hat: Hat::Crown },
                 },
+                Owner::Server,
+                Ammo(Ammo::MAX),
             ));
         })
-        .add_systems(Last, move |app_exit: EventReader<AppExit>| {
-            if !app_exit.is_empty() {
-                for client in &mut clients {
-                    //send_app_exit(&mut client);
-                    //client.wait().unwrap();
-                    client.kill().unwrap();
+        .add_systems(
+            Update,
+            |keys: Res<Input<KeyCode>>,
+             close_requests: EventReader<WindowCloseRequested>,
+             mut shutdown: EventWriter<Shutdown>| {
+                if keys.just_pressed(KeyCode::Escape) || !close_requests.is_empty() {
+                    shutdown.send(Shutdown {
+                        reason: "The host closed the server".to_string(),
+                    });
                 }
-            }
-        })
-        .add_systems(Update, bevy::window::close_on_esc)
+            },
+        )
         .add_systems(
             Update,
             move |mut windows: Query<&mut Window>, time: Res<Time>| {
@@ -144,51 +327,145 @@ pub fn server(mut clients: Vec<Child>) {
                     }
                 }
             },
-        )
-        .run();
-}
+        );
+    }
 
-fn start_server_networking(mut commands: Commands) {
-    let server = RenetServer::new(replication_connection_config());
+    if let Some(path) = &args.record {
+        println!("Recording to {}", path.display());
+        app.insert_resource(ReplayRecorder::create(path).expect("Could not create the replay"));
+    }
+    app.insert_resource(DebugControllers(
+        args.debug_clients
+            .iter()
+            .map(|&client_id| ClientId::from_raw(client_id))
+            .collect(),
+    ));
 
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
-    //let public_addr = "X:5000".parse::<SocketAddr>().unwrap();
-    let localhost = "127.0.0.1:5000".parse::<SocketAddr>().unwrap();
-    let socket = UdpSocket::bind("0.0.0.0:5000").unwrap();
-
-    let server_config = ServerConfig {
-        max_clients: 64,
-        protocol_id: PROTOCOL_ID,
-        authentication: ServerAuthentication::Unsecure,
-        current_time,
-        public_addresses: vec![/*public_addr, */ localhost],
+    app.add_plugins((
+        GamePlugin::with_tick_rate(args.game.tick_rate)
+            .with_visuals(!args.headless)
+            .with_debug_controls(args.game.debug_controls),
+        NetcodeTransportPlugin,
+        WebSocketTransportPlugin,
+    ))
+    .add_systems(Startup, start_server_networking(args))
+    .add_systems(Last, move |app_exit: EventReader<AppExit>| {
+        if !app_exit.is_empty() {
+            wait_for_clients(&mut clients);
+        }
+    })
+    .run();
+}
+
+fn replay(args: ReplayArgs) {
+    let replay = match Replay::load(&args.file) {
+        Ok(replay) => replay,
+        Err(err) => {
+            println!("Could not read {}: {err}", args.file.display());
+            std::process::exit(1);
+        }
     };
+    println!(
+        "Replaying ticks {} to {} with {} inputs",
+        replay.first_tick().0,
+        replay.last_tick().0,
+        replay.inputs.len()
+    );
 
-    let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
+    let (position, resolution) = args.window.server_window();
+    let mut app = App::new();
+    app.add_plugins((
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: Some(Window {
+                    title: "Replay".to_string(),
+                    position,
+                    resolution,
+                    ..default()
+                }),
+                ..default()
+            })
+            .disable::<AudioPlugin>(/* Disabled due to audio bug with pipewire */),
+        GamePlugin::with_tick_rate(replay.header.tick_rate()),
+    ));
+
+    if let Err(err) = replay.check_components(&app.world) {
+        println!("Could not play {}: {err}", args.file.display());
+        std::process::exit(1);
+    }
+
+    app.add_plugins(ReplayPlugin { replay }).run();
+}
 
-    commands.insert_resource(transport);
-    commands.insert_resource(server);
-    commands.insert_resource(Owner::Server);
+/// The clients exit on their own once they hear that the server shut down, but get killed if they
+/// take too long
+fn wait_for_clients(clients: &mut [Child]) {
+    let deadline = Instant::now() + Duration::from_secs(2);
+
+    for client in clients {
+        while client.try_wait().unwrap().is_none() {
+            if Instant::now() > deadline {
+                client.kill().unwrap();
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
 }
 
-pub fn client(index: i32) {
+fn start_server_networking(args: ServerArgs) -> impl FnMut(Commands) {
+    move |mut commands: Commands| {
+        let server = RenetServer::new(replication_connection_config());
+        commands.insert_resource(Owner::Server);
+
+        let mut public_addresses = args.public_addresses();
+        if let Some(conditioned) = spawn_conditioner(&args.conditioner, public_addresses[0]) {
+            public_addresses.push(conditioned);
+        }
+
+        if use_websocket() {
+            let transport = WebSocketServerTransport::bind(args.bind_addr()).unwrap();
+            println!(
+                "Listening for WebSocket connections on {}",
+                transport.addr()
+            );
+
+            commands.insert_resource(transport);
+            commands.insert_resource(server);
+            return;
+        }
+
+        let current_time = now();
+        let socket = UdpSocket::bind(args.bind_addr()).unwrap();
+
+        let authentication = match private_key() {
+            Some(private_key) => {
+                println!("Only accepting clients with a connect token");
+                ServerAuthentication::Secure { private_key }
+            }
+            None => ServerAuthentication::Unsecure,
+        };
+
+        let server_config = ServerConfig {
+            max_clients: args.max_clients,
+            protocol_id: PROTOCOL_ID,
+            authentication,
+            current_time,
+            public_addresses,
+        };
+
+        let transport = NetcodeServerTransport::new(server_config, socket).unwrap();
+
+        commands.insert_resource(transport);
+        commands.insert_resource(server);
+    }
+}
+
+pub fn client(args: ClientArgs) {
     println!("Starting client!");
 
-    let monitor_width = 2560.0;
-    let monitor_height = 1440.0;
-    let window_width = monitor_width / 4.0;
-    let window_height = monitor_height / 4.0;
-    let position = WindowPosition::At(
-        (
-            monitor_width as i32 / 2 - window_width as i32 * (index - 1),
-            0,
-        )
-            .into(),
-    );
-    let resolution =
-        WindowResolution::new(window_width, window_height).with_scale_factor_override(1.0);
+    let (position, resolution) = args.window.client_window(args.index);
+    let server_addr = spawn_conditioner(&args.conditioner, args.server_addr);
 
     App::new()
         .add_plugins((
@@ -207,9 +484,26 @@ pub fn client(index: i32) {
                 })
                 .disable::<AudioPlugin>(/* Disabled due to audio bug with pipewire */),
             //WorldInspectorPlugin::default(),
-            GamePlugin,
+            GamePlugin::with_tick_rate(args.game.tick_rate)
+                .with_debug_controls(args.game.debug_controls),
+            NetcodeTransportPlugin,
+            WebSocketTransportPlugin,
         ))
-        .add_systems(Startup, start_client_networking)
+        .add_systems(
+            Startup,
+            start_client_networking(
+                client_identity(args.index),
+                server_addr.unwrap_or(args.server_addr),
+            ),
+        )
+        .add_systems(
+            Update,
+            own_websocket_client_id.run_if(resource_exists::<WebSocketClientTransport>()),
+        )
+        .add_systems(
+            Update,
+            show_shutdown_reason(args.exit_with_server).run_if(resource_added::<ServerShutdown>()),
+        )
         .add_systems(
             Update,
             move |mut windows: Query<&mut Window>, time: Res<Time>| {
@@ -224,25 +518,201 @@ pub fn client(index: i32) {
         .run();
 }
 
-fn start_client_networking(mut commands: Commands) {
-    let client = RenetClient::new(replication_connection_config());
+fn show_shutdown_reason(
+    exit: bool,
+) -> impl FnMut(Res<ServerShutdown>, Query<&mut Window>, EventWriter<AppExit>) {
+    move |shutdown, mut windows, mut app_exit| {
+        for mut window in &mut windows {
+            window.title = format!("Server shut down: {}", shutdown.reason);
+        }
+        if exit {
+            app_exit.send(AppExit);
+        }
+    }
+}
 
-    let current_time = SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .unwrap();
-    let client_id = rand::random();
-    let server_addr = "127.0.0.1:5000".parse::<SocketAddr>().unwrap();
-    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    let authentication = ClientAuthentication::Unsecure {
-        client_id,
-        protocol_id: PROTOCOL_ID,
-        server_addr,
-        user_data: None,
+fn bots(args: BotsArgs) {
+    if args.processes > 1 {
+        let children = args
+            .split()
+            .into_iter()
+            .enumerate()
+            .map(|(i, share)| {
+                let color = PREFIX_COLORS[i % PREFIX_COLORS.len()];
+                start_client(share.to_args(), None, format!("[P{}]", i + 1).color(color))
+            })
+            .collect_vec();
+
+        for mut child in children {
+            child.wait().unwrap();
+        }
+        return;
+    }
+
+    println!("Starting {} bots!", args.count);
+
+    // Every bot disconnects properly when interrupted
+    let interrupted = Arc::new(AtomicBool::new(false));
+    ctrlc::set_handler({
+        let interrupted = interrupted.clone();
+        move || interrupted.store(true, Ordering::Relaxed)
+    })
+    .unwrap();
+
+    let bots = (args.first_index..args.first_index + args.count)
+        .map(|index| {
+            let status = Arc::new(Mutex::new(BotStatus::default()));
+            let thread = std::thread::spawn({
+                let args = args.clone();
+                let status = status.clone();
+                let interrupted = interrupted.clone();
+                move || bot(index, args, status, interrupted)
+            });
+
+            (index, status, thread)
+        })
+        .collect_vec();
+
+    let report_interval = Duration::from_secs_f32(args.report_interval);
+    while !bots.iter().all(|(_, _, thread)| thread.is_finished()) {
+        std::thread::sleep(report_interval);
+
+        println!(
+            "{:>5} {:<32} {:>9} {:>14}",
+            "Bot", "State", "RTT", "Mispredicted"
+        );
+        for (index, status, _) in &bots {
+            let status = *status.lock().unwrap();
+            println!(
+                "{index:>5} {:<32} {:>6.1} ms {:>14}",
+                status
+                    .state
+                    .map_or("Starting".to_string(), |state| format!("{state:?}")),
+                status.rtt * 1000.0,
+                format!(
+                    "{}/{}",
+                    status.prediction.mispredicted, status.prediction.checked
+                ),
+            );
+        }
+    }
+}
+
+fn bot(index: u32, args: BotsArgs, status: Arc<Mutex<BotStatus>>, interrupted: Arc<AtomicBool>) {
+    let tick = Duration::from_secs_f64(1.0 / args.game.tick_rate as f64);
+    let identity = Identity {
+        name: format!("Bot {index}"),
+        color: rand::random(),
+        loadout: Loadout {
+            hat: Hat::ALL[index as usize % Hat::ALL.len()],
+        },
     };
 
-    let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
+    App::new()
+        .add_plugins((
+            MinimalPlugins.set(ScheduleRunnerPlugin::run_loop(tick)),
+            TransformPlugin,
+            HierarchyPlugin,
+            InputPlugin,
+            GamePlugin::with_tick_rate(args.game.tick_rate)
+                .with_visuals(false)
+                .with_debug_controls(args.game.debug_controls),
+            NetcodeTransportPlugin,
+            WebSocketTransportPlugin,
+            BotPlugin {
+                behavior: args.behavior,
+                seed: index as u64,
+                status,
+            },
+        ))
+        .add_systems(Startup, start_client_networking(identity, args.server_addr))
+        .add_systems(
+            Update,
+            own_websocket_client_id.run_if(resource_exists::<WebSocketClientTransport>()),
+        )
+        .add_systems(
+            Update,
+            show_shutdown_reason(true).run_if(resource_added::<ServerShutdown>()),
+        )
+        .add_systems(Update, move |mut app_exit: EventWriter<AppExit>| {
+            if interrupted.load(Ordering::Relaxed) {
+                app_exit.send(AppExit);
+            }
+        })
+        .run();
+}
 
-    commands.insert_resource(transport);
-    commands.insert_resource(client);
-    commands.insert_resource(Owner::Client(client_id));
+/// Set to choose the name of the player, instead of `Client <index>`
+const NAME_VAR: &str = "MP_NAME";
+
+fn client_identity(index: i32) -> Identity {
+    Identity {
+        name: std::env::var(NAME_VAR).unwrap_or_else(|_| format!("Client {index}")),
+        color: rand::random(),
+        loadout: Loadout {
+            hat: Hat::ALL[index.unsigned_abs() as usize % Hat::ALL.len()],
+        },
+    }
+}
+
+fn start_client_networking(identity: Identity, server_addr: SocketAddr) -> impl FnMut(Commands) {
+    move |mut commands: Commands| {
+        let client = RenetClient::new(replication_connection_config());
+
+        let current_time = now();
+
+        let user_data = identity
+            .to_user_data()
+            .unwrap_or_else(|e| panic!("Invalid {NAME_VAR}: {e}"));
+
+        if use_websocket() {
+            if std::env::var(CONNECT_TOKEN_VAR).is_ok() {
+                panic!("{CONNECT_TOKEN_VAR} only works over UDP, unset it or {TRANSPORT_VAR}");
+            }
+            // The server gives us our id once connected, see `own_websocket_client_id`
+            commands.insert_resource(WebSocketClientTransport::connect(
+                server_addr,
+                Some(user_data),
+            ));
+            commands.insert_resource(client);
+            return;
+        }
+
+        let authentication = if let Ok(path) = std::env::var(CONNECT_TOKEN_VAR) {
+            let mut file = std::fs::File::open(&path)
+                .unwrap_or_else(|e| panic!("Could not open connect token {path}: {e}"));
+            let connect_token = ConnectToken::read(&mut file)
+                .unwrap_or_else(|e| panic!("Invalid connect token {path}: {e}"));
+            ClientAuthentication::Secure { connect_token }
+        } else {
+            ClientAuthentication::Unsecure {
+                client_id: rand::random(),
+                protocol_id: PROTOCOL_ID,
+                server_addr,
+                user_data: Some(user_data),
+            }
+        };
+        let client_id = match authentication {
+            ClientAuthentication::Secure { ref connect_token } => connect_token.client_id,
+            ClientAuthentication::Unsecure { client_id, .. } => client_id,
+        };
+        commands.insert_resource(Owner::Client(client_id));
+
+        let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+        let transport = NetcodeClientTransport::new(current_time, authentication, socket).unwrap();
+
+        commands.insert_resource(transport);
+        commands.insert_resource(client);
+    }
+}
+
+/// Takes on the id the WebSocket server gave us when we connected
+fn own_websocket_client_id(
+    mut commands: Commands,
+    transport: Res<WebSocketClientTransport>,
+    owner: Option<Res<Owner>>,
+) {
+    if let (Some(client_id), None) = (transport.client_id(), owner) {
+        commands.insert_resource(Owner::Client(client_id.raw()));
+    }
 }
@@ -5,9 +5,18 @@ use leafwing_input_manager::axislike::DualAxisData;
 use leafwing_input_manager::prelude::*;
 use serde::{Deserialize, Serialize};
 
+use crate::game::sprite;
+use crate::identity::{Hat, Loadout};
 use crate::prediction::{resimulating, CommitActions};
+use crate::replicate::authority::Authority;
+use crate::replicate::filter::ReplicationFilter;
 use crate::replicate::schedule::{NetworkBlueprint, NetworkPreUpdate, NetworkUpdate};
-use crate::replicate::{AppExt, Owner};
+use crate::replicate::{AppExt, NetworkTick, ReplicationOptions, SendRate};
+
+/// Ticks it takes a player to get a shot back
+const RELOAD_TICKS: u64 = 60;
+/// Others see a new emote of a player at most this many ticks apart, the last one it picked
+const EMOTE_TICKS: u64 = 20;
 
 #[derive(Component, Serialize, Deserialize, Clone)]
 pub struct Control;
@@ -16,7 +25,25 @@ pub struct Control;
 pub struct Player {
     pub name: String,
     pub color: Color,
-    pub controller: Owner,
+    pub loadout: Loadout,
+}
+
+/// Shots a player has left, which only its owner is told about
+#[derive(Component, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Ammo(pub u32);
+
+impl Ammo {
+    pub const MAX: u32 = 5;
+}
+
+/// Shown above a player, picked with the number keys by whoever controls it
+#[derive(Component, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Emote {
+    #[default]
+    None,
+    Happy,
+    Sad,
+    Angry,
 }
 
 #[derive(Actionlike, Debug, Serialize, Deserialize, Clone, PartialEq, Eq, Hash, TypePath)]
@@ -29,24 +56,53 @@ pub enum Action {
     Shoot,
 }
 
-pub struct PlayerPlugin;
+pub struct PlayerPlugin {
+    pub visuals: bool,
+}
 
 impl Plugin for PlayerPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(InputManagerPlugin::<Action>::default())
-            .replicate::<Player>()
+            .replicate_with_options::<Player>(
+                ReplicationOptions::default().with_send_rate(SendRate::SpawnOnly),
+            )
+            .replicate_with_options::<Ammo>(
+                ReplicationOptions::default().with_filter(ReplicationFilter::owner()),
+            )
+            // The owner picked it, so it does not need it back. Only players emote, not what else
+            // a client may come to own.
+            .replicate_with_options::<Emote>(
+                ReplicationOptions::default()
+                    .with_authority(Authority::Client)
+                    .with_validation(|_, entity| entity.contains::<Player>())
+                    .with_filter(ReplicationFilter::except_owner())
+                    .with_send_rate(SendRate::Interval(EMOTE_TICKS)),
+            )
             .add_systems(
                 NetworkBlueprint,
-                (player_blueprint, make_player_controllable).chain(),
+                (player_blueprint, give_emotes, make_player_controllable).chain(),
+            )
+            .add_systems(NetworkUpdate, (rotate_player, reload));
+
+        if self.visuals {
+            app.add_systems(
+                NetworkBlueprint,
+                (
+                    player_visuals,
+                    control_visuals.after(make_player_controllable),
+                ),
+            )
+            .add_systems(
+                Update,
+                ((pick_emote, emote_visuals).chain(), remove_control_visuals),
             )
             .add_systems(
                 NetworkPreUpdate,
                 update_mouse_pos
                     .run_if(not(resimulating))
                     .before(CommitActions),
-            )
-            .add_systems(NetworkUpdate, rotate_player)
-        ;
+            );
+        }
     }
 }
 
@@ -62,6 +118,17 @@ fn rotate_player(mut controlled_players: Query<(&mut Transform, &ActionState<Act
     }
 }
 
+pub fn reload(tick: Res<NetworkTick>, mut players: Query<&mut Ammo>) {
+    if !tick.0.is_multiple_of(RELOAD_TICKS) {
+        return;
+    }
+    for mut ammo in &mut players {
+        if ammo.0 < Ammo::MAX {
+            ammo.0 += 1;
+        }
+    }
+}
+
 fn update_mouse_pos(
     mut action_query: Query<&mut ActionState<Action>, With<Control>>,
     camera: Query<(&Camera, &GlobalTransform)>,
@@ -81,41 +148,60 @@ fn update_mouse_pos(
     };
 }
 
-fn player_blueprint(
-    mut commands: Commands,
-    new_players: Query<(Entity, &Transform, &Player), Added<Player>>,
-    client_id: Option<Res<Owner>>,
-) {
-    for (entity, &transform, player) in &new_players {
-        let color = player.color;
-        let in_control = client_id
-            .as_ref()
-            .map(|id| player.controller == **id)
-            .unwrap_or(false);
-
+fn player_blueprint(mut commands: Commands, new_players: Query<(Entity, &Player), Added<Player>>) {
+    for (entity, player) in &new_players {
         commands.entity(entity).insert((
-            SpriteBundle {
-                sprite: Sprite {
-                    color,
-                    custom_size: Some(Vec2::splat(1.0)),
-                    ..default()
-                },
-                transform,
-                ..default()
-            },
-            player.controller,
+            GlobalTransform::default(),
             Name::from(format!("Player - {}", player.name)),
         ));
+    }
+}
+
+/// Owners are not sent the emote of their own player, and get a new one whenever they start
+/// owning one
+fn give_emotes(mut commands: Commands, players: Query<Entity, (With<Player>, Without<Emote>)>) {
+    for entity in &players {
+        commands.entity(entity).insert(Emote::default());
+    }
+}
+
+fn player_visuals(mut commands: Commands, new_players: Query<(Entity, &Player), Added<Player>>) {
+    for (entity, player) in &new_players {
+        commands.entity(entity).insert(sprite(Sprite {
+            color: player.color,
+            custom_size: Some(Vec2::splat(1.0)),
+            ..default()
+        }));
 
-        if in_control {
-            commands.entity(entity).insert(Control);
+        if let Some((color, size, y)) = hat_sprite(player.loadout.hat) {
+            commands.entity(entity).with_children(|parent| {
+                parent.spawn(SpriteBundle {
+                    sprite: Sprite {
+                        color,
+                        custom_size: Some(size),
+                        ..default()
+                    },
+                    transform: Transform::from_xyz(0.0, y, 0.1),
+                    ..default()
+                });
+            });
         }
     }
 }
 
+/// Color, size and height above the center of the player
+fn hat_sprite(hat: Hat) -> Option<(Color, Vec2, f32)> {
+    match hat {
+        Hat::None => None,
+        Hat::Cap => Some((Color::MIDNIGHT_BLUE, Vec2::new(0.8, 0.25), 0.6)),
+        Hat::Crown => Some((Color::GOLD, Vec2::new(0.6, 0.35), 0.65)),
+        Hat::Halo => Some((Color::rgba(1.0, 1.0, 0.6, 0.8), Vec2::new(0.7, 0.1), 0.85)),
+    }
+}
+
 fn make_player_controllable(
     mut commands: Commands,
-    mut controlled_players: Query<(Entity, &mut Transform), (With<Player>, Added<Control>)>,
+    mut controlled_players: Query<(Entity, &mut Transform), Added<Control>>,
 ) {
     for (entity, mut tf) in &mut controlled_players {
         tf.translation.z = 1.0;
@@ -135,9 +221,20 @@ fn make_player_controllable(
             .insert(InputManagerBundle::<Action> {
                 action_state: default(),
                 input_map,
-            })
-            .with_children(|entity| {
-                entity.spawn(SpriteBundle {
+            });
+    }
+}
+
+#[derive(Component)]
+struct ControlMarker;
+
+/// Marks the player this peer controls
+fn control_visuals(mut commands: Commands, controlled_players: Query<Entity, Added<Control>>) {
+    for entity in &controlled_players {
+        commands.entity(entity).with_children(|entity| {
+            entity.spawn((
+                ControlMarker,
+                SpriteBundle {
                     sprite: Sprite {
                         color: Color::GOLD,
                         custom_size: Some(Vec2::splat(0.3)),
@@ -145,7 +242,78 @@ fn make_player_controllable(
                     },
                     transform: Transform::from_xyz(0.0, 0.0, 1.0),
                     ..default()
-                });
-            });
+                },
+            ));
+        });
+    }
+}
+
+/// Unmarks players handed to someone else. Runs every frame, since removals are only kept for two.
+fn remove_control_visuals(
+    mut commands: Commands,
+    mut uncontrolled: RemovedComponents<Control>,
+    players: Query<&Children, Without<Control>>,
+    markers: Query<(), With<ControlMarker>>,
+) {
+    for entity in uncontrolled.read() {
+        let Ok(children) = players.get(entity) else {
+            continue;
+        };
+        for &child in children {
+            if markers.contains(child) {
+                commands.entity(child).despawn_recursive();
+            }
+        }
+    }
+}
+
+fn pick_emote(keys: Res<Input<KeyCode>>, mut emotes: Query<&mut Emote, With<Control>>) {
+    let emote = match keys.get_just_pressed().next() {
+        Some(KeyCode::Key0) => Emote::None,
+        Some(KeyCode::Key1) => Emote::Happy,
+        Some(KeyCode::Key2) => Emote::Sad,
+        Some(KeyCode::Key3) => Emote::Angry,
+        _ => return,
+    };
+    for mut current in &mut emotes {
+        *current = emote;
+    }
+}
+
+#[derive(Component)]
+struct EmoteBubble;
+
+fn emote_visuals(
+    mut commands: Commands,
+    players: Query<(Entity, &Emote, Option<&Children>), Changed<Emote>>,
+    bubbles: Query<(), With<EmoteBubble>>,
+) {
+    for (entity, emote, children) in &players {
+        for &child in children.into_iter().flatten() {
+            if bubbles.contains(child) {
+                commands.entity(child).despawn_recursive();
+            }
+        }
+
+        let color = match emote {
+            Emote::None => continue,
+            Emote::Happy => Color::YELLOW,
+            Emote::Sad => Color::CYAN,
+            Emote::Angry => Color::RED,
+        };
+        commands.entity(entity).with_children(|parent| {
+            parent.spawn((
+                EmoteBubble,
+                SpriteBundle {
+                    sprite: Sprite {
+                        color,
+                        custom_size: Some(Vec2::splat(0.3)),
+                        ..default()
+                    },
+                    transform: Transform::from_xyz(0.6, 0.6, 0.2),
+                    ..default()
+                },
+            ));
+        });
     }
 }
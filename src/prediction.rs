@@ -2,13 +2,19 @@ use std::collections::VecDeque;
 use std::marker::PhantomData;
 
 use crate::player::{Action, Control};
-use crate::replicate::schedule::{NetworkPostUpdate, NetworkPreUpdate};
-use crate::replicate::{Channel, NetworkEntities, NetworkTick, SyncedServerTick};
-use crate::transport;
+use crate::replicate::replay::ReplayRecorder;
+use crate::replicate::schedule::{
+    NetworkBlueprint, NetworkPostUpdate, NetworkPreUpdate, NetworkResync,
+};
+use crate::replicate::stats::NetworkStats;
+use crate::replicate::{
+    is_client, Channel, CopyReplicated, NetworkEntities, NetworkTick, Owner, Replicated,
+    RoundToCodecs, SyncedServerTick,
+};
+use crate::transport::client_connected;
 use bevy::prelude::*;
 use bevy::transform::systems::propagate_transforms;
 use bevy::window::PrimaryWindow;
-use bevy_renet::client_connected;
 use bevy_renet::renet::{RenetClient, RenetServer};
 use leafwing_input_manager::axislike::DualAxisData;
 use leafwing_input_manager::buttonlike::ButtonState;
@@ -22,6 +28,21 @@ mod tests;
 #[derive(Debug, Resource, Default)]
 pub struct Resimulating;
 
+/// How far the server may put the controlled player from where this client predicted it
+const MISPREDICTION_TOLERANCE: f32 = 0.01;
+/// Ticks of predicted positions kept around to compare with the server
+const PREDICTED_POSITIONS_LEN: usize = 128;
+
+/// How often the server agreed with where this client predicted its own player to be
+#[derive(Debug, Resource, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PredictionStats {
+    pub checked: u64,
+    pub mispredicted: u64,
+}
+
+#[derive(Debug, Resource, Default)]
+struct PredictedPositions(VecDeque<(NetworkTick, Vec3)>);
+
 pub struct PredictionPlugin<A>(PhantomData<A>);
 
 #[derive(Debug, SystemSet, Clone, PartialEq, Eq, Hash)]
@@ -39,8 +60,12 @@ impl<A: Actionlike + Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static
                     |mut action_query: Query<&mut ActionState<Action>, With<Control>>,
                      camera: Query<(&Camera, &GlobalTransform)>,
                      window: Query<&Window, With<PrimaryWindow>>| {
-                        let (camera, camera_tf) = camera.single();
-                        let window = window.single();
+                        // Headless apps have nothing to aim with
+                        let (Ok((camera, camera_tf)), Ok(window)) =
+                            (camera.get_single(), window.get_single())
+                        else {
+                            return;
+                        };
 
                         if let Some(m_pos) = window
                             .cursor_position()
@@ -56,8 +81,7 @@ impl<A: Actionlike + Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static
                     },
                     copy_input_for_tick::<A>,
                     apply_deferred,
-                    send_client_input::<A>
-                        .run_if(client_connected().or_else(transport::client_connected())),
+                    send_client_input::<A>.run_if(client_connected()),
                 )
                     .chain()
                     .run_if(not(resimulating))
@@ -73,7 +97,79 @@ impl<A: Actionlike + Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static
                     .run_if(resource_exists::<RenetServer>()),
             ),
         )
-        .add_systems(NetworkPostUpdate, propagate_transforms);
+        .add_systems(
+            NetworkPostUpdate,
+            (
+                propagate_transforms,
+                record_predicted_position.run_if(is_client),
+            )
+                .after(RoundToCodecs),
+        )
+        .add_systems(NetworkBlueprint, follow_ownership::<A>)
+        .add_systems(NetworkResync, check_prediction.before(CopyReplicated))
+        .init_resource::<PredictionStats>()
+        .init_resource::<PredictedPositions>();
+    }
+}
+
+/// Gives this peer control of what it owns and takes it away from what it no longer owns, so that
+/// the inputs of the previous owner stop driving an entity the server handed to someone else
+fn follow_ownership<A: Actionlike + Send + Sync + 'static>(
+    mut commands: Commands,
+    changed: Query<(Entity, &Owner, Has<Control>, Has<ActionHistory<A>>), Changed<Owner>>,
+    me: Option<Res<Owner>>,
+    mut predicted: ResMut<PredictedPositions>,
+) {
+    for (entity, owner, controlled, has_history) in &changed {
+        let ours = me.as_deref() == Some(owner);
+        if ours && !controlled {
+            commands.entity(entity).insert(Control);
+            predicted.0.clear();
+        } else if !ours && (controlled || has_history) {
+            commands
+                .entity(entity)
+                .remove::<(Control, InputMap<A>, ActionState<A>, ActionHistory<A>)>();
+        }
+    }
+}
+
+fn record_predicted_position(
+    mut predicted: ResMut<PredictedPositions>,
+    player: Query<&Transform, With<Control>>,
+    tick: Res<NetworkTick>,
+) {
+    let Ok(transform) = player.get_single() else {
+        return;
+    };
+
+    // Resimulated ticks replace what was predicted before
+    predicted.0.retain(|&(t, _)| t < *tick);
+    predicted.0.push_back((*tick, transform.translation));
+    while predicted.0.len() > PREDICTED_POSITIONS_LEN {
+        predicted.0.pop_front();
+    }
+}
+
+fn check_prediction(
+    mut stats: ResMut<PredictionStats>,
+    predicted: Res<PredictedPositions>,
+    player: Query<&Replicated<Transform>, With<Control>>,
+    synced_server_tick: Res<SyncedServerTick>,
+) {
+    let Ok(server_transform) = player.get_single() else {
+        return;
+    };
+    let Some(&(_, position)) = predicted
+        .0
+        .iter()
+        .find(|&&(t, _)| t == synced_server_tick.tick)
+    else {
+        return;
+    };
+
+    stats.checked += 1;
+    if position.distance(server_transform.translation) > MISPREDICTION_TOLERANCE {
+        stats.mispredicted += 1;
     }
 }
 
@@ -180,6 +276,7 @@ pub struct InputPacket<A: Actionlike> {
 
 fn send_client_input<A: Actionlike + Send + Sync + Serialize + 'static>(
     mut client: ResMut<RenetClient>,
+    mut stats: ResMut<NetworkStats>,
     history: Query<(Entity, &ActionHistory<A>)>,
     tick: Res<NetworkTick>,
     network_entities: Res<NetworkEntities>,
@@ -200,19 +297,41 @@ fn send_client_input<A: Actionlike + Send + Sync + Serialize + 'static>(
         history: history.clone(),
     };
 
-    client.send_message(
-        Channel::ReliableOrdered,
-        bincode::serialize(&packet).unwrap(),
-    );
+    let message = bincode::serialize(&packet).unwrap();
+    stats.sent(Channel::ReliableOrdered, message.len());
+    client.send_message(Channel::ReliableOrdered, message);
 }
 
 fn receive_client_input<A: Actionlike + for<'a> Deserialize<'a> + Send + Sync + 'static>(
     mut commands: Commands,
     mut server: ResMut<RenetServer>,
+    mut recorder: Option<ResMut<ReplayRecorder>>,
+    mut stats: ResMut<NetworkStats>,
+    tick: Res<NetworkTick>,
+    owners: Query<&Owner>,
 ) {
     for client_id in server.clients_id() {
         while let Some(message) = server.receive_message(client_id, Channel::ReliableOrdered) {
-            let packet = bincode::deserialize::<InputPacket<A>>(&message).unwrap();
+            stats.received(Channel::ReliableOrdered, message.len());
+            if let Some(recorder) = &mut recorder {
+                recorder.record_input(*tick, client_id.raw(), &message);
+            }
+            let packet = match bincode::deserialize::<InputPacket<A>>(&message) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    println!("{client_id} sent unreadable input ({err}), disconnecting it");
+                    server.disconnect(client_id);
+                    break;
+                }
+            };
+            // Inputs still on their way after the entity changed hands are dropped
+            if owners.get(packet.entity) != Ok(&Owner::Client(client_id.raw())) {
+                continue;
+            }
+            stats.input_buffer(
+                Owner::Client(client_id.raw()),
+                packet.history.tick.0 as i64 - tick.0 as i64,
+            );
             commands.entity(packet.entity).insert(packet.history);
         }
     }
@@ -1,17 +1,29 @@
+use bevy::input::InputPlugin;
 use bevy::reflect::TypePath;
+use leafwing_input_manager::clashing_inputs::ClashStrategy;
 
 use crate::replicate::schedule::*;
 use crate::replicate::*;
 use crate::test_utils::{count, tick};
+use crate::transport::memory::MemoryClientTransport;
 
 use super::*;
 
+/// Leafwing reads the input resources that the default plugins would add
+fn add_prediction<A>(app: &mut App) -> &mut App
+where
+    A: Actionlike + Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static,
+{
+    app.add_plugins((InputPlugin, PredictionPlugin::<A>::default()))
+        .init_resource::<ClashStrategy>()
+}
+
 fn create_server<A>() -> App
 where
     A: Actionlike + Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static,
 {
     let mut server = crate::test_utils::create_server();
-    server.add_plugins(PredictionPlugin::<A>::default());
+    add_prediction::<A>(&mut server);
 
     server
 }
@@ -21,11 +33,21 @@ where
     A: Actionlike + Serialize + for<'a> Deserialize<'a> + Send + Sync + 'static,
 {
     let mut client = crate::test_utils::create_client(server);
-    client.add_plugins(PredictionPlugin::<A>::default());
+    let client_id = client
+        .world
+        .resource::<MemoryClientTransport>()
+        .client_id()
+        .raw();
+    add_prediction::<A>(&mut client).insert_resource(Owner::Client(client_id));
 
     client
 }
 
+/// Who the client is, for the entities the client controls
+fn owner(client: &App) -> Owner {
+    *client.world.resource::<Owner>()
+}
+
 #[test]
 fn basic_prediction() {
     #[derive(Component, Serialize, Deserialize)]
@@ -106,7 +128,7 @@ fn predicted_spawn() {
         );
     }
 
-    server.world.spawn((Replicate, Player));
+    server.world.spawn((Replicate, Player, owner(&client)));
     tick(&mut client);
     tick(&mut server);
     tick(&mut client);
@@ -172,7 +194,7 @@ fn client_moving() {
         );
     }
 
-    server.world.spawn((Replicate, Pos(0)));
+    server.world.spawn((Replicate, Pos(0), owner(&client)));
 
     tick(&mut server);
     client.update();
@@ -191,16 +213,18 @@ fn client_moving() {
             .iter_mut(&mut client.world)
             .next()
             .unwrap();
+        client.world.entity_mut(entity).insert((
+            Control,
+            InputManagerBundle::<OneAction> {
+                action_state: default(),
+                input_map: InputMap::new([(KeyCode::A, OneAction::Left)]),
+            },
+        ));
+        // The action state follows the input map every frame, so press the key itself
         client
             .world
-            .entity_mut(entity)
-            .insert((Control, InputManagerBundle::<OneAction>::default()));
-        client
-            .world
-            .entity_mut(entity)
-            .get_mut::<ActionState<OneAction>>()
-            .unwrap()
-            .press(OneAction::Left);
+            .resource_mut::<Input<KeyCode>>()
+            .press(KeyCode::A);
     }
     tick(&mut client);
     tick(&mut client);
@@ -223,3 +247,168 @@ fn client_moving() {
         assert_eq!(pos.0, 3);
     }
 }
+
+#[test]
+fn count_mispredictions() {
+    #[derive(Resource)]
+    struct Speed(f32);
+
+    #[derive(Actionlike, Clone, Copy, TypePath, Serialize, Deserialize)]
+    enum OneAction {
+        Left,
+    }
+
+    let mut server = create_server::<OneAction>();
+    let mut client = create_client::<OneAction>(&mut server);
+    for app in [&mut server, &mut client] {
+        app.insert_resource(Speed(1.0))
+            .replicate_with::<Transform>(
+                |transform| bincode::serialize(&transform.translation).unwrap(),
+                |data| {
+                    let translation = bincode::deserialize(data).map_err(|e| e.to_string())?;
+                    Ok(Transform::from_translation(translation))
+                },
+                default(),
+            )
+            .add_systems(
+                NetworkUpdate,
+                |mut transforms: Query<&mut Transform>, speed: Res<Speed>| {
+                    for mut transform in &mut transforms {
+                        transform.translation.x += speed.0;
+                    }
+                },
+            );
+    }
+
+    server
+        .world
+        .spawn((Replicate, Transform::default(), owner(&client)));
+    tick(&mut server);
+    client.update();
+
+    let entity = client
+        .world
+        .query_filtered::<Entity, With<Transform>>()
+        .single(&client.world);
+    client.world.entity_mut(entity).insert(Control);
+
+    for _ in 0..5 {
+        tick(&mut client);
+        tick(&mut server);
+    }
+    let stats = *client.world.resource::<PredictionStats>();
+    assert!(stats.checked > 0);
+    assert_eq!(stats.mispredicted, 0);
+
+    // The client does not know that the server sped up
+    server.world.resource_mut::<Speed>().0 = 2.0;
+    for _ in 0..5 {
+        tick(&mut client);
+        tick(&mut server);
+    }
+    assert!(client.world.resource::<PredictionStats>().mispredicted > 0);
+}
+
+#[test]
+fn hand_over_control() {
+    #[derive(Component, Serialize, Deserialize)]
+    struct Pos(u64);
+
+    #[derive(Actionlike, Clone, Copy, TypePath, Serialize, Deserialize)]
+    enum OneAction {
+        Left,
+    }
+
+    let mut server = create_server::<OneAction>();
+    let mut client = create_client::<OneAction>(&mut server);
+    for app in [&mut server, &mut client] {
+        app.replicate::<Pos>().add_systems(
+            NetworkUpdate,
+            |mut positions: Query<(&mut Pos, &ActionState<OneAction>)>| {
+                for (mut pos, actions) in &mut positions {
+                    if actions.pressed(OneAction::Left) {
+                        pos.0 += 1;
+                    }
+                }
+            },
+        );
+    }
+    client
+        .world
+        .resource_mut::<Input<KeyCode>>()
+        .press(KeyCode::A);
+    let give_input = |client: &mut App| {
+        let controlled = client
+            .world
+            .query_filtered::<Entity, (With<Control>, Without<InputMap<OneAction>>)>()
+            .iter(&client.world)
+            .collect::<Vec<_>>();
+        for entity in controlled {
+            client
+                .world
+                .entity_mut(entity)
+                .insert(InputManagerBundle::<OneAction> {
+                    action_state: default(),
+                    input_map: InputMap::new([(KeyCode::A, OneAction::Left)]),
+                });
+        }
+    };
+    let server_pos = |server: &App, entity| server.world.get::<Pos>(entity).unwrap().0;
+
+    let car = server.world.spawn((Replicate, Pos(0), owner(&client))).id();
+    let npc = server
+        .world
+        .spawn((Replicate, Pos(100), Owner::Server))
+        .id();
+    for _ in 0..5 {
+        tick(&mut server);
+        tick(&mut client);
+        give_input(&mut client);
+    }
+    assert!(server_pos(&server, car) > 0);
+    assert_eq!(server_pos(&server, npc), 100);
+
+    // The server possesses the npc for the client
+    server.world.entity_mut(car).insert(Owner::Server);
+    server.world.entity_mut(npc).insert(owner(&client));
+    let car_at = server_pos(&server, car);
+    for _ in 0..10 {
+        tick(&mut server);
+        tick(&mut client);
+        give_input(&mut client);
+    }
+
+    assert_eq!(server_pos(&server, car), car_at);
+    assert!(server_pos(&server, npc) > 100);
+    let controlled = client
+        .world
+        .query_filtered::<&Pos, With<Control>>()
+        .single(&client.world);
+    assert!(controlled.0 > 100);
+    assert_eq!(count::<&ActionHistory<OneAction>>(&mut client), 1);
+}
+
+#[test]
+fn unreadable_input_disconnects_the_sender() {
+    #[derive(Actionlike, Clone, Copy, TypePath, Serialize, Deserialize)]
+    enum OneAction {
+        Left,
+    }
+
+    let mut server = create_server::<OneAction>();
+    let mut client = create_client::<OneAction>(&mut server);
+
+    client
+        .world
+        .resource_mut::<RenetClient>()
+        .send_message(Channel::ReliableOrdered, vec![9; 3]);
+    client.update();
+    tick(&mut server);
+    tick(&mut server);
+
+    assert!(server
+        .world
+        .resource::<RenetServer>()
+        .clients_id()
+        .is_empty());
+}
@@ -1,26 +1,55 @@
+use std::sync::Arc;
 use std::time::Duration;
 
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::world::EntityRef;
 use bevy::prelude::*;
-use bevy::utils::HashMap;
-use bevy_renet::renet::transport::NetcodeTransportError;
+use bevy::utils::{HashMap, HashSet};
 use bevy_renet::renet::{ChannelConfig, ConnectionConfig, RenetClient, RenetServer, SendType};
-use bevy_renet::transport::{NetcodeClientPlugin, NetcodeServerPlugin};
 use bevy_renet::{RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
+use self::authority::{
+    has_authority, receive_owned_components, send_owned_components, Authority, SentByClient,
+};
+use self::baseline::{
+    ack_packet, changed_since_sent, fill_in_left_out, has_latest, mark_sent, receive_acks,
+    track_joins, Baselines, ServerState,
+};
+use self::checksum::{
+    compare_checksums, fnv1a, record_predicted_checksums, server_checksums, ChecksumInterval,
+    ComponentChecksum, Desync, PredictedChecksums,
+};
+use self::codec::{decode_message, encode_message, Codec, Compression};
+use self::filter::{receives, visible_to, ReplicationFilter};
+use self::priority::{prioritize, retain_clients, PacketBudget, PriorityAccumulators};
+use self::removals::{client_removals, collect_removals, send_removals, ClientRemovals, Removals};
 use self::schedule::{
-    run_network_fixed, NetworkFixedTime, NetworkResync, NetworkScheduleOrder, NetworkUpdateTick,
-    TickStrategy,
+    is_send_tick, run_network_fixed, NetworkFixedTime, NetworkPostUpdate, NetworkResync,
+    NetworkScheduleOrder, NetworkSend, NetworkSendInterval, NetworkUpdateTick, TickStrategy,
 };
+use self::stats::{update_stats, NetworkStats};
 
 #[cfg(test)]
 mod tests;
 
+pub mod authority;
+mod baseline;
+pub mod checksum;
+pub mod codec;
+pub mod debug;
+pub mod filter;
+pub mod priority;
+mod removals;
+pub mod replay;
 pub mod schedule;
+pub mod stats;
 
 pub const PROTOCOL_ID: u64 = 7;
 
+/// Who controls an entity, and as a resource who this peer is. The server may give an entity to
+/// another owner at any time, for example when a player gets into a vehicle, and control follows.
 #[derive(Debug, Resource, Serialize, Deserialize, PartialEq, Component, Clone, Copy)]
 pub enum Owner {
     Server,
@@ -37,10 +66,29 @@ pub struct NetworkEntities(HashMap<Entity, Entity>);
 pub struct Replicate;
 
 #[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Channel {
     Replication = 0,
     ClientInput,
     ReliableOrdered,
+    Debug,
+    Shutdown,
+    /// Clients acknowledging complete snapshots and removals
+    Ack,
+    /// Clients sending the components they have authority over
+    Authority,
+}
+
+impl Channel {
+    pub const ALL: [Channel; 7] = [
+        Channel::Replication,
+        Channel::ClientInput,
+        Channel::ReliableOrdered,
+        Channel::Debug,
+        Channel::Shutdown,
+        Channel::Ack,
+        Channel::Authority,
+    ];
 }
 
 impl From<Channel> for u8 {
@@ -62,6 +110,10 @@ pub struct Replicated<T>(pub T);
 pub struct ReplicationPlugin {
     period: f32,
     tick_strategy: TickStrategy,
+    send_interval: u64,
+    checksum_interval: Option<u64>,
+    bandwidth_budget: Option<usize>,
+    compression: Compression,
 }
 
 impl ReplicationPlugin {
@@ -69,48 +121,130 @@ impl ReplicationPlugin {
         ReplicationPlugin {
             period,
             tick_strategy,
+            send_interval: 1,
+            checksum_interval: None,
+            bandwidth_budget: None,
+            compression: Compression::None,
         }
     }
 
     pub fn with_step(period: f32) -> Self {
         ReplicationPlugin::new(period, TickStrategy::Automatic)
     }
+
+    /// Only send replication packets every `send_interval` ticks, e.g. simulating at 60 Hz with
+    /// an interval of 3 sends at 20 Hz. Packets always describe the state at the end of a tick.
+    pub fn with_send_interval(mut self, send_interval: u64) -> Self {
+        assert!(
+            send_interval > 0,
+            "The send interval must be at least one tick"
+        );
+        self.send_interval = send_interval;
+        self
+    }
+
+    /// Include checksums of the whole state in every `checksum_interval`th replication packet,
+    /// for clients to detect desyncs with. Defaults to about once per second.
+    pub fn with_checksum_interval(mut self, checksum_interval: u64) -> Self {
+        assert!(
+            checksum_interval > 0,
+            "The checksum interval must be at least one packet"
+        );
+        self.checksum_interval = Some(checksum_interval);
+        self
+    }
+
+    /// Send each client at most about `bytes_per_second` of entity updates. The entities that
+    /// do not fit in a packet follow in later ones, by their [`priority::NetworkPriority`], how
+    /// close they are to the client's player and how long they have waited.
+    pub fn with_bandwidth_budget(mut self, bytes_per_second: usize) -> Self {
+        self.bandwidth_budget = Some(bytes_per_second);
+        self
+    }
+
+    /// Compress the replication packets the server sends. Clients read them either way.
+    pub fn with_compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
 }
 
 impl Plugin for ReplicationPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugins((
-            RenetServerPlugin,
-            RenetClientPlugin,
-            NetcodeServerPlugin,
-            NetcodeClientPlugin,
-        ))
-        .init_resource::<ReplicationFunctions>()
-        .init_resource::<NetworkScheduleOrder>()
-        .init_resource::<NetworkTick>()
-        .init_resource::<NetworkEntities>()
-        .insert_resource(NetworkFixedTime(Timer::from_seconds(
-            self.period,
-            TimerMode::Repeating,
-        )))
-        .insert_resource(self.tick_strategy)
-        .add_systems(Update, panic_on_error_system)
-        .add_systems(
-            PreUpdate,
-            receive_updated_components
-                .after(RenetReceive)
-                .run_if(is_client),
-        )
-        .add_systems(Update, run_network_fixed)
-        .add_systems(
-            PostUpdate,
-            send_updated_components.before(RenetSend).run_if(is_server),
-        )
-        .add_systems(NetworkUpdateTick, increment_tick)
-        .add_systems(
-            NetworkResync,
-            (apply_deferred.after(CopyReplicated), reset_to_server_tick),
-        );
+        let packets_per_second = 1.0 / (self.period * self.send_interval as f32);
+        let checksum_interval = self
+            .checksum_interval
+            .unwrap_or((packets_per_second.round() as u64).max(1));
+
+        if let Some(bytes_per_second) = self.bandwidth_budget {
+            let per_packet = bytes_per_second as f32 / packets_per_second;
+            app.insert_resource(PacketBudget(per_packet as usize));
+        }
+
+        app.add_plugins((RenetServerPlugin, RenetClientPlugin))
+            .init_resource::<ReplicationFunctions>()
+            .init_resource::<NetworkScheduleOrder>()
+            .init_resource::<NetworkTick>()
+            .init_resource::<NetworkEntities>()
+            .insert_resource(NetworkFixedTime(Timer::from_seconds(
+                self.period,
+                TimerMode::Repeating,
+            )))
+            .insert_resource(self.tick_strategy)
+            .insert_resource(self.compression)
+            .insert_resource(NetworkSendInterval(self.send_interval))
+            .insert_resource(ChecksumInterval(checksum_interval))
+            .init_resource::<PredictedChecksums>()
+            .init_resource::<NetworkStats>()
+            .init_resource::<PriorityAccumulators>()
+            .init_resource::<ServerState>()
+            .init_resource::<Baselines>()
+            .init_resource::<Removals>()
+            .init_resource::<SentByClient>()
+            .add_event::<Desync>()
+            .add_systems(
+                PreUpdate,
+                receive_updated_components
+                    .after(RenetReceive)
+                    .run_if(is_client),
+            )
+            .add_systems(
+                PreUpdate,
+                (track_joins, receive_acks, receive_owned_components)
+                    .chain()
+                    .after(RenetReceive)
+                    .run_if(is_server),
+            )
+            .add_systems(
+                PostUpdate,
+                send_owned_components.before(RenetSend).run_if(is_client),
+            )
+            .add_systems(Update, run_network_fixed)
+            .add_systems(
+                Last,
+                (
+                    update_stats,
+                    collect_removals.run_if(is_server),
+                    replay::flush_replay_on_exit,
+                ),
+            )
+            .add_systems(
+                NetworkSend,
+                send_updated_components.run_if(is_server.and_then(is_send_tick)),
+            )
+            .add_systems(NetworkUpdateTick, increment_tick)
+            .add_systems(
+                NetworkPostUpdate,
+                record_predicted_checksums
+                    .after(RoundToCodecs)
+                    .run_if(is_client),
+            )
+            .add_systems(
+                NetworkResync,
+                (apply_deferred.after(CopyReplicated), reset_to_server_tick),
+            );
+        app.world.init_component::<Replicate>();
+        app.replicate::<Owner>();
     }
 }
 
@@ -130,8 +264,13 @@ fn reset_to_server_tick(
     *tick = synced_server_tick.tick;
 }
 
+/// Overwrites the predicted components with the ones received from the server
 #[derive(Debug, SystemSet, Clone, PartialEq, Eq, Hash)]
-struct CopyReplicated;
+pub struct CopyReplicated;
+
+/// Rounds the components replicated with a [`Codec`] at the end of every tick
+#[derive(Debug, SystemSet, Clone, PartialEq, Eq, Hash)]
+pub struct RoundToCodecs;
 
 fn copy_replicated_component<T: Component>(world: &mut World) {
     for entity in world
@@ -145,105 +284,265 @@ fn copy_replicated_component<T: Component>(world: &mut World) {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct ReplicationPacket {
     tick: NetworkTick,
     updates: Vec<EntityUpdates>,
     despawns: Vec<Entity>,
+    /// Starts the client over: it drops what it had that is not in this packet, and gets what
+    /// changed since in the next ones
+    baseline: bool,
+    /// Only in some of the packets, see [`ReplicationPlugin::with_checksum_interval`]
+    checksums: Option<Vec<ComponentChecksum>>,
 }
 
 fn send_updated_components(world: &mut World) {
+    let tick = *world.resource::<NetworkTick>();
+    let ClientRemovals {
+        despawns,
+        mut removals,
+    } = send_removals(world, tick);
+
     let updates = world
         .query_filtered::<Entity, With<Replicate>>()
         .iter(world)
-        .map(|entity| serialize_all_components(world, entity))
-        .collect();
-
-    let despawns = world
-        .removed_components()
-        .get(world.component_id::<Replicate>().unwrap())
-        .map(|events| {
-            events
-                .get_reader()
-                .read(events)
-                .map(|e| e.clone().into())
-                .collect_vec()
+        .collect_vec()
+        .into_iter()
+        .map(|entity| EntityUpdates {
+            removals: removals.remove(&entity).unwrap_or_default(),
+            ..serialize_all_components(world, entity)
         })
-        .unwrap_or_default();
-
-    let tick = *world.resource::<NetworkTick>();
+        .collect();
+    let checksums = server_checksums(world);
 
-    let packet = ReplicationPacket {
+    // Replays get everything, whatever each client got
+    let everything = ReplicationPacket {
         tick,
         updates,
         despawns,
+        baseline: true,
+        checksums,
     };
-    let mut server = world.resource_mut::<RenetServer>();
-
-    server.broadcast_message(Channel::Replication, bincode::serialize(&packet).unwrap());
+    replay::record_replication_packet(world, &everything);
+    debug::record_sent(world, &everything);
+    let ReplicationPacket {
+        updates, checksums, ..
+    } = everything;
+
+    let compression = *world.resource::<Compression>();
+    let clients = world.resource::<RenetServer>().clients_id();
+    retain_clients(world, &clients.iter().map(|c| c.raw()).collect_vec());
+
+    for client_id in clients {
+        let client = client_id.raw();
+        let ClientRemovals { despawns, removals } = client_removals(world, client);
+        let visible = visible_to(world, client, &updates);
+        let (changed, baseline) = changed_since_sent(world, client, &visible, &removals);
+        // A joining client gets the world over as many packets as the budget takes
+        let included = prioritize(world, client, &changed)
+            .into_iter()
+            .map(|i| changed[i].clone())
+            .collect_vec();
+        mark_sent(world, client, tick, &included);
+
+        // The client only has the current state of the entities that are not still waiting
+        let sent = included.iter().map(|u| u.entity).collect::<HashSet<_>>();
+        let waiting = changed
+            .iter()
+            .map(|u| u.entity)
+            .filter(|entity| !sent.contains(entity))
+            .collect::<HashSet<_>>();
+        let checksums = checksums.as_ref().map(|checksums| {
+            checksums
+                .iter()
+                .filter(|c| !waiting.contains(&c.entity))
+                .filter(|c| receives(world, client, c.entity, c.replication_id))
+                // Held back by its send rate
+                .filter(|c| has_latest(world, client, c.entity, c.replication_id, c.checksum))
+                .copied()
+                .collect()
+        });
+
+        let packet = ReplicationPacket {
+            tick,
+            updates: included,
+            despawns,
+            baseline,
+            checksums,
+        };
+        let message = encode_message(&packet, compression);
+
+        let mut stats = world.resource_mut::<NetworkStats>();
+        stats.sent(Channel::Replication, message.len());
+        stats.replication_packet(message.len());
+
+        let mut server = world.resource_mut::<RenetServer>();
+        server.send_message(client_id, Channel::Replication, message);
+    }
 }
 
 fn receive_updated_components(world: &mut World) {
-    while let Some(packet) = world
-        .resource_scope::<RenetClient, _>(|_, mut client| {
-            //println!("Rtt: {}", client.rtt());
-            client.receive_message(Channel::Replication)
-        })
-        .map(|msg| bincode::deserialize::<ReplicationPacket>(&msg).unwrap())
+    while let Some(msg) = world
+        .resource_mut::<RenetClient>()
+        .receive_message(Channel::Replication)
     {
-        world.insert_resource(SyncedServerTick { tick: packet.tick });
-
-        for despawn in packet.despawns {
-            if let Some(local_entity) = world.resource::<NetworkEntities>().get(&despawn).copied() {
-                world.despawn(local_entity);
+        let mut stats = world.resource_mut::<NetworkStats>();
+        stats.received(Channel::Replication, msg.len());
+        stats.replication_packet(msg.len());
+
+        let mut packet = match decode_message::<ReplicationPacket>(&msg) {
+            Ok(packet) => packet,
+            Err(err) => {
+                println!("Dropped an unreadable replication packet: {err}");
+                continue;
             }
+        };
+        // The server keeps sending removals until they are acknowledged
+        let removes =
+            !packet.despawns.is_empty() || packet.updates.iter().any(|u| !u.removals.is_empty());
+        if packet.baseline || removes {
+            ack_packet(world, packet.tick);
         }
+        fill_in_left_out(world, &mut packet);
+        apply_replication_packet(world, packet);
+    }
+}
 
-        for EntityUpdates {
-            entity,
-            updates,
-            removals,
-        } in packet.updates
-        {
-            for removal in removals {
-                world.resource_scope::<ReplicationFunctions, ()>(|world, f| {
-                    let apply = &f[removal].remove;
-                    apply(world, entity);
-                })
-            }
-            for update in updates {
-                world.resource_scope::<ReplicationFunctions, ()>(|world, f| {
-                    let apply = &f[update.replication_id].update;
-                    apply(world, entity, &update.data);
-                })
-            }
+fn apply_replication_packet(world: &mut World, packet: ReplicationPacket) {
+    world.insert_resource(SyncedServerTick { tick: packet.tick });
+
+    if let Some(checksums) = &packet.checksums {
+        compare_checksums(world, packet.tick, checksums);
+    }
+
+    for despawn in packet.despawns {
+        if let Some(local_entity) = world.resource_mut::<NetworkEntities>().remove(&despawn) {
+            world.despawn(local_entity);
+        }
+    }
+
+    for EntityUpdates {
+        entity,
+        updates,
+        removals,
+    } in packet.updates
+    {
+        let local_entity = world.resource::<NetworkEntities>().get(&entity).copied();
+        for removal in removals {
+            let Some(local_entity) = local_entity.filter(|&e| world.get_entity(e).is_some()) else {
+                break;
+            };
+            world.resource_scope::<ReplicationFunctions, ()>(|world, f| {
+                let apply = &f[removal].remove;
+                apply(world, local_entity);
+            })
+        }
+        for update in updates {
+            world.resource_scope::<ReplicationFunctions, ()>(|world, functions| {
+                let f = &functions[update.replication_id];
+                // What this client has authority over is newer here than what the server echoes
+                let ours = local_entity.is_some_and(|local| {
+                    has_authority(world, local, f.authority) && (f.gather)(world, local).is_some()
+                });
+                if ours {
+                    return;
+                }
+                if let Err(err) = (f.update)(world, entity, &update.data) {
+                    println!("Dropped the unreadable {} of {entity:?}: {err}", f.name);
+                }
+            })
         }
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct EntityUpdates {
     entity: Entity,
     updates: Vec<UpdateComponent>,
     removals: Vec<usize>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct EntityDespawns {
-    entity: Entity,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct UpdateComponent {
     replication_id: usize,
     data: Vec<u8>,
 }
 
 struct ReplicationFunction {
+    name: &'static str,
     gather: Box<dyn Fn(&World, Entity) -> Option<Vec<u8>> + Send + Sync>,
-    update: Box<dyn Fn(&mut World, Entity, &[u8]) + Send + Sync>,
-    has_removed: Box<dyn Fn(&World, Entity) -> bool + Send + Sync>,
+    checksum: Box<dyn Fn(&World, Entity) -> Option<u64> + Send + Sync>,
+    update: Box<dyn Fn(&mut World, Entity, &[u8]) -> Result<(), String> + Send + Sync>,
+    restore: Box<dyn Fn(&mut World, Entity, &[u8]) -> Result<(), String> + Send + Sync>,
+    /// Takes the component from the client that owns the entity. Fails when it is unreadable,
+    /// and returns whether it was valid.
+    accept: Box<dyn Fn(&mut World, Entity, &[u8]) -> Result<bool, String> + Send + Sync>,
+    component_id: ComponentId,
     remove: Box<dyn Fn(&mut World, Entity) + Send + Sync>,
+    authority: Authority,
+    filter: Option<ReplicationFilter>,
+    send_rate: SendRate,
+}
+
+type Validation<T> = Box<dyn Fn(&T, EntityRef) -> bool + Send + Sync>;
+
+/// How a component is replicated, see [`AppExt::replicate_with_options`]
+pub struct ReplicationOptions<T> {
+    authority: Authority,
+    filter: Option<ReplicationFilter>,
+    send_rate: SendRate,
+    validate: Option<Validation<T>>,
+}
+
+impl<T> Default for ReplicationOptions<T> {
+    fn default() -> Self {
+        Self {
+            authority: default(),
+            filter: None,
+            send_rate: default(),
+            validate: None,
+        }
+    }
+}
+
+/// How often the changes of a component are sent to a client that already has it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SendRate {
+    #[default]
+    EveryChange,
+    /// At most once every this many ticks
+    Interval(u64),
+    /// Only when the client first gets it, for what never changes after the entity spawns
+    SpawnOnly,
+}
+
+impl<T> ReplicationOptions<T> {
+    /// Let the owning client send the component instead of the server
+    pub fn with_authority(mut self, authority: Authority) -> Self {
+        self.authority = authority;
+        self
+    }
+
+    /// Only take the component from the owning client when `validate` accepts it for the entity
+    pub fn with_validation(
+        mut self,
+        validate: impl Fn(&T, EntityRef) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        self.validate = Some(Box::new(validate));
+        self
+    }
+
+    /// Only send the component to some clients. The others remove it when they stop getting it.
+    pub fn with_filter(mut self, filter: ReplicationFilter) -> Self {
+        self.filter = Some(filter);
+        self
+    }
+
+    /// Send the changes of the component less often, or never
+    pub fn with_send_rate(mut self, send_rate: SendRate) -> Self {
+        self.send_rate = send_rate;
+        self
+    }
 }
 
 #[derive(Resource, Deref, DerefMut, Default)]
@@ -263,46 +562,98 @@ fn serialize_all_components(world: &World, entity: Entity) -> EntityUpdates {
                 })
             })
             .collect(),
-        removals: world
-            .resource::<ReplicationFunctions>()
-            .iter()
-            .enumerate()
-            .filter(|(_, f)| (f.has_removed)(world, entity))
-            .map(|(replication_id, _)| replication_id)
-            .collect(),
-    }
-}
-
-// If any error is found we just panic
-pub fn panic_on_error_system(mut renet_error: EventReader<NetcodeTransportError>) {
-    if let Some(e) = renet_error.read().next() {
-        panic!("{}", e);
+        removals: vec![],
     }
 }
 
 // Implement convenience method on App
 pub trait AppExt {
     fn replicate<T: Component + Serialize + for<'a> Deserialize<'a>>(&mut self) -> &mut Self;
+    fn replicate_with_options<T: Component + Serialize + for<'a> Deserialize<'a>>(
+        &mut self,
+        options: ReplicationOptions<T>,
+    ) -> &mut Self;
     fn replicate_with<T: Component>(
         &mut self,
         gather: impl Fn(&T) -> Vec<u8> + Send + Sync + 'static,
-        update: impl Fn(&[u8]) -> T + Send + Sync + 'static,
+        update: impl Fn(&[u8]) -> Result<T, String> + Send + Sync + 'static,
+        options: ReplicationOptions<T>,
+    ) -> &mut Self;
+    fn replicate_codec<T: Component>(
+        &mut self,
+        codec: impl Codec<T>,
+        options: ReplicationOptions<T>,
     ) -> &mut Self;
 }
 
 impl AppExt for App {
     fn replicate<T: Component + Serialize + for<'a> Deserialize<'a>>(&mut self) -> &mut Self {
+        self.replicate_with_options::<T>(default())
+    }
+
+    fn replicate_with_options<T: Component + Serialize + for<'a> Deserialize<'a>>(
+        &mut self,
+        options: ReplicationOptions<T>,
+    ) -> &mut Self {
         self.replicate_with::<T>(
             |component| bincode::serialize(component).unwrap(),
-            |data| bincode::deserialize(data).unwrap(),
+            |data| bincode::deserialize(data).map_err(|err| err.to_string()),
+            options,
+        )
+    }
+
+    /// Server and client both round the component the way the codec does after every tick, so
+    /// that the client resimulates from the same state the server simulated from
+    fn replicate_codec<T: Component>(
+        &mut self,
+        codec: impl Codec<T>,
+        options: ReplicationOptions<T>,
+    ) -> &mut Self {
+        let round = codec.clone();
+        let decode = codec.clone();
+
+        self.add_systems(
+            NetworkPostUpdate,
+            (move |mut replicated: Query<&mut T, With<Replicate>>,
+                   mut others: Query<&mut T, Without<Replicate>>,
+                   entities: Res<NetworkEntities>| {
+                for mut component in &mut replicated {
+                    *component = round.round_trip(&component);
+                }
+                for &entity in entities.values() {
+                    if let Ok(mut component) = others.get_mut(entity) {
+                        *component = round.round_trip(&component);
+                    }
+                }
+            })
+            .in_set(RoundToCodecs),
+        );
+        self.replicate_with::<T>(
+            move |component| codec.encode_vec(component),
+            move |data| decode.decode_slice(data),
+            options,
         )
     }
 
     fn replicate_with<T: Component>(
         &mut self,
         gather: impl Fn(&T) -> Vec<u8> + Send + Sync + 'static,
-        update: impl Fn(&[u8]) -> T + Send + Sync + 'static,
+        update: impl Fn(&[u8]) -> Result<T, String> + Send + Sync + 'static,
+        options: ReplicationOptions<T>,
     ) -> &mut Self {
+        let gather = Arc::new(gather);
+        let checksum = gather.clone();
+        let update = Arc::new(update);
+        let restore = update.clone();
+        let accept = update.clone();
+        let component_id = self.world.init_component::<T>();
+        let ReplicationOptions {
+            authority,
+            filter,
+            send_rate,
+            validate,
+        } = options;
+
         self.add_systems(
             NetworkResync,
             copy_replicated_component::<T>.in_set(CopyReplicated),
@@ -310,15 +661,21 @@ impl AppExt for App {
         self.world
             .resource_mut::<ReplicationFunctions>()
             .push(ReplicationFunction {
+                name: std::any::type_name::<T>(),
                 gather: Box::new(move |world, entity| {
                     let component = world.entity(entity).get::<T>()?;
 
                     Some(gather(component))
                 }),
+                checksum: Box::new(move |world, entity| {
+                    let component = world.entity(entity).get::<T>()?;
+
+                    Some(fnv1a(&checksum(component)))
+                }),
                 update: Box::new(move |world, entity, data| {
                     let local_entity = world.resource::<NetworkEntities>().get(&entity).copied();
 
-                    let component = Replicated(update(data));
+                    let component = Replicated(update(data)?);
                     match local_entity {
                         Some(local_entity) => {
                             if let Some(mut e) = world.get_entity_mut(local_entity) {
@@ -332,25 +689,29 @@ impl AppExt for App {
                                 .insert(entity, local_entity);
                         }
                     }
+                    Ok(())
                 }),
-                has_removed: Box::new(move |world, entity| {
-                    let Some(replicate_component_id) = world.component_id::<T>() else {
-                        return false;
-                    };
-
-                    if let Some(events) = world.removed_components().get(replicate_component_id) {
-                        for event in events.get_reader().read(events) {
-                            if entity == (*event).clone().into() {
-                                return true;
-                            }
+                restore: Box::new(move |world, entity, data| {
+                    world.entity_mut(entity).insert(restore(data)?);
+                    Ok(())
+                }),
+                accept: Box::new(move |world, entity, data| {
+                    let component = accept(data)?;
+                    if let Some(validate) = &validate {
+                        if !validate(&component, world.entity(entity)) {
+                            return Ok(false);
                         }
                     }
-
-                    false
+                    world.entity_mut(entity).insert(component);
+                    Ok(true)
                 }),
+                component_id,
                 remove: Box::new(move |world, entity| {
-                    world.entity_mut(entity).remove::<T>();
+                    world.entity_mut(entity).remove::<(T, Replicated<T>)>();
                 }),
+                authority,
+                filter,
+                send_rate,
             });
         self
     }
@@ -372,6 +733,34 @@ pub fn replication_connection_config() -> ConnectionConfig {
                 resend_time: Duration::from_millis(300),
             },
         },
+        ChannelConfig {
+            channel_id: Channel::Debug as u8,
+            max_memory_usage_bytes: 1024 * 1024,
+            send_type: SendType::ReliableOrdered {
+                resend_time: Duration::from_millis(300),
+            },
+        },
+        ChannelConfig {
+            channel_id: Channel::Shutdown as u8,
+            max_memory_usage_bytes: 64 * 1024,
+            send_type: SendType::ReliableOrdered {
+                resend_time: Duration::from_millis(100),
+            },
+        },
+        ChannelConfig {
+            channel_id: Channel::Ack as u8,
+            max_memory_usage_bytes: 64 * 1024,
+            send_type: SendType::ReliableOrdered {
+                resend_time: Duration::from_millis(100),
+            },
+        },
+        ChannelConfig {
+            channel_id: Channel::Authority as u8,
+            max_memory_usage_bytes: 1024 * 1024,
+            send_type: SendType::ReliableOrdered {
+                resend_time: Duration::from_millis(100),
+            },
+        },
     ];
 
     ConnectionConfig {
@@ -0,0 +1,155 @@
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+use bevy_renet::renet::{ClientId, RenetClient, RenetServer};
+use serde::{Deserialize, Serialize};
+
+use super::checksum::fnv1a;
+use super::stats::NetworkStats;
+use super::{
+    Channel, EntityUpdates, NetworkEntities, Owner, ReplicationFunctions, UpdateComponent,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Who sends a replicated component, see [`super::ReplicationOptions::with_authority`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Authority {
+    #[default]
+    Server,
+    /// The client in the entity's [`Owner`] sends it, and the server passes it on to the others.
+    /// For what the server does not simulate, like an emote or where a player aims.
+    Client,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ClientUpdates {
+    updates: Vec<EntityUpdates>,
+}
+
+/// On the client, checksums of the components it last sent the server
+#[derive(Debug, Resource, Default)]
+pub(super) struct SentByClient(HashMap<(Entity, usize), u64>);
+
+/// Whether this client has the say over the component on this local entity
+pub(super) fn has_authority(world: &World, entity: Entity, authority: Authority) -> bool {
+    let Some(&me) = world.get_resource::<Owner>() else {
+        return false;
+    };
+    authority == Authority::Client && world.get::<Owner>(entity) == Some(&me)
+}
+
+/// Sends the server the client-authoritative components of the entities this client owns that
+/// changed since they were last sent
+pub(super) fn send_owned_components(world: &mut World) {
+    if !world.resource::<RenetClient>().is_connected() {
+        return;
+    }
+
+    let mut sent = world.remove_resource::<SentByClient>().unwrap();
+    let functions = world.resource::<ReplicationFunctions>();
+    let network_entities = world.resource::<NetworkEntities>();
+    sent.0
+        .retain(|(entity, _), _| network_entities.contains_key(entity));
+
+    let mut updates = vec![];
+    for (&server_entity, &entity) in network_entities.iter() {
+        let components = functions
+            .iter()
+            .enumerate()
+            .filter(|(_, f)| has_authority(world, entity, f.authority))
+            .filter_map(|(replication_id, f)| {
+                let data = (f.gather)(world, entity)?;
+                let checksum = fnv1a(&data);
+                let changed = sent.0.insert((server_entity, replication_id), checksum);
+                (changed != Some(checksum)).then_some(UpdateComponent {
+                    replication_id,
+                    data,
+                })
+            })
+            .collect::<Vec<_>>();
+
+        if !components.is_empty() {
+            updates.push(EntityUpdates {
+                entity: server_entity,
+                updates: components,
+                removals: vec![],
+            });
+        }
+    }
+    world.insert_resource(sent);
+
+    if updates.is_empty() {
+        return;
+    }
+    let message = bincode::serialize(&ClientUpdates { updates }).unwrap();
+    world
+        .resource_mut::<NetworkStats>()
+        .sent(Channel::Authority, message.len());
+    world
+        .resource_mut::<RenetClient>()
+        .send_message(Channel::Authority, message);
+}
+
+/// Takes the components clients have authority over from their owners, and only from them.
+/// Clients that send what no working client would are disconnected.
+pub(super) fn receive_owned_components(world: &mut World) {
+    let clients = world.resource::<RenetServer>().clients_id();
+    for client_id in clients {
+        while let Some(message) = world
+            .resource_mut::<RenetServer>()
+            .receive_message(client_id, Channel::Authority)
+        {
+            world
+                .resource_mut::<NetworkStats>()
+                .received(Channel::Authority, message.len());
+            let applied = bincode::deserialize(&message)
+                .map_err(|err| format!("unreadable components ({err})"))
+                .and_then(|ClientUpdates { updates }| apply_updates(world, client_id, updates));
+            if let Err(err) = applied {
+                println!("{client_id} sent {err}, disconnecting it");
+                world.resource_mut::<RenetServer>().disconnect(client_id);
+                break;
+            }
+        }
+    }
+}
+
+fn apply_updates(
+    world: &mut World,
+    client_id: ClientId,
+    updates: Vec<EntityUpdates>,
+) -> Result<(), String> {
+    world.resource_scope(|world, functions: Mut<ReplicationFunctions>| {
+        for EntityUpdates {
+            entity, updates, ..
+        } in updates
+        {
+            let owner = world
+                .get_entity(entity)
+                .and_then(|e| e.get::<Owner>())
+                .copied();
+            for UpdateComponent {
+                replication_id,
+                data,
+            } in updates
+            {
+                let f = functions
+                    .get(replication_id)
+                    .ok_or("an unknown component")?;
+                // Ownership may have changed since the client sent it
+                if f.authority != Authority::Client || owner != Some(Owner::Client(client_id.raw()))
+                {
+                    println!("{client_id} may not change {} of {entity:?}", f.name);
+                    continue;
+                }
+                match (f.accept)(world, entity, &data) {
+                    Ok(true) => (),
+                    Ok(false) => println!("{client_id} sent an invalid {} of {entity:?}", f.name),
+                    Err(err) => return Err(format!("an unreadable {} ({err})", f.name)),
+                }
+            }
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,191 @@
+use crate::replicate::*;
+use crate::test_utils::*;
+use crate::transport::memory::MemoryClientTransport;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Emote(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u32);
+
+fn setup(app: &mut App) {
+    app.replicate::<Num>().replicate_with_options::<Emote>(
+        ReplicationOptions::default()
+            .with_authority(Authority::Client)
+            .with_validation(|emote: &Emote, _| emote.0 < 10),
+    );
+}
+
+fn join(server: &mut App) -> (App, Owner) {
+    let mut client = create_client(server);
+    setup(&mut client);
+    let owner = Owner::Client(
+        client
+            .world
+            .resource::<MemoryClientTransport>()
+            .client_id()
+            .raw(),
+    );
+    client.insert_resource(owner);
+    (client, owner)
+}
+
+/// The server's entity for the one on the client
+fn on_client<C: Component + Copy>(client: &mut App) -> C {
+    *client.world.query::<&C>().single(&client.world)
+}
+
+fn set_on_client<C: Component>(client: &mut App, component: C) {
+    let entity = client
+        .world
+        .query_filtered::<Entity, With<Owner>>()
+        .single(&client.world);
+    client.world.entity_mut(entity).insert(component);
+}
+
+/// Sends what changed on the clients, then the server's state back to them
+fn exchange(server: &mut App, clients: &mut [&mut App]) {
+    for client in clients.iter_mut() {
+        client.update();
+    }
+    tick(server);
+    for client in clients.iter_mut() {
+        client.update();
+    }
+}
+
+#[test]
+fn owners_send_it_to_everyone() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    let (mut other, _) = join(&mut server);
+    let entity = server
+        .world
+        .spawn((Replicate, owner_id, Emote(0), Num(0)))
+        .id();
+    exchange(&mut server, &mut [&mut owner, &mut other]);
+
+    set_on_client(&mut owner, Emote(3));
+    exchange(&mut server, &mut [&mut owner, &mut other]);
+
+    assert_eq!(server.world.get::<Emote>(entity), Some(&Emote(3)));
+    assert_eq!(on_client::<Emote>(&mut other), Emote(3));
+    assert_eq!(on_client::<Emote>(&mut owner), Emote(3));
+}
+
+#[test]
+fn others_are_refused() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    let (mut other, _) = join(&mut server);
+    let entity = server
+        .world
+        .spawn((Replicate, owner_id, Emote(0), Num(0)))
+        .id();
+    exchange(&mut server, &mut [&mut owner, &mut other]);
+
+    // The other client thinks it owns the entity, but the server knows better
+    let other_entity = other
+        .world
+        .query_filtered::<Entity, With<Emote>>()
+        .single(&other.world);
+    other.world.entity_mut(other_entity).insert(owner_id);
+    *other.world.resource_mut::<Owner>() = owner_id;
+    set_on_client(&mut other, Emote(9));
+    exchange(&mut server, &mut [&mut owner, &mut other]);
+
+    assert_eq!(server.world.get::<Emote>(entity), Some(&Emote(0)));
+    assert_eq!(on_client::<Emote>(&mut owner), Emote(0));
+}
+
+#[test]
+fn the_server_keeps_the_rest() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    let entity = server
+        .world
+        .spawn((Replicate, owner_id, Emote(0), Num(0)))
+        .id();
+    exchange(&mut server, &mut [&mut owner]);
+
+    set_on_client(&mut owner, Num(5));
+    exchange(&mut server, &mut [&mut owner]);
+
+    assert_eq!(server.world.get::<Num>(entity), Some(&Num(0)));
+    assert_eq!(on_client::<Num>(&mut owner), Num(0));
+}
+
+#[test]
+fn owners_keep_what_they_set() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    server.world.spawn((Replicate, owner_id, Emote(0)));
+    exchange(&mut server, &mut [&mut owner]);
+
+    // The server sends the old emote before it gets the new one
+    set_on_client(&mut owner, Emote(3));
+    tick(&mut server);
+    owner.update();
+    assert_eq!(on_client::<Emote>(&mut owner), Emote(3));
+}
+
+#[test]
+fn invalid_values_are_refused() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    let entity = server.world.spawn((Replicate, owner_id, Emote(0))).id();
+    exchange(&mut server, &mut [&mut owner]);
+
+    set_on_client(&mut owner, Emote(10));
+    exchange(&mut server, &mut [&mut owner]);
+    assert_eq!(server.world.get::<Emote>(entity), Some(&Emote(0)));
+
+    set_on_client(&mut owner, Emote(9));
+    exchange(&mut server, &mut [&mut owner]);
+    assert_eq!(server.world.get::<Emote>(entity), Some(&Emote(9)));
+}
+
+#[test]
+fn unreadable_components_disconnect_the_sender() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    let entity = server.world.spawn((Replicate, owner_id, Emote(0))).id();
+    exchange(&mut server, &mut [&mut owner]);
+
+    let emote = server
+        .world
+        .resource::<ReplicationFunctions>()
+        .iter()
+        .position(|f| f.name.ends_with("Emote"))
+        .unwrap();
+    let truncated = ClientUpdates {
+        updates: vec![EntityUpdates {
+            entity,
+            updates: vec![UpdateComponent {
+                replication_id: emote,
+                data: vec![1],
+            }],
+            removals: vec![],
+        }],
+    };
+    owner
+        .world
+        .resource_mut::<RenetClient>()
+        .send_message(Channel::Authority, bincode::serialize(&truncated).unwrap());
+    exchange(&mut server, &mut [&mut owner]);
+
+    assert_eq!(server.world.get::<Emote>(entity), Some(&Emote(0)));
+    assert!(server
+        .world
+        .resource::<RenetServer>()
+        .clients_id()
+        .is_empty());
+}
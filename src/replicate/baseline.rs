@@ -0,0 +1,319 @@
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+use bevy_renet::renet::{RenetClient, RenetServer, ServerEvent};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::checksum::fnv1a;
+use super::stats::NetworkStats;
+use super::{
+    Channel, EntityUpdates, NetworkEntities, NetworkTick, ReplicationFunctions, ReplicationPacket,
+    SendRate, UpdateComponent,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// What the server sent each client, so that after the packet it starts from it only gets what
+/// changed. The replication channel is reliable and ordered, so nothing is sent twice.
+#[derive(Debug, Resource, Default)]
+pub(super) struct Baselines(HashMap<u64, Baseline>);
+
+#[derive(Debug, Default)]
+struct Baseline {
+    /// Whether the client got the packet that starts it over from nothing but what is in it
+    started: bool,
+    /// The last packet the client acknowledged
+    acked: Option<NetworkTick>,
+    /// The components as they were last sent
+    sent: HashMap<Entity, HashMap<usize, Sent>>,
+    /// Components the client was told to remove, by the tick of the first packet that did, which
+    /// are sent again until it acknowledges that packet
+    removing: HashMap<(Entity, usize), NetworkTick>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sent {
+    checksum: u64,
+    tick: NetworkTick,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Ack {
+    tick: NetworkTick,
+}
+
+/// On the client, what the server last sent of every entity
+#[derive(Debug, Resource, Default)]
+pub(super) struct ServerState(HashMap<Entity, Vec<UpdateComponent>>);
+
+/// Starts over with every client that connects
+pub(super) fn track_joins(mut events: EventReader<ServerEvent>, mut baselines: ResMut<Baselines>) {
+    for event in events.read() {
+        match event {
+            ServerEvent::ClientConnected { client_id } => {
+                baselines.0.insert(client_id.raw(), Baseline::default());
+            }
+            ServerEvent::ClientDisconnected { client_id, .. } => {
+                baselines.0.remove(&client_id.raw());
+            }
+        }
+    }
+}
+
+/// Starts every client over, with what is sent to it from then on
+pub(super) fn restart_baselines(world: &mut World) {
+    for baseline in world.resource_mut::<Baselines>().0.values_mut() {
+        *baseline = Baseline::default();
+    }
+}
+
+pub(super) fn receive_acks(
+    mut server: ResMut<RenetServer>,
+    mut baselines: ResMut<Baselines>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive_message(client_id, Channel::Ack) {
+            stats.received(Channel::Ack, message.len());
+            let Ok(Ack { tick }) = bincode::deserialize(&message) else {
+                println!("{client_id} sent an unreadable ack");
+                continue;
+            };
+            let baseline = baselines.0.entry(client_id.raw()).or_default();
+            baseline.acked = Some(tick);
+        }
+    }
+}
+
+/// Tells the server that the packet of this tick arrived, and with it every packet before
+pub(super) fn ack_packet(world: &mut World, tick: NetworkTick) {
+    let message = bincode::serialize(&Ack { tick }).unwrap();
+    world
+        .resource_mut::<NetworkStats>()
+        .sent(Channel::Ack, message.len());
+    world
+        .resource_mut::<RenetClient>()
+        .send_message(Channel::Ack, message);
+}
+
+/// The last packet the client acknowledged
+pub(super) fn acked(world: &World, client: u64) -> Option<NetworkTick> {
+    world.resource::<Baselines>().0.get(&client)?.acked
+}
+
+/// Whether every client acknowledged the packet of this tick
+pub(super) fn everyone_acked(world: &World, tick: NetworkTick) -> bool {
+    world
+        .resource::<Baselines>()
+        .0
+        .values()
+        .all(|baseline| baseline.acked.is_some_and(|acked| acked >= tick))
+}
+
+/// Whether a component that was last sent as `last` is sent again as `data` on this tick
+fn is_due(rate: SendRate, last: Option<&Sent>, data: &[u8], tick: NetworkTick) -> bool {
+    let Some(last) = last else {
+        return true;
+    };
+    last.checksum != fnv1a(data)
+        && match rate {
+            SendRate::EveryChange => true,
+            SendRate::Interval(interval) => tick.0 >= last.tick.0 + interval,
+            SendRate::SpawnOnly => false,
+        }
+}
+
+/// The updates the client does not have yet with the components it still has to remove, and
+/// whether they start the client over. The client also removes what it had but is no longer in
+/// `updates`, like filtered out components.
+pub(super) fn changed_since_sent(
+    world: &mut World,
+    client: u64,
+    updates: &[EntityUpdates],
+    removals: &HashMap<Entity, Vec<usize>>,
+) -> (Vec<EntityUpdates>, bool) {
+    let tick = *world.resource::<NetworkTick>();
+    let rates = world
+        .resource::<ReplicationFunctions>()
+        .iter()
+        .map(|f| f.send_rate)
+        .collect_vec();
+    let mut baselines = world.resource_mut::<Baselines>();
+    let baseline = baselines.0.entry(client).or_default();
+
+    let present = updates
+        .iter()
+        .map(|u| {
+            (
+                u.entity,
+                u.updates.iter().map(|c| c.replication_id).collect_vec(),
+            )
+        })
+        .collect::<HashMap<_, _>>();
+    baseline
+        .sent
+        .retain(|entity, _| present.contains_key(entity));
+    let acked = baseline.acked;
+    baseline
+        .removing
+        .retain(|(entity, replication_id), &mut tick| {
+            acked.is_none_or(|acked| tick > acked)
+                && present
+                    .get(entity)
+                    .is_some_and(|components| !components.contains(replication_id))
+        });
+
+    let removals_of = |entity| removals.get(&entity).cloned().unwrap_or_default();
+    let starts_over = !baseline.started;
+    baseline.started = true;
+
+    let changed = updates
+        .iter()
+        .filter_map(|update| {
+            let sent = baseline.sent.get(&update.entity);
+            let components = update
+                .updates
+                .iter()
+                .filter(|c| {
+                    let last = sent.and_then(|s| s.get(&c.replication_id));
+                    is_due(rates[c.replication_id], last, &c.data, tick)
+                })
+                .cloned()
+                .collect_vec();
+            let left_out = sent
+                .into_iter()
+                .flat_map(|s| s.keys())
+                .chain(
+                    baseline
+                        .removing
+                        .keys()
+                        .filter(|(entity, _)| *entity == update.entity)
+                        .map(|(_, replication_id)| replication_id),
+                )
+                .filter(|&&id| !update.updates.iter().any(|c| c.replication_id == id))
+                .copied();
+            let removals = removals_of(update.entity)
+                .into_iter()
+                .chain(left_out)
+                .unique()
+                .collect_vec();
+
+            (!components.is_empty() || !removals.is_empty()).then_some(EntityUpdates {
+                entity: update.entity,
+                updates: components,
+                removals,
+            })
+        })
+        .collect();
+    (changed, starts_over)
+}
+
+pub(super) fn mark_sent(world: &mut World, client: u64, tick: NetworkTick, sent: &[EntityUpdates]) {
+    let mut baselines = world.resource_mut::<Baselines>();
+    let baseline = baselines.0.entry(client).or_default();
+
+    for update in sent {
+        let components = baseline.sent.entry(update.entity).or_default();
+        for &removal in &update.removals {
+            if components.remove(&removal).is_some() {
+                baseline.removing.insert((update.entity, removal), tick);
+            }
+        }
+        for component in &update.updates {
+            let sent = Sent {
+                checksum: fnv1a(&component.data),
+                tick,
+            };
+            components.insert(component.replication_id, sent);
+        }
+    }
+}
+
+/// Whether the client was sent the component as it is now
+pub(super) fn has_latest(
+    world: &World,
+    client: u64,
+    entity: Entity,
+    replication_id: usize,
+    checksum: u64,
+) -> bool {
+    world
+        .resource::<Baselines>()
+        .0
+        .get(&client)
+        .and_then(|baseline| baseline.sent.get(&entity)?.get(&replication_id))
+        .is_some_and(|sent| sent.checksum == checksum)
+}
+
+/// Despawns the entities from the server that are not in `present`
+pub(super) fn despawn_missing(world: &mut World, present: &HashSet<Entity>) {
+    let gone = world
+        .resource::<NetworkEntities>()
+        .iter()
+        .filter(|(remote, _)| !present.contains(*remote))
+        .map(|(&remote, &local)| (remote, local))
+        .collect_vec();
+    for (remote, local) in gone {
+        world.resource_mut::<NetworkEntities>().remove(&remote);
+        if let Some(entity) = world.get_entity_mut(local) {
+            entity.despawn_recursive();
+        }
+    }
+}
+
+/// Completes the packet with what the server sent before of the entities and components it left
+/// out, so that resyncing resets everything. Those may be as old as the last packet they were in.
+pub(super) fn fill_in_left_out(world: &mut World, packet: &mut ReplicationPacket) {
+    let mut state = world.resource_mut::<ServerState>();
+    if packet.baseline {
+        state.0.clear();
+    }
+    for despawn in &packet.despawns {
+        state.0.remove(despawn);
+    }
+
+    for update in &packet.updates {
+        let components = state.0.entry(update.entity).or_default();
+        components.retain(|c| {
+            !update.removals.contains(&c.replication_id)
+                && !update
+                    .updates
+                    .iter()
+                    .any(|u| u.replication_id == c.replication_id)
+        });
+        components.extend(update.updates.iter().cloned());
+    }
+
+    // The entities in the packet keep its order, which is the order new ones spawn in
+    let mut removals = packet
+        .updates
+        .iter()
+        .map(|u| (u.entity, u.removals.clone()))
+        .collect::<HashMap<_, _>>();
+    let entities = packet
+        .updates
+        .iter()
+        .map(|u| u.entity)
+        .chain(
+            state
+                .0
+                .keys()
+                .filter(|e| !removals.contains_key(*e))
+                .copied(),
+        )
+        .collect_vec();
+    packet.updates = entities
+        .into_iter()
+        .map(|entity| EntityUpdates {
+            entity,
+            updates: state.0[&entity].clone(),
+            removals: removals.remove(&entity).unwrap_or_default(),
+        })
+        .collect();
+
+    if packet.baseline {
+        let present = state.0.keys().copied().collect();
+        despawn_missing(world, &present);
+    }
+}
@@ -0,0 +1,237 @@
+use crate::replicate::*;
+use crate::test_utils::*;
+use crate::transport::memory::MemoryClientTransport;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct Num(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+struct Still(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Name(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Slow(u32);
+
+fn setup(server: &mut App) {
+    server
+        .replicate::<Num>()
+        .replicate::<Still>()
+        .replicate_with_options::<Name>(
+            ReplicationOptions::default().with_send_rate(SendRate::SpawnOnly),
+        )
+        .replicate_with_options::<Slow>(
+            ReplicationOptions::default().with_send_rate(SendRate::Interval(4)),
+        );
+}
+
+fn join(server: &mut App) -> App {
+    let mut client = create_client(server);
+    setup(&mut client);
+    client
+}
+
+fn acked(server: &App, client: &App) -> bool {
+    let client_id = client
+        .world
+        .resource::<MemoryClientTransport>()
+        .client_id()
+        .raw();
+    server
+        .world
+        .resource::<Baselines>()
+        .0
+        .get(&client_id)
+        .is_some_and(|baseline| baseline.acked.is_some())
+}
+
+fn last_packet(app: &App) -> usize {
+    app.world
+        .resource::<NetworkStats>()
+        .replication_packets
+        .last
+}
+
+fn sorted<C: Component + Copy + Ord>(app: &mut App) -> Vec<C> {
+    app.world
+        .query::<&C>()
+        .iter(&app.world)
+        .copied()
+        .sorted()
+        .collect()
+}
+
+#[test]
+fn sent_once() {
+    let mut server = create_server();
+    setup(&mut server);
+    let mut client = join(&mut server);
+    for i in 0..20 {
+        server.world.spawn((Replicate, Still(i)));
+    }
+
+    tick(&mut server);
+    let baseline = last_packet(&server);
+    assert!(!acked(&server, &client));
+
+    // Whether or not the client acknowledged it, the next packets only have what changed
+    tick(&mut server);
+    assert!(last_packet(&server) * 5 < baseline);
+
+    client.update();
+    server.update();
+    assert!(acked(&server, &client));
+    assert_eq!(count::<&Still>(&mut client), 20);
+}
+
+#[test]
+fn joining_keeps_to_the_budget() {
+    let budget = 200;
+    let mut server = create_server_with(
+        ReplicationPlugin::new(0.01, TickStrategy::Manual).with_bandwidth_budget(100 * budget),
+    );
+    setup(&mut server);
+    for i in 0..100 {
+        server.world.spawn((Replicate, Still(i)));
+    }
+    tick(&mut server);
+
+    let mut client = join(&mut server);
+    let mut packets = 0;
+    while count::<&Still>(&mut client) < 100 {
+        tick(&mut server);
+        assert!(last_packet(&server) < 2 * budget);
+        client.update();
+        packets += 1;
+    }
+    assert!(packets > 3);
+    assert_eq!(sorted::<Still>(&mut client), sorted::<Still>(&mut server));
+}
+
+#[test]
+fn only_changes_after_the_ack() {
+    let mut server = create_server();
+    setup(&mut server);
+    let mut client = join(&mut server);
+    for i in 0..20 {
+        server.world.spawn((Replicate, Still(i)));
+    }
+    let moving = server.world.spawn((Replicate, Num(0))).id();
+
+    tick(&mut server);
+    let baseline = last_packet(&server);
+    client.update();
+    server.update();
+
+    for i in 1..10 {
+        server.world.get_mut::<Num>(moving).unwrap().0 = i;
+        tick(&mut server);
+        assert!(last_packet(&server) * 5 < baseline);
+
+        client.update();
+        assert_eq!(sorted::<Num>(&mut client), vec![Num(i)]);
+        assert_eq!(count::<&Still>(&mut client), 20);
+    }
+}
+
+#[test]
+fn late_joiners_see_what_does_not_change() {
+    let mut server = create_server();
+    setup(&mut server);
+    let mut first = join(&mut server);
+    for i in 0..5 {
+        server.world.spawn((Replicate, Still(i)));
+    }
+    for _ in 0..5 {
+        tick(&mut server);
+        first.update();
+    }
+
+    let mut second = join(&mut server);
+    tick(&mut server);
+    for client in [&mut first, &mut second] {
+        client.update();
+        assert_eq!(count::<&Still>(client), 5);
+    }
+}
+
+#[test]
+fn join_while_the_world_changes() {
+    let mut server = create_server();
+    setup(&mut server);
+    let mut entities = vec![];
+
+    // Entities come and go on every tick, including the ones the client joins on
+    let mut change = |server: &mut App, i: u32| {
+        entities.push(server.world.spawn((Replicate, Num(i))).id());
+        if i.is_multiple_of(3) {
+            server.world.despawn(entities.remove(0));
+        }
+        for &entity in &entities {
+            server.world.get_mut::<Num>(entity).unwrap().0 += 100;
+        }
+    };
+    for i in 0..5 {
+        change(&mut server, i);
+        tick(&mut server);
+    }
+
+    let mut client = join(&mut server);
+    for i in 5..30 {
+        change(&mut server, i);
+        tick(&mut server);
+        client.update();
+    }
+
+    assert!(acked(&server, &client));
+    assert_eq!(sorted::<Num>(&mut client), sorted::<Num>(&mut server));
+}
+
+#[test]
+fn spawn_only_once_acknowledged() {
+    let mut server = create_server();
+    setup(&mut server);
+    let mut first = join(&mut server);
+    let entity = server.world.spawn((Replicate, Name(1), Num(0))).id();
+    tick(&mut server);
+    first.update();
+    server.update();
+
+    server.world.entity_mut(entity).insert((Name(2), Num(1)));
+    tick(&mut server);
+    first.update();
+    assert_eq!(sorted::<Num>(&mut first), vec![Num(1)]);
+    assert_eq!(*first.world.query::<&Name>().single(&first.world), Name(1));
+
+    // Joining clients get it as it is now
+    let mut second = join(&mut server);
+    tick(&mut server);
+    second.update();
+    assert_eq!(
+        *second.world.query::<&Name>().single(&second.world),
+        Name(2)
+    );
+}
+
+#[test]
+fn held_back_until_the_interval_passed() {
+    let mut server = create_server();
+    setup(&mut server);
+    let mut client = join(&mut server);
+    let entity = server.world.spawn((Replicate, Slow(0))).id();
+    tick(&mut server);
+    client.update();
+    server.update();
+
+    let mut seen = vec![];
+    for i in 1..=8 {
+        server.world.get_mut::<Slow>(entity).unwrap().0 = i;
+        tick(&mut server);
+        client.update();
+        seen.push(client.world.query::<&Slow>().single(&client.world).0);
+    }
+    assert_eq!(seen, vec![0, 0, 0, 4, 4, 4, 4, 8]);
+}
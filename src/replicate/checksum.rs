@@ -0,0 +1,186 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy::utils::{get_short_name, HashSet};
+use serde::{Deserialize, Serialize};
+
+use super::authority::Authority;
+use super::schedule::NetworkSendInterval;
+use super::{NetworkEntities, NetworkTick, Replicate, ReplicationFunctions};
+
+#[cfg(test)]
+mod tests;
+
+/// Ticks of predicted checksums kept around to compare with the server
+const PREDICTED_CHECKSUMS_LEN: usize = 128;
+
+/// Sent when a component the client predicted does not match what the server had on that tick
+#[derive(Debug, Event, Clone, PartialEq)]
+pub struct Desync {
+    pub tick: NetworkTick,
+    /// The server's entity
+    pub entity: Entity,
+    pub component: &'static str,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct ComponentChecksum {
+    pub(super) entity: Entity,
+    pub(super) replication_id: usize,
+    pub(super) checksum: u64,
+}
+
+/// Every how many replication packets the server includes checksums
+#[derive(Debug, Resource, Clone, Copy)]
+pub(super) struct ChecksumInterval(pub u64);
+
+#[derive(Debug, Resource, Default)]
+pub(super) struct PredictedChecksums {
+    ticks: VecDeque<(NetworkTick, Vec<ComponentChecksum>)>,
+    /// What did not match last time, so that a lasting desync is only logged when it starts
+    desynced: HashSet<(Entity, usize)>,
+}
+
+/// FNV-1a, which unlike the std hashers gives the same hash in every build
+pub(super) fn fnv1a(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A checksum of each replicated component over the given entities, by the name of the component.
+/// It does not depend on the order or ids of the entities, or on the order the components were
+/// registered in, so that it can be stored.
+#[cfg(test)]
+pub fn checksums(world: &World, entities: &[Entity]) -> std::collections::BTreeMap<String, u64> {
+    world
+        .resource::<ReplicationFunctions>()
+        .iter()
+        .map(|f| {
+            let checksum = entities
+                .iter()
+                .filter_map(|&entity| (f.checksum)(world, entity))
+                .fold(0, u64::wrapping_add);
+            (f.name.to_string(), checksum)
+        })
+        .collect()
+}
+
+/// Whether the packets of this tick carry checksums, which the client needs to know too so that
+/// it only keeps the checksums of those ticks
+pub(super) fn is_checksum_tick(world: &World, tick: NetworkTick) -> bool {
+    let send_interval = world.resource::<NetworkSendInterval>().0.max(1);
+    let interval = world.resource::<ChecksumInterval>().0.max(1);
+
+    tick.0.is_multiple_of(send_interval) && (tick.0 / send_interval).is_multiple_of(interval)
+}
+
+/// Checksums of the given entities, which are stored under the server's entities
+fn component_checksums(
+    world: &World,
+    entities: impl Iterator<Item = (Entity, Entity)>,
+) -> Vec<ComponentChecksum> {
+    let functions = world.resource::<ReplicationFunctions>();
+
+    entities
+        .flat_map(|(server_entity, entity)| {
+            functions
+                .iter()
+                .enumerate()
+                // Those are only simulated on the owning client
+                .filter(|(_, f)| f.authority == Authority::Server)
+                .filter_map(move |(replication_id, f)| {
+                    Some(ComponentChecksum {
+                        entity: server_entity,
+                        replication_id,
+                        checksum: (f.checksum)(world, entity)?,
+                    })
+                })
+        })
+        .collect()
+}
+
+/// What the server puts in the replication packet
+pub(super) fn server_checksums(world: &mut World) -> Option<Vec<ComponentChecksum>> {
+    if !is_checksum_tick(world, *world.resource::<NetworkTick>()) {
+        return None;
+    }
+
+    let entities = world
+        .query_filtered::<Entity, With<Replicate>>()
+        .iter(world)
+        .map(|entity| (entity, entity))
+        .collect::<Vec<_>>();
+    Some(component_checksums(world, entities.into_iter()))
+}
+
+/// Remembers the predicted state of the ticks the server will send checksums for
+pub(super) fn record_predicted_checksums(world: &mut World) {
+    let tick = *world.resource::<NetworkTick>();
+    if !is_checksum_tick(world, tick) {
+        return;
+    }
+
+    let entities = world
+        .resource::<NetworkEntities>()
+        .iter()
+        .map(|(&server_entity, &entity)| (server_entity, entity))
+        .filter(|&(_, entity)| world.get_entity(entity).is_some())
+        .collect::<Vec<_>>();
+    let checksums = component_checksums(world, entities.into_iter());
+
+    let mut predicted = world.resource_mut::<PredictedChecksums>();
+    // Resimulated ticks replace what was predicted before
+    predicted.ticks.retain(|&(t, _)| t < tick);
+    predicted.ticks.push_back((tick, checksums));
+    while predicted.ticks.len() > PREDICTED_CHECKSUMS_LEN {
+        predicted.ticks.pop_front();
+    }
+}
+
+/// Compares the server's checksums with what this client predicted for the same tick. Entities
+/// the client did not know about yet are skipped.
+pub(super) fn compare_checksums(
+    world: &mut World,
+    tick: NetworkTick,
+    server: &[ComponentChecksum],
+) {
+    let Some(predicted) = world.get_resource::<PredictedChecksums>() else {
+        return;
+    };
+    let Some((_, predicted_tick)) = predicted.ticks.iter().find(|&&(t, _)| t == tick) else {
+        return;
+    };
+
+    let desynced = server
+        .iter()
+        .filter(|server| {
+            predicted_tick.iter().any(|predicted| {
+                predicted.entity == server.entity
+                    && predicted.replication_id == server.replication_id
+                    && predicted.checksum != server.checksum
+            })
+        })
+        .map(|server| (server.entity, server.replication_id))
+        .collect::<HashSet<_>>();
+
+    let functions = world.resource::<ReplicationFunctions>();
+    let desyncs = desynced
+        .iter()
+        .map(|&(entity, replication_id)| Desync {
+            tick,
+            entity,
+            component: functions[replication_id].name,
+        })
+        .collect::<Vec<_>>();
+    for &(entity, replication_id) in desynced.difference(&predicted.desynced) {
+        println!(
+            "Desync at tick {}: {} of {entity:?} differs from the server",
+            tick.0,
+            get_short_name(functions[replication_id].name),
+        );
+    }
+
+    world.resource_mut::<PredictedChecksums>().desynced = desynced;
+    world.send_event_batch(desyncs);
+}
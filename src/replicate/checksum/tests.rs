@@ -0,0 +1,96 @@
+use crate::replicate::schedule::{NetworkUpdate, TickStrategy};
+use crate::replicate::*;
+use crate::test_utils::*;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u32);
+
+fn count_up(mut nums: Query<&mut Num>) {
+    for mut num in &mut nums {
+        num.0 += 1;
+    }
+}
+
+fn setup() -> (App, App) {
+    let replication =
+        || ReplicationPlugin::new(0.01, TickStrategy::Manual).with_checksum_interval(2);
+    let mut server = create_server_with(replication());
+    let mut client = create_client_with(&mut server, replication());
+    for app in [&mut server, &mut client] {
+        app.replicate::<Num>().add_systems(NetworkUpdate, count_up);
+    }
+    server.world.spawn((Replicate, Num(0)));
+
+    (server, client)
+}
+
+fn step(server: &mut App, client: &mut App) {
+    tick(client);
+    tick(server);
+}
+
+fn desyncs(client: &App) -> Vec<Desync> {
+    let events = client.world.resource::<Events<Desync>>();
+    events.get_reader().read(events).cloned().collect()
+}
+
+#[test]
+fn only_some_packets_have_checksums() {
+    let (mut server, _) = setup();
+
+    let ticks = (1..=6)
+        .filter(|&tick| is_checksum_tick(&server.world, NetworkTick(tick)))
+        .collect::<Vec<_>>();
+    assert_eq!(ticks, [2, 4, 6]);
+
+    tick(&mut server);
+    assert_eq!(server_checksums(&mut server.world), None);
+    tick(&mut server);
+    assert_eq!(
+        server_checksums(&mut server.world).map(|c| c.len()),
+        Some(1)
+    );
+}
+
+#[test]
+fn correct_predictions_match() {
+    let (mut server, mut client) = setup();
+
+    for _ in 0..20 {
+        step(&mut server, &mut client);
+        assert_eq!(desyncs(&client), []);
+    }
+    assert!(!client
+        .world
+        .resource::<PredictedChecksums>()
+        .ticks
+        .is_empty());
+}
+
+#[test]
+fn report_mispredicted_components() {
+    let (mut server, mut client) = setup();
+    for _ in 0..5 {
+        step(&mut server, &mut client);
+    }
+
+    // Something only the server knows about
+    server.add_systems(NetworkUpdate, count_up);
+    let entity = server
+        .world
+        .query_filtered::<Entity, With<Num>>()
+        .single(&server.world);
+
+    let mut found = vec![];
+    for _ in 0..4 {
+        step(&mut server, &mut client);
+        found.extend(desyncs(&client));
+    }
+
+    assert!(!found.is_empty());
+    assert!(found.iter().all(|desync| desync.entity == entity
+        && desync.component == std::any::type_name::<Num>()
+        && desync.tick.0.is_multiple_of(2)));
+}
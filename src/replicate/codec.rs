@@ -0,0 +1,255 @@
+use std::borrow::Cow;
+use std::f32::consts::TAU;
+
+use bevy::prelude::*;
+use bincode::Options;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+#[cfg(test)]
+mod tests;
+
+/// Turns a component into bytes and back, see [`super::AppExt::replicate_codec`]. Codecs may
+/// round what they encode.
+pub trait Codec<T>: Clone + Send + Sync + 'static {
+    fn encode(&self, value: &T, out: &mut Vec<u8>);
+
+    /// Reads a value from the start of `data` and moves past it. Fails when `data` is too short.
+    fn decode(&self, data: &mut &[u8]) -> Result<T, String>;
+
+    fn encode_vec(&self, value: &T) -> Vec<u8> {
+        let mut out = vec![];
+        self.encode(value, &mut out);
+        out
+    }
+
+    fn decode_slice(&self, mut data: &[u8]) -> Result<T, String> {
+        self.decode(&mut data)
+    }
+
+    /// The value as the other side gets it
+    fn round_trip(&self, value: &T) -> T {
+        self.decode_slice(&self.encode_vec(value))
+            .expect("Codecs decode what they encode")
+    }
+}
+
+/// Integers in replication packets are varints, so ticks, entity ids and lengths mostly take a
+/// byte or two instead of eight
+fn packet_options() -> impl Options {
+    bincode::DefaultOptions::new()
+}
+
+pub(super) fn serialize_packet<T: Serialize>(value: &T) -> Vec<u8> {
+    packet_options().serialize(value).unwrap()
+}
+
+pub(super) fn deserialize_packet<T: DeserializeOwned>(data: &[u8]) -> bincode::Result<T> {
+    packet_options().deserialize(data)
+}
+
+pub(super) fn packet_size<T: Serialize>(value: &T) -> usize {
+    packet_options().serialized_size(value).unwrap() as usize
+}
+
+/// How replication packets are compressed, see [`super::ReplicationPlugin::with_compression`]
+#[repr(u8)]
+#[derive(Debug, Resource, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Compression {
+    #[default]
+    None = 0,
+    /// Fast, and worth it once packets hold more than a few entities
+    Lz4 = 1,
+}
+
+/// A packet as it goes over the network: a byte saying how it is compressed, then the payload
+pub(super) fn encode_message<T: Serialize>(value: &T, compression: Compression) -> Vec<u8> {
+    let payload = serialize_packet(value);
+
+    let mut message = vec![compression as u8];
+    match compression {
+        Compression::None => message.extend(payload),
+        Compression::Lz4 => message.extend(lz4_flex::compress_prepend_size(&payload)),
+    }
+    message
+}
+
+/// Reads packets compressed in any way, whatever this side would compress them with
+pub(super) fn decode_message<T: DeserializeOwned>(message: &[u8]) -> Result<T, String> {
+    let (&flag, payload) = message.split_first().ok_or("Empty message")?;
+    let payload = match flag {
+        f if f == Compression::None as u8 => Cow::Borrowed(payload),
+        f if f == Compression::Lz4 as u8 => {
+            Cow::Owned(lz4_flex::decompress_size_prepended(payload).map_err(|err| err.to_string())?)
+        }
+        _ => return Err(format!("Unknown compression {flag}")),
+    };
+
+    deserialize_packet(&payload).map_err(|err| err.to_string())
+}
+
+fn write_uint(out: &mut Vec<u8>, value: u64, bytes: usize) {
+    out.extend_from_slice(&value.to_le_bytes()[..bytes]);
+}
+
+fn read_uint(data: &mut &[u8], bytes: usize) -> Result<u64, String> {
+    if data.len() < bytes {
+        return Err(format!("Needed {bytes} bytes, {} left", data.len()));
+    }
+    let mut buf = [0; 8];
+    buf[..bytes].copy_from_slice(&data[..bytes]);
+    *data = &data[bytes..];
+    Ok(u64::from_le_bytes(buf))
+}
+
+fn read_f32(data: &mut &[u8]) -> Result<f32, String> {
+    Ok(f32::from_bits(read_uint(data, 4)? as u32))
+}
+
+/// A 2D position within `min` and `max`, rounded to `precision`. Positions outside are clamped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct QuantizedVec2 {
+    min: Vec2,
+    max: Vec2,
+    precision: f32,
+    bits: u32,
+}
+
+impl QuantizedVec2 {
+    pub fn new(min: Vec2, max: Vec2, precision: f32) -> Self {
+        let steps = ((max - min) / precision).max_element().ceil() as u64;
+        let bits = u64::BITS - steps.leading_zeros();
+        assert!(bits <= 32, "Too many steps between {min} and {max}");
+
+        Self {
+            min,
+            max,
+            precision,
+            bits,
+        }
+    }
+
+    fn bytes(&self) -> usize {
+        (2 * self.bits as usize).div_ceil(8)
+    }
+}
+
+impl Codec<Vec2> for QuantizedVec2 {
+    fn encode(&self, value: &Vec2, out: &mut Vec<u8>) {
+        let steps = ((value.clamp(self.min, self.max) - self.min) / self.precision).round();
+        write_uint(
+            out,
+            steps.x as u64 | (steps.y as u64) << self.bits,
+            self.bytes(),
+        );
+    }
+
+    fn decode(&self, data: &mut &[u8]) -> Result<Vec2, String> {
+        let packed = read_uint(data, self.bytes())?;
+        let mask = (1 << self.bits) - 1;
+        let steps = Vec2::new((packed & mask) as f32, (packed >> self.bits) as f32);
+        Ok(self.min + steps * self.precision)
+    }
+}
+
+/// A rotation about the z axis, like all of them in 2D, in `bits` bits
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Angle2d {
+    bits: u32,
+}
+
+impl Angle2d {
+    pub fn new(bits: u32) -> Self {
+        assert!((1..=32).contains(&bits), "An angle takes 1 to 32 bits");
+        Self { bits }
+    }
+
+    fn steps(&self) -> u64 {
+        1 << self.bits
+    }
+}
+
+impl Codec<Quat> for Angle2d {
+    fn encode(&self, value: &Quat, out: &mut Vec<u8>) {
+        let forward = *value * Vec3::X;
+        let turns = (forward.y.atan2(forward.x) / TAU).rem_euclid(1.0);
+        let step = (turns * self.steps() as f32).round() as u64 % self.steps();
+        write_uint(out, step, (self.bits as usize).div_ceil(8));
+    }
+
+    fn decode(&self, data: &mut &[u8]) -> Result<Quat, String> {
+        let step = read_uint(data, (self.bits as usize).div_ceil(8))?;
+        Ok(Quat::from_rotation_z(
+            step as f32 / self.steps() as f32 * TAU,
+        ))
+    }
+}
+
+/// A [`Transform`] in a 2D game: the quantized x and y, the angle about z, and z and the scale
+/// only when they are not 0 and 1
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform2d {
+    pub position: QuantizedVec2,
+    pub rotation: Angle2d,
+}
+
+impl Transform2d {
+    const HAS_Z: u8 = 1;
+    const UNIFORM_SCALE: u8 = 2;
+    const SCALE: u8 = 4;
+}
+
+impl Codec<Transform> for Transform2d {
+    fn encode(&self, value: &Transform, out: &mut Vec<u8>) {
+        let z = value.translation.z;
+        let scale = value.scale;
+
+        let mut flags = 0;
+        if z != 0.0 {
+            flags |= Self::HAS_Z;
+        }
+        if scale == Vec3::splat(scale.x) && scale != Vec3::ONE {
+            flags |= Self::UNIFORM_SCALE;
+        } else if scale != Vec3::ONE {
+            flags |= Self::SCALE;
+        }
+
+        out.push(flags);
+        self.position.encode(&value.translation.xy(), out);
+        self.rotation.encode(&value.rotation, out);
+        if flags & Self::HAS_Z != 0 {
+            out.extend_from_slice(&z.to_le_bytes());
+        }
+        if flags & Self::UNIFORM_SCALE != 0 {
+            out.extend_from_slice(&scale.x.to_le_bytes());
+        }
+        if flags & Self::SCALE != 0 {
+            for axis in scale.to_array() {
+                out.extend_from_slice(&axis.to_le_bytes());
+            }
+        }
+    }
+
+    fn decode(&self, data: &mut &[u8]) -> Result<Transform, String> {
+        let flags = read_uint(data, 1)? as u8;
+        let position = self.position.decode(data)?;
+        let rotation = self.rotation.decode(data)?;
+        let z = match flags & Self::HAS_Z {
+            0 => 0.0,
+            _ => read_f32(data)?,
+        };
+        let scale = if flags & Self::UNIFORM_SCALE != 0 {
+            Vec3::splat(read_f32(data)?)
+        } else if flags & Self::SCALE != 0 {
+            Vec3::new(read_f32(data)?, read_f32(data)?, read_f32(data)?)
+        } else {
+            Vec3::ONE
+        };
+
+        Ok(Transform {
+            translation: position.extend(z),
+            rotation,
+            scale,
+        })
+    }
+}
@@ -0,0 +1,163 @@
+use crate::replicate::*;
+
+use super::*;
+
+fn position() -> QuantizedVec2 {
+    QuantizedVec2::new(Vec2::splat(-512.0), Vec2::splat(512.0), 1.0 / 512.0)
+}
+
+fn transform() -> Transform2d {
+    Transform2d {
+        position: position(),
+        rotation: Angle2d::new(16),
+    }
+}
+
+#[test]
+fn positions_round_to_the_precision() {
+    let codec = position();
+    for value in [
+        Vec2::ZERO,
+        Vec2::new(1.2345, -6.789),
+        Vec2::new(-512.0, 511.9),
+    ] {
+        let decoded = codec.round_trip(&value);
+        assert!(
+            (decoded - value).abs().max_element() <= 0.5 / 512.0,
+            "{value}"
+        );
+    }
+
+    assert_eq!(
+        codec.round_trip(&Vec2::new(1000.0, -1000.0)),
+        Vec2::new(512.0, -512.0)
+    );
+    assert_eq!(codec.encode_vec(&Vec2::ZERO).len(), 5);
+}
+
+#[test]
+fn angles_about_z() {
+    let codec = Angle2d::new(16);
+    for angle in [0.0, 0.1, 1.0, -2.5, 3.0] {
+        let decoded = codec.round_trip(&Quat::from_rotation_z(angle));
+        assert!(decoded.angle_between(Quat::from_rotation_z(angle)) < 0.001);
+    }
+    assert_eq!(codec.encode_vec(&Quat::IDENTITY).len(), 2);
+}
+
+#[test]
+fn rounding_again_changes_nothing() {
+    let codec = transform();
+    for transform in [
+        Transform::from_xyz(0.1, -0.2, 0.0),
+        Transform::from_xyz(123.456, 7.89, -2.0).with_rotation(Quat::from_rotation_z(2.0)),
+        Transform::from_xyz(-3.3, 5.0, 1.0).with_scale(Vec3::new(1.0, 2.0, 1.0)),
+    ] {
+        let rounded = codec.round_trip(&transform);
+        assert_eq!(codec.round_trip(&rounded), rounded);
+        assert_eq!(rounded.translation.z, transform.translation.z);
+        assert_eq!(rounded.scale, transform.scale);
+    }
+}
+
+#[test]
+fn transforms_take_what_they_need() {
+    let codec = transform();
+    let transform = Transform::from_xyz(1.0, 2.0, 0.0);
+    assert_eq!(codec.encode_vec(&transform).len(), 8);
+    assert_eq!(
+        codec
+            .encode_vec(&transform.with_translation(Vec3::new(1.0, 2.0, -2.0)))
+            .len(),
+        12
+    );
+    assert_eq!(
+        codec
+            .encode_vec(&transform.with_scale(Vec3::splat(0.2)))
+            .len(),
+        12
+    );
+    assert_eq!(
+        codec
+            .encode_vec(&transform.with_scale(Vec3::new(1.0, 2.0, 3.0)))
+            .len(),
+        20
+    );
+}
+
+#[test]
+fn packets_use_varints() {
+    let packet = ReplicationPacket {
+        tick: NetworkTick(1234),
+        updates: (0..10)
+            .map(|i| EntityUpdates {
+                entity: Entity::from_raw(i),
+                updates: vec![UpdateComponent {
+                    replication_id: 2,
+                    data: vec![0; 8],
+                }],
+                removals: vec![],
+            })
+            .collect(),
+        despawns: vec![Entity::from_raw(11)],
+        baseline: false,
+        checksums: None,
+    };
+
+    let compact = serialize_packet(&packet);
+    assert!(compact.len() * 3 < bincode::serialize(&packet).unwrap().len());
+    assert_eq!(compact.len(), packet_size(&packet));
+
+    let read = deserialize_packet::<ReplicationPacket>(&compact).unwrap();
+    assert_eq!(read.tick, packet.tick);
+    assert_eq!(read.updates.len(), 10);
+    assert_eq!(read.despawns, packet.despawns);
+}
+
+#[test]
+fn compress_large_packets() {
+    let packet = ReplicationPacket {
+        tick: NetworkTick(1),
+        updates: (0..100)
+            .map(|i| EntityUpdates {
+                entity: Entity::from_raw(i),
+                updates: vec![UpdateComponent {
+                    replication_id: 0,
+                    data: vec![1, 2, 3, 4],
+                }],
+                removals: vec![],
+            })
+            .collect(),
+        despawns: vec![],
+        baseline: true,
+        checksums: None,
+    };
+
+    let plain = encode_message(&packet, Compression::None);
+    let compressed = encode_message(&packet, Compression::Lz4);
+    assert!(compressed.len() * 2 < plain.len());
+
+    for message in [plain, compressed] {
+        let read = decode_message::<ReplicationPacket>(&message).unwrap();
+        assert_eq!(read.updates.len(), 100);
+        assert_eq!(read.updates[99].entity, Entity::from_raw(99));
+    }
+}
+
+#[test]
+fn unknown_compression_is_refused() {
+    let mut message = encode_message(&NetworkTick(1), Compression::None);
+    message[0] = 7;
+    assert!(decode_message::<NetworkTick>(&message).is_err());
+    assert!(decode_message::<NetworkTick>(&[]).is_err());
+}
+
+#[test]
+fn truncated_data_is_refused() {
+    let codec = transform();
+    let data = codec.encode_vec(&Transform::from_xyz(1.0, 2.0, -2.0).with_scale(Vec3::splat(2.0)));
+    for len in 0..data.len() {
+        assert!(codec.decode_slice(&data[..len]).is_err(), "{len} bytes");
+    }
+    assert!(codec.decode_slice(&data).is_ok());
+}
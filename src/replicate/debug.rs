@@ -0,0 +1,290 @@
+use std::collections::VecDeque;
+
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use bevy_renet::renet::{ClientId, RenetClient, RenetServer};
+use bevy_renet::RenetReceive;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::baseline::restart_baselines;
+use super::schedule::{run_network_fixed, DoTick, NetworkFixedTime, TickStrategy};
+use super::stats::NetworkStats;
+use super::{
+    is_client, is_server, send_updated_components, Channel, EntityUpdates, NetworkTick, Replicate,
+    ReplicationFunctions, ReplicationPacket,
+};
+
+#[cfg(test)]
+mod tests;
+
+/// Controls the network simulation of every peer at once.
+///
+/// Commands sent on a client are forwarded to the server, which applies them and broadcasts them
+/// to all clients so that everyone pauses, steps and rewinds together. The server only takes them
+/// from the clients in [`DebugControllers`].
+#[derive(Debug, Event, Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DebugCommand {
+    Pause,
+    Resume,
+    /// Pause and run the given number of ticks, one per frame
+    Step(u32),
+    /// Pause and restore the replicated state the server sent at the given tick, or the last one
+    /// before it
+    Rewind(NetworkTick),
+}
+
+#[derive(Debug, Resource, Default, Deref, DerefMut)]
+pub struct PendingSteps(pub u32);
+
+/// The clients the server takes debug commands from, besides its own
+#[derive(Debug, Resource, Default, Clone)]
+pub struct DebugControllers(pub HashSet<ClientId>);
+
+/// The whole state of the last ticks the server sent, which it rewinds to. It is the same state
+/// that replays record.
+#[derive(Debug, Resource)]
+pub struct SentHistory {
+    capacity: usize,
+    snapshots: VecDeque<(NetworkTick, Vec<EntityUpdates>)>,
+}
+
+impl SentHistory {
+    pub fn new(capacity: usize) -> Self {
+        SentHistory {
+            capacity,
+            snapshots: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn push(&mut self, tick: NetworkTick, snapshot: Vec<EntityUpdates>) {
+        // Sending again after a rewind replaces what was sent of that tick before
+        while self.snapshots.back().is_some_and(|&(t, _)| t >= tick) {
+            self.snapshots.pop_back();
+        }
+        while self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((tick, snapshot));
+    }
+
+    /// Forget everything that was sent after `tick`, and return the last snapshot at or before it
+    fn rewind_to(&mut self, tick: NetworkTick) -> Option<(NetworkTick, Vec<EntityUpdates>)> {
+        let index = self
+            .snapshots
+            .partition_point(|&(t, _)| t <= tick)
+            .checked_sub(1)?;
+        self.snapshots.truncate(index + 1);
+
+        Some(self.snapshots[index].clone())
+    }
+}
+
+pub struct NetworkDebugPlugin {
+    history_len: usize,
+}
+
+impl NetworkDebugPlugin {
+    /// Keeps the state of the last `history_len` ticks that were sent to rewind to
+    pub fn with_history(history_len: usize) -> Self {
+        NetworkDebugPlugin { history_len }
+    }
+}
+
+impl Default for NetworkDebugPlugin {
+    fn default() -> Self {
+        NetworkDebugPlugin::with_history(200)
+    }
+}
+
+impl Plugin for NetworkDebugPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DebugCommand>()
+            .init_resource::<PendingSteps>()
+            .init_resource::<DebugControllers>()
+            .insert_resource(SentHistory::new(self.history_len))
+            .add_systems(
+                PreUpdate,
+                (
+                    server_handle_commands.run_if(is_server),
+                    client_handle_commands.run_if(is_client),
+                )
+                    .after(RenetReceive),
+            )
+            .add_systems(Update, step_paused_simulation.before(run_network_fixed));
+    }
+}
+
+fn server_handle_commands(
+    mut commands: Commands,
+    mut events: EventReader<DebugCommand>,
+    mut server: ResMut<RenetServer>,
+    mut stats: ResMut<NetworkStats>,
+    controllers: Res<DebugControllers>,
+) {
+    let mut received = events.read().copied().collect_vec();
+    for client_id in server.clients_id() {
+        while let Some(message) = server.receive_message(client_id, Channel::Debug) {
+            stats.received(Channel::Debug, message.len());
+            match bincode::deserialize(&message) {
+                Ok(command) if controllers.0.contains(&client_id) => received.push(command),
+                Ok(command) => {
+                    println!("Ignored {command:?} from {client_id}, which may not debug")
+                }
+                Err(err) => {
+                    println!(
+                        "{client_id} sent an unreadable debug command, disconnecting it: {err}"
+                    );
+                    server.disconnect(client_id);
+                    break;
+                }
+            }
+        }
+    }
+
+    for command in received {
+        commands.add(move |world: &mut World| {
+            if let Some(command) = apply_command(world, command) {
+                let message = bincode::serialize(&command).unwrap();
+                let mut server = world.resource_mut::<RenetServer>();
+                let bytes = message.len() * server.clients_id().len();
+                server.broadcast_message(Channel::Debug, message);
+                world
+                    .resource_mut::<NetworkStats>()
+                    .sent(Channel::Debug, bytes);
+            }
+        });
+    }
+}
+
+fn client_handle_commands(
+    mut commands: Commands,
+    mut events: EventReader<DebugCommand>,
+    mut client: ResMut<RenetClient>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    for command in events.read() {
+        let message = bincode::serialize(command).unwrap();
+        stats.sent(Channel::Debug, message.len());
+        client.send_message(Channel::Debug, message);
+    }
+
+    while let Some(message) = client.receive_message(Channel::Debug) {
+        stats.received(Channel::Debug, message.len());
+        let command = match bincode::deserialize::<DebugCommand>(&message) {
+            Ok(command) => command,
+            Err(err) => {
+                println!("Dropped an unreadable debug command: {err}");
+                continue;
+            }
+        };
+        commands.add(move |world: &mut World| {
+            apply_command(world, command);
+        });
+    }
+}
+
+/// Returns the command as it was applied, for the clients to follow, or nothing if it could not be
+fn apply_command(world: &mut World, command: DebugCommand) -> Option<DebugCommand> {
+    match command {
+        DebugCommand::Pause => {
+            *world.resource_mut::<TickStrategy>() = TickStrategy::Manual;
+        }
+        DebugCommand::Resume => {
+            world.resource_mut::<NetworkFixedTime>().reset();
+            world.resource_mut::<PendingSteps>().0 = 0;
+            *world.resource_mut::<TickStrategy>() = TickStrategy::Automatic;
+        }
+        DebugCommand::Step(ticks) => {
+            world.resource_mut::<PendingSteps>().0 += ticks;
+            *world.resource_mut::<TickStrategy>() = TickStrategy::Manual;
+        }
+        DebugCommand::Rewind(mut tick) => {
+            if world.contains_resource::<RenetServer>() {
+                tick = rewind_server(world, tick)?;
+            } else {
+                // The server sends its rewound state right away, so once it has been copied in
+                // the client is exactly at the server tick and nothing needs to be resimulated
+                *world.resource_mut::<NetworkTick>() = tick;
+            }
+            world.resource_mut::<PendingSteps>().0 = 0;
+            *world.resource_mut::<TickStrategy>() = TickStrategy::Manual;
+            return Some(DebugCommand::Rewind(tick));
+        }
+    }
+
+    Some(command)
+}
+
+/// Returns the tick it rewound to
+fn rewind_server(world: &mut World, tick: NetworkTick) -> Option<NetworkTick> {
+    let (tick, snapshot) = world.resource_mut::<SentHistory>().rewind_to(tick)?;
+
+    let in_snapshot = snapshot
+        .iter()
+        .map(|updates| updates.entity)
+        .collect::<HashSet<_>>();
+    let spawned_since = world
+        .query_filtered::<Entity, With<Replicate>>()
+        .iter(world)
+        .filter(|entity| !in_snapshot.contains(entity))
+        .collect_vec();
+    for entity in spawned_since {
+        world.entity_mut(entity).despawn_recursive();
+    }
+
+    world.resource_scope(|world, functions: Mut<ReplicationFunctions>| {
+        for EntityUpdates {
+            entity, updates, ..
+        } in snapshot
+        {
+            // Entities despawned since the snapshot come back under their old ids, unless another
+            // entity has taken them since
+            let Some(mut entity) = world.get_or_spawn(entity) else {
+                println!("Could not rewind {entity:?}, its id was taken");
+                continue;
+            };
+            let entity = entity.insert(Replicate).id();
+
+            for (replication_id, f) in functions.iter().enumerate() {
+                match updates
+                    .iter()
+                    .find(|update| update.replication_id == replication_id)
+                {
+                    Some(update) => {
+                        if let Err(err) = (f.restore)(world, entity, &update.data) {
+                            println!("Could not rewind the {} of {entity:?}: {err}", f.name);
+                        }
+                    }
+                    None if (f.gather)(world, entity).is_some() => (f.remove)(world, entity),
+                    None => (),
+                }
+            }
+        }
+    });
+
+    // Acknowledged ticks are in the future now, so everyone starts over from a complete snapshot
+    *world.resource_mut::<NetworkTick>() = tick;
+    restart_baselines(world);
+    send_updated_components(world);
+
+    Some(tick)
+}
+
+fn step_paused_simulation(
+    mut commands: Commands,
+    mut pending: ResMut<PendingSteps>,
+    strategy: Res<TickStrategy>,
+) {
+    if *strategy == TickStrategy::Manual && pending.0 > 0 {
+        pending.0 -= 1;
+        commands.insert_resource(DoTick);
+    }
+}
+
+/// Called with the whole state every time the server sends replication packets
+pub(super) fn record_sent(world: &mut World, packet: &ReplicationPacket) {
+    if let Some(mut history) = world.get_resource_mut::<SentHistory>() {
+        history.push(packet.tick, packet.updates.clone());
+    }
+}
@@ -0,0 +1,228 @@
+use crate::replicate::*;
+use crate::test_utils::*;
+use crate::transport::memory::MemoryClientTransport;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u32);
+
+fn count_up(mut nums: Query<&mut Num>) {
+    for mut num in &mut nums {
+        num.0 += 1;
+    }
+}
+
+fn setup() -> (App, App) {
+    let mut server = create_server();
+    let mut client = create_client(&mut server);
+    for app in [&mut server, &mut client] {
+        app.add_plugins(NetworkDebugPlugin::default())
+            .replicate::<Num>()
+            .add_systems(schedule::NetworkUpdate, count_up);
+    }
+    let client_id = client_id(&client);
+    server
+        .world
+        .resource_mut::<DebugControllers>()
+        .0
+        .insert(client_id);
+
+    (server, client)
+}
+
+fn client_id(client: &App) -> ClientId {
+    client.world.resource::<MemoryClientTransport>().client_id()
+}
+
+fn client_num(client: &mut App) -> Num {
+    *client.world.query::<&Num>().single(&client.world)
+}
+
+#[test]
+fn pause_and_resume_everyone() {
+    let (mut server, mut client) = setup();
+    for app in [&mut server, &mut client] {
+        app.insert_resource(TickStrategy::Automatic);
+    }
+
+    server.world.send_event(DebugCommand::Pause);
+    server.update();
+    client.update();
+
+    assert_eq!(
+        *server.world.resource::<TickStrategy>(),
+        TickStrategy::Manual
+    );
+    assert_eq!(
+        *client.world.resource::<TickStrategy>(),
+        TickStrategy::Manual
+    );
+
+    client.world.send_event(DebugCommand::Resume);
+    client.update();
+
+    // The client only asks the server, which decides for everyone
+    assert_eq!(
+        *client.world.resource::<TickStrategy>(),
+        TickStrategy::Manual
+    );
+
+    server.update();
+    client.update();
+
+    assert_eq!(
+        *server.world.resource::<TickStrategy>(),
+        TickStrategy::Automatic
+    );
+    assert_eq!(
+        *client.world.resource::<TickStrategy>(),
+        TickStrategy::Automatic
+    );
+}
+
+#[test]
+fn step_ticks() {
+    let (mut server, mut client) = setup();
+    server.world.spawn((Replicate, Num(0)));
+
+    server.world.send_event(DebugCommand::Step(3));
+    for _ in 0..5 {
+        server.update();
+    }
+    client.update();
+
+    assert_eq!(server.world.resource::<NetworkTick>().0, 3);
+    assert_eq!(
+        client.world.resource::<SyncedServerTick>().tick,
+        NetworkTick(3)
+    );
+    assert_eq!(client_num(&mut client), Num(3));
+}
+
+#[test]
+fn rewind() {
+    let (mut server, mut client) = setup();
+    let num = server.world.spawn((Replicate, Num(0))).id();
+
+    for _ in 0..5 {
+        tick(&mut server);
+    }
+    let late = server.world.spawn((Replicate, Num(100))).id();
+    tick(&mut server);
+    server.world.despawn(num);
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(count::<&Num>(&mut client), 1);
+    assert_eq!(client_num(&mut client), Num(102));
+
+    server
+        .world
+        .send_event(DebugCommand::Rewind(NetworkTick(3)));
+    server.update();
+    client.update();
+
+    assert!(server.world.get_entity(late).is_none());
+    // Back under the same id
+    assert_eq!(server.world.get::<Num>(num), Some(&Num(3)));
+    assert_eq!(*server.world.resource::<NetworkTick>(), NetworkTick(3));
+
+    assert_eq!(*client.world.resource::<NetworkTick>(), NetworkTick(3));
+    assert_eq!(count::<&Num>(&mut client), 1);
+    assert_eq!(client_num(&mut client), Num(3));
+
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(client_num(&mut client), Num(4));
+}
+
+#[test]
+fn rewind_too_far() {
+    let (mut server, mut client) = setup();
+    server.world.spawn((Replicate, Num(0)));
+
+    for _ in 0..5 {
+        tick(&mut server);
+    }
+
+    server
+        .world
+        .send_event(DebugCommand::Rewind(NetworkTick(100)));
+    server.update();
+    client.update();
+
+    assert_eq!(*server.world.resource::<NetworkTick>(), NetworkTick(5));
+    assert_eq!(
+        *client.world.resource::<TickStrategy>(),
+        TickStrategy::Manual
+    );
+    assert_eq!(client_num(&mut client), Num(5));
+}
+
+#[test]
+fn rewind_between_sent_ticks() {
+    let (mut server, mut client) = setup();
+    server.insert_resource(NetworkSendInterval(2));
+    server.world.spawn((Replicate, Num(0)));
+
+    for _ in 0..6 {
+        tick(&mut server);
+    }
+
+    server
+        .world
+        .send_event(DebugCommand::Rewind(NetworkTick(3)));
+    server.update();
+    client.update();
+
+    assert_eq!(*server.world.resource::<NetworkTick>(), NetworkTick(2));
+    assert_eq!(*client.world.resource::<NetworkTick>(), NetworkTick(2));
+    assert_eq!(client_num(&mut client), Num(2));
+}
+
+#[test]
+fn only_controllers_command() {
+    let (mut server, mut client) = setup();
+    let client_id = client_id(&client);
+    server
+        .world
+        .resource_mut::<DebugControllers>()
+        .0
+        .remove(&client_id);
+    server.insert_resource(TickStrategy::Automatic);
+
+    client.world.send_event(DebugCommand::Pause);
+    client.update();
+    server.update();
+    client.update();
+
+    assert_eq!(
+        *server.world.resource::<TickStrategy>(),
+        TickStrategy::Automatic
+    );
+    assert_eq!(
+        server.world.resource::<RenetServer>().clients_id(),
+        [client_id]
+    );
+}
+
+#[test]
+fn unreadable_commands_disconnect_the_sender() {
+    let (mut server, mut client) = setup();
+
+    client
+        .world
+        .resource_mut::<RenetClient>()
+        .send_message(Channel::Debug, vec![9; 3]);
+    client.update();
+    server.update();
+    server.update();
+
+    assert!(server
+        .world
+        .resource::<RenetServer>()
+        .clients_id()
+        .is_empty());
+}
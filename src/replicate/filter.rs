@@ -0,0 +1,67 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use bevy::ecs::world::EntityRef;
+use bevy::prelude::*;
+
+use super::{EntityUpdates, Owner, ReplicationFunctions};
+
+#[cfg(test)]
+mod tests;
+
+/// Which clients get a replicated component, see [`super::ReplicationOptions::with_filter`]
+#[derive(Clone)]
+pub struct ReplicationFilter(Arc<dyn Fn(EntityRef, Owner) -> bool + Send + Sync>);
+
+impl ReplicationFilter {
+    /// The clients for which `predicate` returns true for the entity
+    pub fn new(predicate: impl Fn(EntityRef, Owner) -> bool + Send + Sync + 'static) -> Self {
+        Self(Arc::new(predicate))
+    }
+
+    /// Only the client in the entity's [`Owner`]
+    pub fn owner() -> Self {
+        Self::new(|entity, client| entity.get::<Owner>() == Some(&client))
+    }
+
+    /// Every client but the one in the entity's [`Owner`], for what the owner decides. What it
+    /// only predicts it still needs from the server to correct mispredictions.
+    pub fn except_owner() -> Self {
+        Self::new(|entity, client| entity.get::<Owner>() != Some(&client))
+    }
+}
+
+/// Whether the client gets this component of the entity
+pub(super) fn receives(world: &World, client: u64, entity: Entity, replication_id: usize) -> bool {
+    let functions = world.resource::<ReplicationFunctions>();
+    match &functions[replication_id].filter {
+        Some(ReplicationFilter(filter)) => filter(world.entity(entity), Owner::Client(client)),
+        None => true,
+    }
+}
+
+/// The updates without the components the client does not get
+pub(super) fn visible_to<'a>(
+    world: &World,
+    client: u64,
+    updates: &'a [EntityUpdates],
+) -> Cow<'a, [EntityUpdates]> {
+    let functions = world.resource::<ReplicationFunctions>();
+    if functions.iter().all(|f| f.filter.is_none()) {
+        return Cow::Borrowed(updates);
+    }
+
+    let visible = updates
+        .iter()
+        .map(|update| EntityUpdates {
+            updates: update
+                .updates
+                .iter()
+                .filter(|c| receives(world, client, update.entity, c.replication_id))
+                .cloned()
+                .collect(),
+            ..update.clone()
+        })
+        .collect();
+    Cow::Owned(visible)
+}
@@ -0,0 +1,132 @@
+use crate::replicate::*;
+use crate::test_utils::*;
+use crate::transport::memory::MemoryClientTransport;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Ammo(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Echo(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Secret(u32);
+
+/// Keeps the [`Secret`] of an entity from everyone
+#[derive(Component)]
+struct Hidden;
+
+fn setup(app: &mut App) {
+    app.replicate::<Num>()
+        .replicate_with_options::<Ammo>(
+            ReplicationOptions::default().with_filter(ReplicationFilter::owner()),
+        )
+        .replicate_with_options::<Echo>(
+            ReplicationOptions::default().with_filter(ReplicationFilter::except_owner()),
+        )
+        .replicate_with_options::<Secret>(ReplicationOptions::default().with_filter(
+            ReplicationFilter::new(|entity, _| !entity.contains::<Hidden>()),
+        ));
+}
+
+fn join(server: &mut App) -> (App, Owner) {
+    let mut client = create_client(server);
+    setup(&mut client);
+    let owner = Owner::Client(
+        client
+            .world
+            .resource::<MemoryClientTransport>()
+            .client_id()
+            .raw(),
+    );
+    (client, owner)
+}
+
+/// Sends the server's state, and the clients' acks back
+fn exchange(server: &mut App, clients: &mut [&mut App]) {
+    tick(server);
+    for client in clients.iter_mut() {
+        client.update();
+    }
+    server.update();
+}
+
+#[test]
+fn only_the_owner() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    let (mut other, _) = join(&mut server);
+    let entity = server
+        .world
+        .spawn((Replicate, owner_id, Num(0), Ammo(5)))
+        .id();
+    exchange(&mut server, &mut [&mut owner, &mut other]);
+
+    server.world.get_mut::<Ammo>(entity).unwrap().0 = 4;
+    exchange(&mut server, &mut [&mut owner, &mut other]);
+
+    assert_eq!(count::<&Ammo>(&mut other), 0);
+    assert_eq!(count::<&Num>(&mut other), 1);
+    assert_eq!(*owner.world.query::<&Ammo>().single(&owner.world), Ammo(4));
+}
+
+#[test]
+fn everyone_but_the_owner() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut owner, owner_id) = join(&mut server);
+    let (mut other, _) = join(&mut server);
+    server.world.spawn((Replicate, owner_id, Num(0), Echo(1)));
+    exchange(&mut server, &mut [&mut owner, &mut other]);
+
+    assert_eq!(count::<&Echo>(&mut owner), 0);
+    assert_eq!(count::<&Num>(&mut owner), 1);
+    assert_eq!(count::<&Echo>(&mut other), 1);
+}
+
+#[test]
+fn by_predicate() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut client, _) = join(&mut server);
+    let entity = server
+        .world
+        .spawn((Replicate, Owner::Server, Secret(7), Hidden))
+        .id();
+    exchange(&mut server, &mut [&mut client]);
+    assert_eq!(count::<&Secret>(&mut client), 0);
+
+    server.world.entity_mut(entity).remove::<Hidden>();
+    exchange(&mut server, &mut [&mut client]);
+    assert_eq!(count::<&Secret>(&mut client), 1);
+
+    // Once the client has it, it is told to remove it when it stops getting it
+    server.world.entity_mut(entity).insert(Hidden);
+    exchange(&mut server, &mut [&mut client]);
+    assert_eq!(count::<&Secret>(&mut client), 0);
+    assert_eq!(count::<&Owner>(&mut client), 1);
+}
+
+#[test]
+fn follows_the_owner() {
+    let mut server = create_server();
+    setup(&mut server);
+    let (mut first, first_id) = join(&mut server);
+    let (mut second, second_id) = join(&mut server);
+    let entity = server
+        .world
+        .spawn((Replicate, first_id, Ammo(5), Echo(1)))
+        .id();
+    exchange(&mut server, &mut [&mut first, &mut second]);
+
+    server.world.entity_mut(entity).insert(second_id);
+    exchange(&mut server, &mut [&mut first, &mut second]);
+
+    assert_eq!(count::<(&Echo, Without<Ammo>)>(&mut first), 1);
+    assert_eq!(count::<(&Ammo, Without<Echo>)>(&mut second), 1);
+}
@@ -0,0 +1,88 @@
+use bevy::prelude::*;
+use bevy::utils::{HashMap, HashSet};
+
+use super::codec::packet_size;
+use super::{EntityUpdates, Owner};
+
+#[cfg(test)]
+mod tests;
+
+/// Entities this far from a client's player are worth half as much to that client
+const HALF_PRIORITY_DISTANCE: f32 = 16.0;
+
+/// How much an entity matters compared to others when not all of them fit in a packet.
+/// Entities without one have a priority of 1.
+#[derive(Debug, Component, Clone, Copy, PartialEq)]
+pub struct NetworkPriority(pub f32);
+
+/// Bytes of entity updates each client gets per replication packet
+#[derive(Debug, Resource, Clone, Copy)]
+pub(super) struct PacketBudget(pub usize);
+
+/// The priority each entity built up for each client since it was last sent to them
+#[derive(Debug, Resource, Default)]
+pub(super) struct PriorityAccumulators(HashMap<u64, HashMap<Entity, f32>>);
+
+fn priority(world: &World, entity: Entity, player: Option<Vec3>) -> f32 {
+    let entity = world.entity(entity);
+    let priority = entity.get::<NetworkPriority>().map_or(1.0, |p| p.0);
+    let distance = match (player, entity.get::<Transform>()) {
+        (Some(player), Some(transform)) => player.distance(transform.translation),
+        _ => 0.0,
+    };
+
+    priority * HALF_PRIORITY_DISTANCE / (HALF_PRIORITY_DISTANCE + distance)
+}
+
+/// Forgets the clients that left
+pub(super) fn retain_clients(world: &mut World, clients: &[u64]) {
+    if let Some(mut accumulators) = world.get_resource_mut::<PriorityAccumulators>() {
+        accumulators.0.retain(|client, _| clients.contains(client));
+    }
+}
+
+/// Indices of the updates to send to this client, the ones that built up the most priority
+/// first for as long as they fit in the budget. The first one and the ones removing components
+/// are always sent, so that everything gets there eventually.
+pub(super) fn prioritize(world: &mut World, client: u64, updates: &[EntityUpdates]) -> Vec<usize> {
+    let Some(PacketBudget(budget)) = world.get_resource::<PacketBudget>().copied() else {
+        return (0..updates.len()).collect();
+    };
+
+    let player = world
+        .query::<(&Owner, &Transform)>()
+        .iter(world)
+        .find(|(&owner, _)| owner == Owner::Client(client))
+        .map(|(_, transform)| transform.translation);
+    let priorities = updates
+        .iter()
+        .map(|update| priority(world, update.entity, player))
+        .collect::<Vec<_>>();
+
+    let mut accumulators = world.resource_mut::<PriorityAccumulators>();
+    let accumulated = accumulators.0.entry(client).or_default();
+    let present = updates.iter().map(|u| u.entity).collect::<HashSet<_>>();
+    accumulated.retain(|entity, _| present.contains(entity));
+    for (update, priority) in updates.iter().zip(priorities) {
+        *accumulated.entry(update.entity).or_default() += priority;
+    }
+
+    let mut order = (0..updates.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        accumulated[&updates[b].entity].total_cmp(&accumulated[&updates[a].entity])
+    });
+
+    let mut left = budget;
+    let mut included = vec![];
+    for i in order {
+        let size = packet_size(&updates[i]);
+        if size <= left || included.is_empty() || !updates[i].removals.is_empty() {
+            left = left.saturating_sub(size);
+            included.push(i);
+            accumulated.insert(updates[i].entity, 0.0);
+        }
+    }
+
+    included.sort();
+    included
+}
@@ -0,0 +1,156 @@
+use crate::replicate::schedule::{NetworkUpdate, TickStrategy};
+use crate::replicate::*;
+use crate::test_utils::*;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u32);
+
+fn count_up(mut nums: Query<&mut Num>) {
+    for mut num in &mut nums {
+        num.0 += 1;
+    }
+}
+
+/// About two entities with a `Num` fit in a packet
+fn limited() -> ReplicationPlugin {
+    ReplicationPlugin::new(0.01, TickStrategy::Manual).with_bandwidth_budget(2_000)
+}
+
+fn all_updates(world: &mut World) -> Vec<EntityUpdates> {
+    world
+        .query_filtered::<Entity, With<Replicate>>()
+        .iter(world)
+        .collect_vec()
+        .into_iter()
+        .map(|entity| serialize_all_components(world, entity))
+        .collect()
+}
+
+/// Which entities get sent to client 1 for the next `packets` packets
+fn sent(world: &mut World, packets: usize) -> Vec<Vec<Entity>> {
+    let updates = all_updates(world);
+    (0..packets)
+        .map(|_| {
+            prioritize(world, 1, &updates)
+                .into_iter()
+                .map(|i| updates[i].entity)
+                .collect()
+        })
+        .collect()
+}
+
+#[test]
+fn everything_without_a_budget() {
+    let mut server = create_server();
+    server.replicate::<Num>();
+    for i in 0..10 {
+        server.world.spawn((Replicate, Num(i)));
+    }
+
+    assert_eq!(sent(&mut server.world, 1)[0].len(), 10);
+}
+
+#[test]
+fn the_rest_follows_later() {
+    let mut server = create_server_with(limited());
+    server.replicate::<Num>();
+    let entities = (0..6)
+        .map(|i| server.world.spawn((Replicate, Num(i))).id())
+        .collect_vec();
+
+    let packets = sent(&mut server.world, 3);
+    for packet in &packets {
+        assert_eq!(packet.len(), 2);
+    }
+    let mut all = packets.concat();
+    all.sort();
+    assert_eq!(all, entities);
+}
+
+#[test]
+fn higher_priorities_are_sent_more_often() {
+    let mut server = create_server_with(limited());
+    server.replicate::<Num>();
+    let important = server
+        .world
+        .spawn((Replicate, Num(0), NetworkPriority(4.0)))
+        .id();
+    let others = (0..4)
+        .map(|i| server.world.spawn((Replicate, Num(i))).id())
+        .collect_vec();
+
+    let packets = sent(&mut server.world, 20);
+    let times_sent = |entity| packets.iter().filter(|p| p.contains(&entity)).count();
+    for other in others {
+        assert!(times_sent(important) > 2 * times_sent(other));
+        assert!(times_sent(other) > 0);
+    }
+}
+
+#[test]
+fn closer_to_the_player_first() {
+    let mut server = create_server_with(limited());
+    server.replicate::<Num>();
+    server.world.spawn((Owner::Client(1), Transform::default()));
+    let far = (0..4)
+        .map(|i| {
+            let transform = Transform::from_xyz(100.0 + i as f32, 0.0, 0.0);
+            server.world.spawn((Replicate, Num(i), transform)).id()
+        })
+        .collect_vec();
+    let near = server
+        .world
+        .spawn((Replicate, Num(0), Transform::from_xyz(1.0, 0.0, 0.0)))
+        .id();
+
+    let packets = sent(&mut server.world, 6);
+    assert!(packets[0].contains(&near));
+    let times_sent = |entity| packets.iter().filter(|p| p.contains(&entity)).count();
+    assert!(times_sent(near) > times_sent(far[0]));
+}
+
+#[test]
+fn left_out_entities_keep_their_last_state() {
+    let mut server = create_server_with(limited());
+    let mut client = create_client_with(&mut server, limited());
+    for app in [&mut server, &mut client] {
+        app.replicate::<Num>().add_systems(NetworkUpdate, count_up);
+    }
+    for i in 0..6 {
+        server.world.spawn((Replicate, Num(i * 100)));
+    }
+
+    // Predict a few ticks ahead, which gets resimulated with every packet
+    for _ in 0..3 {
+        tick(&mut client);
+    }
+    for _ in 0..20 {
+        tick(&mut client);
+        tick(&mut server);
+        client.update();
+
+        // Resimulating from what the server last sent never runs ahead of the server
+        let ahead =
+            client.world.resource::<NetworkTick>().0 - server.world.resource::<NetworkTick>().0;
+        let server_nums = server
+            .world
+            .query::<&Num>()
+            .iter(&server.world)
+            .map(|n| n.0)
+            .collect_vec();
+        for num in client.world.query::<&Num>().iter(&client.world) {
+            // Each entity counts up from a different hundred
+            let on_server = server_nums
+                .iter()
+                .find(|&&n| n / 100 == num.0 / 100)
+                .unwrap();
+            assert!(
+                num.0 <= on_server + ahead as u32,
+                "{num:?} ran ahead of {on_server}"
+            );
+        }
+    }
+    assert_eq!(count::<&Num>(&mut client), 6);
+}
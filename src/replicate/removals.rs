@@ -0,0 +1,124 @@
+use std::collections::VecDeque;
+
+use bevy::ecs::component::ComponentId;
+use bevy::ecs::event::ManualEventReader;
+use bevy::ecs::removal_detection::RemovedComponentEntity;
+use bevy::prelude::*;
+use bevy::utils::HashMap;
+
+use super::baseline::{acked, everyone_acked};
+use super::{NetworkTick, Replicate, ReplicationFunctions};
+
+#[cfg(test)]
+mod tests;
+
+/// Despawns and component removals on the server, by the tick of the packet they were first
+/// sent in, until every client acknowledged that packet
+#[derive(Resource, Default)]
+pub(super) struct Removals {
+    readers: HashMap<ComponentId, ManualEventReader<RemovedComponentEntity>>,
+    unsent: TickRemovals,
+    sent: VecDeque<TickRemovals>,
+}
+
+#[derive(Default)]
+struct TickRemovals {
+    tick: NetworkTick,
+    despawns: Vec<Entity>,
+    removals: Vec<(Entity, usize)>,
+}
+
+/// What a client still has to despawn, and the components it still has to remove by entity
+#[derive(Default)]
+pub(super) struct ClientRemovals {
+    pub despawns: Vec<Entity>,
+    pub removals: HashMap<Entity, Vec<usize>>,
+}
+
+fn read_removed(world: &World, removals: &mut Removals, component_id: ComponentId) -> Vec<Entity> {
+    let Some(events) = world.removed_components().get(component_id) else {
+        return vec![];
+    };
+    let reader = removals.readers.entry(component_id).or_default();
+    reader.read(events).map(|e| e.clone().into()).collect()
+}
+
+/// Keeps the removal events before they are cleared at the end of the frame, as the send ticks
+/// may be frames apart
+pub(super) fn collect_removals(world: &mut World) {
+    world.resource_scope::<Removals, ()>(|world, mut removals| {
+        let replicate = world.component_id::<Replicate>().unwrap();
+        let despawns = read_removed(world, &mut removals, replicate);
+        removals.unsent.despawns.extend(despawns);
+
+        for (replication_id, f) in world.resource::<ReplicationFunctions>().iter().enumerate() {
+            let removed = read_removed(world, &mut removals, f.component_id);
+            removals
+                .unsent
+                .removals
+                .extend(removed.into_iter().map(|entity| (entity, replication_id)));
+        }
+    });
+}
+
+/// Starts tracking the removals since the last packet as part of the packet of this tick, and
+/// forgets the ones every client has. Returns those new removals.
+pub(super) fn send_removals(world: &mut World, tick: NetworkTick) -> ClientRemovals {
+    collect_removals(world);
+
+    let mut new = std::mem::take(&mut world.resource_mut::<Removals>().unsent);
+    new.tick = tick;
+    let sent = still_true(world, std::iter::once(&new));
+
+    if !new.despawns.is_empty() || !new.removals.is_empty() {
+        world.resource_mut::<Removals>().sent.push_back(new);
+    }
+    while let Some(oldest) = world.resource::<Removals>().sent.front() {
+        if !everyone_acked(world, oldest.tick) {
+            break;
+        }
+        world.resource_mut::<Removals>().sent.pop_front();
+    }
+
+    sent
+}
+
+/// The removals this client has not acknowledged
+pub(super) fn client_removals(world: &World, client: u64) -> ClientRemovals {
+    let acked = acked(world, client);
+    let removals = world.resource::<Removals>();
+    let unacked = removals
+        .sent
+        .iter()
+        .filter(|removals| acked.is_none_or(|acked| removals.tick > acked));
+    still_true(world, unacked)
+}
+
+/// Leaves out the entities and components that came back since they were removed
+fn still_true<'a>(world: &World, ticks: impl Iterator<Item = &'a TickRemovals>) -> ClientRemovals {
+    let functions = world.resource::<ReplicationFunctions>();
+    let replicated = |entity| {
+        world
+            .get_entity(entity)
+            .is_some_and(|e| e.contains::<Replicate>())
+    };
+
+    let mut client = ClientRemovals::default();
+    for removals in ticks {
+        for &entity in &removals.despawns {
+            if !replicated(entity) && !client.despawns.contains(&entity) {
+                client.despawns.push(entity);
+            }
+        }
+        for &(entity, replication_id) in &removals.removals {
+            if !replicated(entity) || (functions[replication_id].gather)(world, entity).is_some() {
+                continue;
+            }
+            let components = client.removals.entry(entity).or_default();
+            if !components.contains(&replication_id) {
+                components.push(replication_id);
+            }
+        }
+    }
+    client
+}
@@ -0,0 +1,141 @@
+use crate::replicate::schedule::TickStrategy;
+use crate::replicate::*;
+use crate::test_utils::*;
+use crate::transport::memory::MemoryClientTransport;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u32);
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Marker;
+
+fn setup(replication: impl Fn() -> ReplicationPlugin) -> (App, App) {
+    let mut server = create_server_with(replication());
+    let mut client = create_client_with(&mut server, replication());
+    for app in [&mut server, &mut client] {
+        app.replicate::<Num>().replicate::<Marker>();
+    }
+    (server, client)
+}
+
+fn every_tick() -> ReplicationPlugin {
+    ReplicationPlugin::new(0.01, TickStrategy::Manual)
+}
+
+fn client_id(client: &App) -> u64 {
+    client
+        .world
+        .resource::<MemoryClientTransport>()
+        .client_id()
+        .raw()
+}
+
+fn replication_id<C: Component>(app: &App) -> usize {
+    let component_id = app.world.component_id::<C>().unwrap();
+    app.world
+        .resource::<ReplicationFunctions>()
+        .iter()
+        .position(|f| f.component_id == component_id)
+        .unwrap()
+}
+
+fn nums(app: &mut App) -> Vec<u32> {
+    app.world
+        .query::<&Num>()
+        .iter(&app.world)
+        .map(|n| n.0)
+        .sorted()
+        .collect()
+}
+
+#[test]
+fn despawn_and_respawn_in_the_same_tick() {
+    let (mut server, mut client) = setup(every_tick);
+    let first = server.world.spawn((Replicate, Num(1))).id();
+    tick(&mut server);
+    client.update();
+    assert_eq!(nums(&mut client), vec![1]);
+
+    server.world.despawn(first);
+    let second = server.world.spawn((Replicate, Num(2))).id();
+    assert_eq!(first.index(), second.index());
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(nums(&mut client), vec![2]);
+    let entities = client.world.resource::<NetworkEntities>();
+    assert!(!entities.contains_key(&first));
+    assert!(entities.contains_key(&second));
+}
+
+#[test]
+fn remove_and_add_back_in_the_same_tick() {
+    let (mut server, mut client) = setup(every_tick);
+    let entity = server.world.spawn((Replicate, Num(1), Marker)).id();
+    tick(&mut server);
+    client.update();
+
+    server.world.entity_mut(entity).remove::<Marker>();
+    server.world.entity_mut(entity).insert(Marker);
+    tick(&mut server);
+    client.update();
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(count::<&Marker>(&mut client), 1);
+}
+
+#[test]
+fn removals_between_send_ticks_are_kept() {
+    let (mut server, mut client) = setup(|| every_tick().with_send_interval(4));
+    let despawned = server.world.spawn((Replicate, Num(1))).id();
+    let changed = server.world.spawn((Replicate, Num(2), Marker)).id();
+    for _ in 0..4 {
+        tick(&mut server);
+    }
+    client.update();
+    assert_eq!(count::<&Marker>(&mut client), 1);
+
+    // The removal events are long gone by the next send tick
+    server.world.despawn(despawned);
+    server.world.entity_mut(changed).remove::<Marker>();
+    for _ in 0..4 {
+        tick(&mut server);
+    }
+    client.update();
+
+    assert_eq!(nums(&mut client), vec![2]);
+    assert_eq!(count::<&Marker>(&mut client), 0);
+}
+
+#[test]
+fn resent_until_acknowledged() {
+    let (mut server, mut client) = setup(every_tick);
+    let entity = server.world.spawn((Replicate, Num(1), Marker)).id();
+    tick(&mut server);
+    client.update();
+    server.update();
+
+    server.world.entity_mut(entity).remove::<Marker>();
+    for _ in 0..3 {
+        tick(&mut server);
+        let removals = client_removals(&server.world, client_id(&client));
+        assert_eq!(
+            removals.removals[&entity],
+            vec![replication_id::<Marker>(&server)]
+        );
+    }
+
+    client.update();
+    assert_eq!(count::<&Marker>(&mut client), 0);
+    tick(&mut server);
+    assert!(server.world.resource::<Removals>().sent.is_empty());
+
+    // Adding it back is not undone by the removal the client got more than once
+    server.world.entity_mut(entity).insert(Marker);
+    tick(&mut server);
+    client.update();
+    assert_eq!(count::<&Marker>(&mut client), 1);
+}
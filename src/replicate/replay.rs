@@ -0,0 +1,387 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::HashSet;
+use serde::{Deserialize, Serialize};
+
+use super::baseline::despawn_missing;
+use super::codec::{deserialize_packet, serialize_packet};
+use super::schedule::{NetworkBlueprint, NetworkFixedTime, NetworkResync, TickStrategy};
+use super::{apply_replication_packet, NetworkTick, ReplicationFunctions, ReplicationPacket};
+
+#[cfg(test)]
+mod tests;
+
+/// Bumped whenever the layout of replay files changes
+const REPLAY_VERSION: u32 = 3;
+/// How much of the replay a crashed server may lose
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ReplayHeader {
+    version: u32,
+    /// Seconds per tick
+    pub period: f32,
+    /// Type names of the replicated components, in the order of their replication ids
+    pub components: Vec<String>,
+}
+
+impl ReplayHeader {
+    fn new(world: &World) -> Self {
+        Self {
+            version: REPLAY_VERSION,
+            period: world
+                .resource::<NetworkFixedTime>()
+                .duration()
+                .as_secs_f32(),
+            components: component_names(world),
+        }
+    }
+
+    pub fn tick_rate(&self) -> u32 {
+        (1.0 / self.period).round() as u32
+    }
+}
+
+fn component_names(world: &World) -> Vec<String> {
+    world
+        .resource::<ReplicationFunctions>()
+        .iter()
+        .map(|f| f.name.to_string())
+        .collect()
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum ReplayFrame {
+    /// A serialized [`ReplicationPacket`]
+    Replication(Vec<u8>),
+    Input(RecordedInput),
+}
+
+/// An input packet as the server received it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecordedInput {
+    /// The server tick it arrived on
+    pub tick: NetworkTick,
+    pub client_id: u64,
+    /// A serialized `InputPacket`
+    pub packet: Vec<u8>,
+}
+
+/// Insert on the server to write every replication packet it sends and every input packet it
+/// receives to a replay file. Flushed every [`FLUSH_INTERVAL`], on [`AppExit`] and when dropped.
+#[derive(Resource)]
+pub struct ReplayRecorder {
+    writer: Box<dyn Write + Send + Sync>,
+    wrote_header: bool,
+    last_flush: Instant,
+}
+
+impl ReplayRecorder {
+    pub fn create(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(Self::new(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn new(writer: impl Write + Send + Sync + 'static) -> Self {
+        Self {
+            writer: Box::new(writer),
+            wrote_header: false,
+            last_flush: Instant::now(),
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+
+    fn write(&mut self, frame: &ReplayFrame) -> bincode::Result<()> {
+        bincode::serialize_into(&mut self.writer, frame)?;
+        if self.last_flush.elapsed() >= FLUSH_INTERVAL {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn record_input(&mut self, tick: NetworkTick, client_id: u64, packet: &[u8]) {
+        // Inputs from before the first replication packet have nothing to apply to
+        if !self.wrote_header {
+            return;
+        }
+
+        let frame = ReplayFrame::Input(RecordedInput {
+            tick,
+            client_id,
+            packet: packet.to_vec(),
+        });
+        if let Err(err) = self.write(&frame) {
+            println!("Could not record input: {err}");
+        }
+    }
+}
+
+impl Drop for ReplayRecorder {
+    fn drop(&mut self) {
+        if let Err(err) = self.flush() {
+            println!("Could not finish the replay: {err}");
+        }
+    }
+}
+
+/// The app may be ended without dropping the recorder
+pub(super) fn flush_replay_on_exit(
+    app_exit: EventReader<AppExit>,
+    recorder: Option<ResMut<ReplayRecorder>>,
+) {
+    let Some(mut recorder) = recorder else {
+        return;
+    };
+    if app_exit.is_empty() {
+        return;
+    }
+
+    if let Err(err) = recorder.flush() {
+        println!("Could not finish the replay: {err}");
+    }
+}
+
+/// Called with the whole state every time the server sends replication packets
+pub(super) fn record_replication_packet(world: &mut World, packet: &ReplicationPacket) {
+    let Some(recorder) = world.get_resource::<ReplayRecorder>() else {
+        return;
+    };
+    let packet = serialize_packet(packet);
+    let header = (!recorder.wrote_header).then(|| ReplayHeader::new(world));
+    let mut recorder = world.resource_mut::<ReplayRecorder>();
+
+    let mut result = Ok(());
+    if let Some(header) = header {
+        result = bincode::serialize_into(&mut recorder.writer, &header);
+        recorder.wrote_header = true;
+    }
+    if let Err(err) = result.and_then(|_| recorder.write(&ReplayFrame::Replication(packet))) {
+        println!("Could not record replication packet: {err}");
+    }
+}
+
+/// A recorded match, read back from a replay file
+#[derive(Debug, Clone)]
+pub struct Replay {
+    pub header: ReplayHeader,
+    snapshots: Vec<ReplicationPacket>,
+    pub inputs: Vec<RecordedInput>,
+}
+
+impl Replay {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let file = File::open(path).map_err(|err| err.to_string())?;
+        Self::read(BufReader::new(file))
+    }
+
+    /// A file that ends partway through a frame, like when the server crashed, is read up to that
+    /// frame
+    pub fn read(mut reader: impl Read) -> Result<Self, String> {
+        let header: ReplayHeader = bincode::deserialize_from(&mut reader)
+            .map_err(|err| format!("Not a replay file: {err}"))?;
+        if header.version != REPLAY_VERSION {
+            return Err(format!(
+                "The replay is version {}, but only version {REPLAY_VERSION} can be played",
+                header.version
+            ));
+        }
+
+        let mut replay = Replay {
+            header,
+            snapshots: vec![],
+            inputs: vec![],
+        };
+        loop {
+            match bincode::deserialize_from(&mut reader) {
+                Ok(ReplayFrame::Replication(packet)) => replay
+                    .snapshots
+                    .push(deserialize_packet(&packet).map_err(|err| format!("Bad packet: {err}"))?),
+                Ok(ReplayFrame::Input(input)) => replay.inputs.push(input),
+                Err(err) => match *err {
+                    bincode::ErrorKind::Io(err) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                        break
+                    }
+                    err => return Err(format!("Bad frame: {err}")),
+                },
+            }
+        }
+
+        Ok(replay)
+    }
+
+    /// The replication ids in the replay only mean something if this app registered the same
+    /// components in the same order
+    pub fn check_components(&self, world: &World) -> Result<(), String> {
+        let components = component_names(world);
+        if components == self.header.components {
+            return Ok(());
+        }
+
+        let missing = self
+            .header
+            .components
+            .iter()
+            .filter(|c| !components.contains(c))
+            .cloned()
+            .collect::<Vec<_>>();
+        Err(format!(
+            "The replay was recorded with different replicated components. Missing here: {missing:?}"
+        ))
+    }
+
+    pub fn first_tick(&self) -> NetworkTick {
+        self.snapshots.first().map(|s| s.tick).unwrap_or_default()
+    }
+
+    pub fn last_tick(&self) -> NetworkTick {
+        self.snapshots.last().map(|s| s.tick).unwrap_or_default()
+    }
+
+    /// Index of the last snapshot at or before `tick`
+    fn snapshot_at(&self, tick: f64) -> Option<usize> {
+        self.snapshots
+            .partition_point(|s| s.tick.0 as f64 <= tick)
+            .checked_sub(1)
+    }
+}
+
+/// Plays a replay instead of receiving packets from a server
+pub struct ReplayPlugin {
+    pub replay: Replay,
+}
+
+/// Where the replay is at and how fast it goes
+#[derive(Resource)]
+pub struct ReplayPlayer {
+    replay: Replay,
+    /// The tick being played, which moves in fractions of a tick between snapshots
+    position: f64,
+    pub speed: f64,
+    pub paused: bool,
+    shown: Option<usize>,
+}
+
+impl ReplayPlayer {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            position: replay.first_tick().0 as f64,
+            replay,
+            speed: 1.0,
+            paused: false,
+            shown: None,
+        }
+    }
+
+    pub fn tick(&self) -> NetworkTick {
+        NetworkTick(self.position as u64)
+    }
+
+    pub fn seek(&mut self, tick: f64) {
+        self.position = tick.clamp(
+            self.replay.first_tick().0 as f64,
+            self.replay.last_tick().0 as f64,
+        );
+    }
+
+    pub fn advance(&mut self, seconds: f64) {
+        if !self.paused {
+            self.seek(self.position + seconds * self.speed / self.replay.header.period as f64);
+        }
+    }
+}
+
+impl Plugin for ReplayPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(ReplayPlayer::new(self.replay.clone()))
+            // The replay moves the world, so it must not simulate on its own
+            .insert_resource(TickStrategy::Manual)
+            .add_systems(PreUpdate, show_replay)
+            .add_systems(
+                Update,
+                (replay_controls, advance_replay, replay_title).chain(),
+            );
+    }
+}
+
+/// Space pauses, the left and right arrows seek five seconds, up and down change the speed and
+/// home starts over
+fn replay_controls(mut player: ResMut<ReplayPlayer>, keys: Res<Input<KeyCode>>) {
+    let seconds = 5.0 / player.replay.header.period as f64;
+
+    if keys.just_pressed(KeyCode::Space) {
+        player.paused = !player.paused;
+    }
+    if keys.just_pressed(KeyCode::Left) {
+        let position = player.position;
+        player.seek(position - seconds);
+    }
+    if keys.just_pressed(KeyCode::Right) {
+        let position = player.position;
+        player.seek(position + seconds);
+    }
+    if keys.just_pressed(KeyCode::Up) {
+        player.speed = (player.speed * 2.0).min(16.0);
+    }
+    if keys.just_pressed(KeyCode::Down) {
+        player.speed = (player.speed / 2.0).max(1.0 / 16.0);
+    }
+    if keys.just_pressed(KeyCode::Home) {
+        let first = player.replay.first_tick().0 as f64;
+        player.seek(first);
+    }
+}
+
+fn advance_replay(mut player: ResMut<ReplayPlayer>, time: Res<Time>) {
+    player.advance(time.delta_seconds_f64());
+}
+
+fn replay_title(player: Res<ReplayPlayer>, mut windows: Query<&mut Window>) {
+    let mut title = format!(
+        "Replay - tick {} of {} at {}x",
+        player.tick().0,
+        player.replay.last_tick().0,
+        player.speed
+    );
+    if player.paused {
+        title += ", paused";
+    }
+
+    for mut window in &mut windows {
+        if window.title != title {
+            window.title = title.clone();
+        }
+    }
+}
+
+/// Applies the snapshot for the current position. Every snapshot holds the whole state, so
+/// seeking only has to get rid of the entities that are not in it.
+fn show_replay(world: &mut World) {
+    let player = world.resource::<ReplayPlayer>();
+    let Some(index) = player.replay.snapshot_at(player.position) else {
+        return;
+    };
+    if player.shown == Some(index) {
+        return;
+    }
+    let packet = player.replay.snapshots[index].clone();
+
+    let present = packet
+        .updates
+        .iter()
+        .map(|updates| updates.entity)
+        .collect::<HashSet<_>>();
+    despawn_missing(world, &present);
+
+    apply_replication_packet(world, packet);
+    let _ = world.try_run_schedule(NetworkResync);
+    let _ = world.try_run_schedule(NetworkBlueprint);
+    world.resource_mut::<ReplayPlayer>().shown = Some(index);
+}
@@ -0,0 +1,132 @@
+use std::env;
+use std::fs;
+
+use crate::replicate::schedule::NetworkUpdate;
+use crate::replicate::*;
+use crate::test_utils::*;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u64);
+
+fn count_up(mut nums: Query<&mut Num>) {
+    for mut num in &mut nums {
+        num.0 += 1;
+    }
+}
+
+fn nums(app: &mut App) -> Vec<u64> {
+    let mut nums = app
+        .world
+        .query::<&Num>()
+        .iter(&app.world)
+        .map(|num| num.0)
+        .collect_vec();
+    nums.sort();
+    nums
+}
+
+fn show(app: &mut App, tick: u64) {
+    let mut player = app.world.resource_mut::<ReplayPlayer>();
+    player.paused = true;
+    player.seek(tick as f64);
+    app.update();
+}
+
+#[test]
+fn record_and_seek() {
+    let path = env::temp_dir().join(format!("mp-test-{}.replay", std::process::id()));
+
+    let mut server = create_server();
+    server
+        .replicate::<Num>()
+        .add_systems(NetworkUpdate, count_up)
+        .insert_resource(ReplayRecorder::create(&path).unwrap());
+    server.world.spawn((Replicate, Num(0)));
+    let despawned = server.world.spawn((Replicate, Num(0))).id();
+
+    for _ in 0..10 {
+        tick(&mut server);
+    }
+    server.world.despawn(despawned);
+    for _ in 0..10 {
+        tick(&mut server);
+    }
+    server.world.remove_resource::<ReplayRecorder>();
+
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let replay = Replay::read(bytes.as_slice()).unwrap();
+    assert_eq!(
+        replay.header.components,
+        [std::any::type_name::<Owner>(), std::any::type_name::<Num>()]
+    );
+    assert_eq!(replay.header.tick_rate(), 100);
+    assert_eq!((replay.first_tick().0, replay.last_tick().0), (1, 20));
+
+    // A server that died while writing leaves the last frame cut off
+    let truncated = Replay::read(&bytes[..bytes.len() - 3]).unwrap();
+    assert_eq!(truncated.last_tick().0, 19);
+
+    let mut viewer = App::new();
+    viewer
+        .add_plugins((
+            MinimalPlugins,
+            ReplicationPlugin::new(0.01, TickStrategy::Manual),
+        ))
+        .init_resource::<Input<KeyCode>>()
+        .replicate::<Num>();
+    replay.check_components(&viewer.world).unwrap();
+    viewer.add_plugins(ReplayPlugin { replay });
+
+    show(&mut viewer, 5);
+    assert_eq!(nums(&mut viewer), [5, 5]);
+    assert_eq!(*viewer.world.resource::<NetworkTick>(), NetworkTick(5));
+
+    show(&mut viewer, 15);
+    assert_eq!(nums(&mut viewer), [15]);
+
+    // Seeking back brings the despawned entity back
+    show(&mut viewer, 5);
+    assert_eq!(nums(&mut viewer), [5, 5]);
+}
+
+#[test]
+fn exiting_finishes_the_replay() {
+    let path = env::temp_dir().join(format!("mp-test-exit-{}.replay", std::process::id()));
+
+    let mut server = create_server();
+    server
+        .replicate::<Num>()
+        .add_systems(NetworkUpdate, count_up)
+        .insert_resource(ReplayRecorder::create(&path).unwrap());
+    server.world.spawn((Replicate, Num(0)));
+    for _ in 0..10 {
+        tick(&mut server);
+    }
+
+    server.world.send_event(AppExit);
+    server.update();
+
+    let bytes = fs::read(&path).unwrap();
+    fs::remove_file(&path).unwrap();
+    let replay = Replay::read(bytes.as_slice()).unwrap();
+    assert_eq!(replay.last_tick().0, 10);
+}
+
+#[test]
+fn different_components_are_refused() {
+    let mut server = create_server();
+    server.replicate::<Num>();
+    let replay = Replay {
+        header: ReplayHeader::new(&server.world),
+        snapshots: vec![],
+        inputs: vec![],
+    };
+
+    let mut viewer = create_server();
+    assert!(replay.check_components(&viewer.world).is_err());
+    viewer.replicate::<Num>();
+    assert!(replay.check_components(&viewer.world).is_ok());
+}
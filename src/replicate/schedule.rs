@@ -8,6 +8,7 @@ use itertools::Itertools;
 use crate::prediction::{is_desynced, Resimulating};
 use crate::replicate::{NetworkTick, SyncedServerTick};
 
+use super::stats::NetworkStats;
 use super::Replicate;
 
 #[cfg(test)]
@@ -16,14 +17,23 @@ mod tests;
 #[derive(Debug, Resource, Deref, DerefMut)]
 pub struct NetworkFixedTime(pub Timer);
 
+/// How many network ticks pass between each replication packet sent by the server.
+#[derive(Debug, Resource, Clone, Copy, PartialEq, Eq, Deref, DerefMut)]
+pub struct NetworkSendInterval(pub u64);
+
+impl Default for NetworkSendInterval {
+    fn default() -> Self {
+        NetworkSendInterval(1)
+    }
+}
+
 #[derive(Resource)]
 pub struct DoTick;
 
-#[derive(Default, Resource, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Default, Resource, PartialEq, Eq, Clone, Copy)]
 pub enum TickStrategy {
     #[default]
     Automatic,
-    #[allow(unused)]
     Manual,
 }
 
@@ -39,6 +49,8 @@ pub struct NetworkPreUpdate;
 pub struct NetworkUpdate;
 #[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
 pub struct NetworkPostUpdate;
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct NetworkSend;
 
 #[derive(Resource, Debug, PartialEq, Eq, Hash)]
 pub(super) struct NetworkScheduleOrder {
@@ -54,6 +66,7 @@ impl Default for NetworkScheduleOrder {
                 NetworkPreUpdate.intern(),
                 NetworkUpdate.intern(),
                 NetworkPostUpdate.intern(),
+                NetworkSend.intern(),
             ],
         }
     }
@@ -124,6 +137,8 @@ pub(super) fn run_network_fixed(world: &mut World) {
                     }
                 }
                 world.remove_resource::<Resimulating>();
+                world.resource_mut::<NetworkStats>().resimulated_ticks +=
+                    current_tick.0 - synced_server_tick.0;
             }
         }
 
@@ -135,6 +150,10 @@ pub(super) fn run_network_fixed(world: &mut World) {
     });
 }
 
+pub(super) fn is_send_tick(tick: Res<NetworkTick>, interval: Res<NetworkSendInterval>) -> bool {
+    tick.0.is_multiple_of(interval.0.max(1))
+}
+
 fn how_many_times_to_run(world: &mut World) -> u32 {
     match *world.resource::<TickStrategy>() {
         TickStrategy::Automatic => world
@@ -0,0 +1,269 @@
+use std::fmt::Write;
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy_renet::renet::RenetServer;
+
+use crate::transport::{ClientConnection, ConnectionStats};
+
+use super::{Channel, NetworkTick, Owner, SyncedServerTick};
+
+#[cfg(test)]
+mod tests;
+
+/// Rates are measured over this long
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ChannelStats {
+    /// Message bytes handed to renet, counting a broadcast once per client
+    pub sent: u64,
+    pub received: u64,
+    pub sent_per_sec: f64,
+    pub received_per_sec: f64,
+    sent_at_last_rate: u64,
+    received_at_last_rate: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct PacketSizes {
+    pub count: u64,
+    pub last: usize,
+    pub max: usize,
+    total: u64,
+}
+
+impl PacketSizes {
+    fn add(&mut self, size: usize) {
+        self.count += 1;
+        self.last = size;
+        self.max = self.max.max(size);
+        self.total += size as u64;
+    }
+
+    pub fn average(&self) -> f64 {
+        self.total as f64 / self.count.max(1) as f64
+    }
+}
+
+/// What the network is doing, on the server as well as on the client
+#[derive(Debug, Resource, Default, Clone)]
+pub struct NetworkStats {
+    /// The server on a client, and every client on the server
+    pub connections: Vec<(Owner, ConnectionStats)>,
+    /// Indexed by [`Channel`]
+    pub channels: [ChannelStats; Channel::ALL.len()],
+    pub replication_packets: PacketSizes,
+    pub resimulated_ticks: u64,
+    pub resimulated_per_sec: f64,
+    resimulated_at_last_rate: u64,
+    /// How many ticks the client's prediction is ahead of the last tick it got from the server
+    pub ticks_ahead: Option<i64>,
+    /// How many ticks of input the server has for each client beyond the current tick. Negative
+    /// when the input arrives too late.
+    pub input_buffers: Vec<(Owner, i64)>,
+    last_rate: Duration,
+}
+
+impl NetworkStats {
+    pub fn sent(&mut self, channel: Channel, bytes: usize) {
+        self.channels[channel as usize].sent += bytes as u64;
+    }
+
+    pub fn received(&mut self, channel: Channel, bytes: usize) {
+        self.channels[channel as usize].received += bytes as u64;
+    }
+
+    pub fn channel(&self, channel: Channel) -> &ChannelStats {
+        &self.channels[channel as usize]
+    }
+
+    pub fn input_buffer(&mut self, owner: Owner, depth: i64) {
+        match self.input_buffers.iter_mut().find(|(o, _)| *o == owner) {
+            Some((_, d)) => *d = depth,
+            None => self.input_buffers.push((owner, depth)),
+        }
+    }
+
+    pub(super) fn replication_packet(&mut self, bytes: usize) {
+        self.replication_packets.add(bytes);
+    }
+
+    fn update_rates(&mut self, now: Duration) {
+        let elapsed = (now - self.last_rate).as_secs_f64();
+        if now - self.last_rate < RATE_WINDOW {
+            return;
+        }
+        self.last_rate = now;
+
+        for channel in &mut self.channels {
+            channel.sent_per_sec = (channel.sent - channel.sent_at_last_rate) as f64 / elapsed;
+            channel.received_per_sec =
+                (channel.received - channel.received_at_last_rate) as f64 / elapsed;
+            channel.sent_at_last_rate = channel.sent;
+            channel.received_at_last_rate = channel.received;
+        }
+        self.resimulated_per_sec =
+            (self.resimulated_ticks - self.resimulated_at_last_rate) as f64 / elapsed;
+        self.resimulated_at_last_rate = self.resimulated_ticks;
+    }
+
+    /// A few lines of text for people to read
+    pub fn summary(&self, tick: NetworkTick) -> String {
+        let mut summary = format!("Tick {}", tick.0);
+        if let Some(ticks_ahead) = self.ticks_ahead {
+            let _ = write!(summary, ", {ticks_ahead} ahead of the server");
+        }
+        let _ = writeln!(
+            summary,
+            "\nResimulated {:.0} ticks/s",
+            self.resimulated_per_sec
+        );
+
+        for (owner, connection) in &self.connections {
+            let peer = match owner {
+                Owner::Server => "Server".to_string(),
+                Owner::Client(id) => format!("Client {id}"),
+            };
+            let _ = writeln!(
+                summary,
+                "{peer}: rtt {:.0} ms, loss {:.1}%, up {:.1} kB/s, down {:.1} kB/s",
+                connection.rtt * 1000.0,
+                connection.packet_loss * 100.0,
+                connection.bytes_sent_per_sec / 1000.0,
+                connection.bytes_received_per_sec / 1000.0,
+            );
+        }
+
+        for channel in Channel::ALL {
+            let stats = self.channel(channel);
+            let _ = writeln!(
+                summary,
+                "{channel:?}: up {:.1} kB/s, down {:.1} kB/s",
+                stats.sent_per_sec / 1000.0,
+                stats.received_per_sec / 1000.0,
+            );
+        }
+
+        let packets = &self.replication_packets;
+        let _ = writeln!(
+            summary,
+            "Replication packets: last {} B, average {:.0} B, max {} B",
+            packets.last,
+            packets.average(),
+            packets.max
+        );
+
+        for (owner, depth) in &self.input_buffers {
+            let _ = writeln!(summary, "Input buffer of {owner:?}: {depth} ticks");
+        }
+
+        summary
+    }
+}
+
+pub(super) fn update_stats(
+    mut stats: ResMut<NetworkStats>,
+    time: Res<Time<Real>>,
+    connection: Option<Res<ClientConnection>>,
+    server: Option<Res<RenetServer>>,
+    tick: Res<NetworkTick>,
+    synced_server_tick: Option<Res<SyncedServerTick>>,
+) {
+    stats.update_rates(time.elapsed());
+
+    stats.connections.clear();
+    if let Some(connection) = connection {
+        stats.connections.push((Owner::Server, connection.stats));
+    }
+    if let Some(server) = server {
+        for client_id in server.clients_id() {
+            let Ok(info) = server.network_info(client_id) else {
+                continue;
+            };
+            stats.connections.push((
+                Owner::Client(client_id.raw()),
+                ConnectionStats {
+                    rtt: info.rtt,
+                    packet_loss: info.packet_loss,
+                    bytes_sent_per_sec: info.bytes_sent_per_second,
+                    bytes_received_per_sec: info.bytes_received_per_second,
+                },
+            ));
+        }
+    }
+
+    // Forget the clients that left
+    let NetworkStats {
+        connections,
+        input_buffers,
+        ..
+    } = &mut *stats;
+    input_buffers.retain(|(owner, _)| connections.iter().any(|(o, _)| o == owner));
+
+    if let Some(synced_server_tick) = synced_server_tick {
+        stats.ticks_ahead = Some(tick.0 as i64 - synced_server_tick.tick.0 as i64);
+    }
+}
+
+/// Shows [`NetworkStats`] in the corner of the window, F3 toggles it
+pub struct NetworkStatsOverlayPlugin;
+
+#[derive(Component)]
+struct Overlay;
+
+impl Plugin for NetworkStatsOverlayPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Startup, spawn_overlay)
+            .add_systems(Update, (toggle_overlay, update_overlay).chain());
+    }
+}
+
+fn spawn_overlay(mut commands: Commands) {
+    commands.spawn((
+        Overlay,
+        Name::new("Network stats"),
+        TextBundle {
+            visibility: Visibility::Hidden,
+            ..TextBundle::from_section(
+                "",
+                TextStyle {
+                    font_size: 14.0,
+                    color: Color::WHITE,
+                    ..default()
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                top: Val::Px(8.0),
+                left: Val::Px(8.0),
+                ..default()
+            })
+            .with_background_color(Color::rgba(0.0, 0.0, 0.0, 0.6))
+        },
+    ));
+}
+
+fn toggle_overlay(keys: Res<Input<KeyCode>>, mut overlay: Query<&mut Visibility, With<Overlay>>) {
+    if keys.just_pressed(KeyCode::F3) {
+        for mut visibility in &mut overlay {
+            *visibility = match *visibility {
+                Visibility::Hidden => Visibility::Visible,
+                _ => Visibility::Hidden,
+            };
+        }
+    }
+}
+
+fn update_overlay(
+    stats: Res<NetworkStats>,
+    tick: Res<NetworkTick>,
+    mut overlay: Query<(&mut Text, &Visibility), With<Overlay>>,
+) {
+    for (mut text, visibility) in &mut overlay {
+        if visibility == Visibility::Hidden {
+            continue;
+        }
+        text.sections[0].value = stats.summary(*tick);
+    }
+}
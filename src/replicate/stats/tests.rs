@@ -0,0 +1,89 @@
+use crate::replicate::schedule::NetworkUpdate;
+use crate::replicate::*;
+use crate::test_utils::*;
+
+use super::*;
+
+#[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
+struct Num(u32);
+
+fn count_up(mut nums: Query<&mut Num>) {
+    for mut num in &mut nums {
+        num.0 += 1;
+    }
+}
+
+fn setup() -> (App, App) {
+    let mut server = create_server();
+    let mut client = create_client(&mut server);
+    for app in [&mut server, &mut client] {
+        app.replicate::<Num>().add_systems(NetworkUpdate, count_up);
+    }
+    server.world.spawn((Replicate, Num(0)));
+
+    (server, client)
+}
+
+#[test]
+fn count_replication_traffic() {
+    let (mut server, mut client) = setup();
+    for _ in 0..5 {
+        tick(&mut server);
+        tick(&mut client);
+    }
+
+    let server_stats = server.world.resource::<NetworkStats>();
+    let client_stats = client.world.resource::<NetworkStats>();
+    let sent = server_stats.channel(Channel::Replication).sent;
+    assert!(sent > 0);
+    assert_eq!(client_stats.channel(Channel::Replication).received, sent);
+    assert_eq!(
+        client_stats.replication_packets,
+        server_stats.replication_packets
+    );
+    assert_eq!(server_stats.replication_packets.count, 5);
+}
+
+#[test]
+fn both_sides_see_the_connection() {
+    let (mut server, mut client) = setup();
+    let client_id = client
+        .world
+        .resource::<crate::transport::memory::MemoryClientTransport>()
+        .client_id()
+        .raw();
+    tick(&mut server);
+    tick(&mut client);
+
+    let owners = |app: &App| {
+        app.world
+            .resource::<NetworkStats>()
+            .connections
+            .iter()
+            .map(|(owner, _)| *owner)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(owners(&server), [Owner::Client(client_id)]);
+    assert_eq!(owners(&client), [Owner::Server]);
+
+    assert_eq!(server.world.resource::<NetworkStats>().ticks_ahead, None);
+    assert!(client
+        .world
+        .resource::<NetworkStats>()
+        .ticks_ahead
+        .is_some());
+}
+
+#[test]
+fn rates_are_per_second() {
+    let mut stats = NetworkStats::default();
+    stats.sent(Channel::Replication, 500);
+    stats.resimulated_ticks = 30;
+
+    stats.update_rates(Duration::from_millis(500));
+    assert_eq!(stats.channel(Channel::Replication).sent_per_sec, 0.0);
+
+    stats.update_rates(Duration::from_secs(2));
+    assert_eq!(stats.channel(Channel::Replication).sent_per_sec, 250.0);
+    assert_eq!(stats.resimulated_per_sec, 15.0);
+}
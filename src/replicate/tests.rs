@@ -2,6 +2,36 @@ use crate::test_utils::*;
 
 use super::*;
 
+/// Runs every test with each kind of compression
+macro_rules! with_each_compression {
+    ($($test:ident),* $(,)?) => {
+        mod uncompressed {
+            $(#[test]
+            fn $test() {
+                super::$test(super::Compression::None)
+            })*
+        }
+
+        mod lz4 {
+            $(#[test]
+            fn $test() {
+                super::$test(super::Compression::Lz4)
+            })*
+        }
+    };
+}
+
+with_each_compression!(
+    basic_repl,
+    multiple_repl,
+    modified_same_entity,
+    stress_100,
+    replicate_transform,
+    remove_component,
+    despawn_entity,
+    send_interval
+);
+
 #[derive(Debug, Serialize, Deserialize, Component)]
 struct Marker;
 
@@ -11,9 +41,14 @@ struct Marker2;
 #[derive(Debug, Serialize, Deserialize, Component, PartialEq, Eq, Clone, Copy)]
 struct Num(u32);
 
-#[test]
-fn basic_repl() {
-    let mut server = create_server();
+fn server(compression: Compression) -> App {
+    create_server_with(
+        ReplicationPlugin::new(0.01, TickStrategy::Manual).with_compression(compression),
+    )
+}
+
+fn basic_repl(compression: Compression) {
+    let mut server = server(compression);
     let mut client = create_client(&mut server);
     for app in [&mut server, &mut client] {
         app.replicate::<Marker>();
@@ -21,15 +56,14 @@ fn basic_repl() {
 
     server.world.spawn((Replicate, Marker));
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     assert_eq!(count::<&Marker>(&mut client), 1);
 }
 
-#[test]
-fn multiple_repl() {
-    let mut server = create_server();
+fn multiple_repl(compression: Compression) {
+    let mut server = server(compression);
     let mut client = create_client(&mut server);
     for app in [&mut server, &mut client] {
         app.replicate::<Marker>().replicate::<Marker2>();
@@ -41,7 +75,7 @@ fn multiple_repl() {
     server.world.spawn((Replicate, Marker2));
     server.world.spawn((Replicate, Marker, Marker2));
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     assert_eq!(count::<&Marker>(&mut client), 2);
@@ -49,9 +83,8 @@ fn multiple_repl() {
     assert_eq!(count::<(&Marker, &Marker2)>(&mut client), 1);
 }
 
-#[test]
-fn modified_same_entity() {
-    let mut server = create_server();
+fn modified_same_entity(compression: Compression) {
+    let mut server = server(compression);
     let mut client = create_client(&mut server);
     for app in [&mut server, &mut client] {
         app.replicate::<Num>();
@@ -60,7 +93,7 @@ fn modified_same_entity() {
     // Spawn one replicated component
     server.world.spawn((Replicate, Num(0)));
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     let (first_marked_entity, &marker_num) =
@@ -71,7 +104,7 @@ fn modified_same_entity() {
         num.0 = 1;
     }
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     let (second_marked_entity, &marker_num) =
@@ -81,21 +114,23 @@ fn modified_same_entity() {
     assert_eq!(first_marked_entity, second_marked_entity);
 }
 
-#[test]
-fn stress_100() {
+fn stress_100(compression: Compression) {
     for _ in 0..100 {
-        modified_same_entity();
+        modified_same_entity(compression);
     }
 }
 
-#[test]
-fn replicate_transform() {
-    let mut server = create_server();
+fn replicate_transform(compression: Compression) {
+    let mut server = server(compression);
     let mut client = create_client(&mut server);
     for app in [&mut server, &mut client] {
         app.replicate_with::<Transform>(
             |component| bincode::serialize(&component.translation).unwrap(),
-            |data| Transform::from_translation(bincode::deserialize::<Vec3>(data).unwrap()),
+            |data| {
+                let translation = bincode::deserialize::<Vec3>(data).map_err(|e| e.to_string())?;
+                Ok(Transform::from_translation(translation))
+            },
+            default(),
         );
     }
 
@@ -103,16 +138,15 @@ fn replicate_transform() {
         .world
         .spawn((Replicate, Transform::from_xyz(1.0, 2.0, 3.0)));
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     let tf = server.world.query::<&Transform>().single(&server.world);
     assert_eq!(Vec3::new(1.0, 2.0, 3.0), tf.translation);
 }
 
-#[test]
-fn remove_component() {
-    let mut server = create_server();
+fn remove_component(compression: Compression) {
+    let mut server = server(compression);
     let mut client = create_client(&mut server);
     for app in [&mut server, &mut client] {
         app.replicate::<Marker>();
@@ -120,29 +154,28 @@ fn remove_component() {
 
     let marker = server.world.spawn((Replicate, Marker)).id();
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     assert_eq!(count::<&Marker>(&mut client), 1);
 
     server.world.entity_mut(marker).remove::<Marker>();
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     assert_eq!(count::<&Marker>(&mut client), 0);
 
     server.world.entity_mut(marker).insert(Marker);
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     assert_eq!(count::<&Marker>(&mut client), 1);
 }
 
-#[test]
-fn despawn_entity() {
-    let mut server = create_server();
+fn despawn_entity(compression: Compression) {
+    let mut server = server(compression);
     let mut client = create_client(&mut server);
     for app in [&mut server, &mut client] {
         app.replicate::<Marker>();
@@ -150,15 +183,88 @@ fn despawn_entity() {
 
     let marker = server.world.spawn((Replicate, Marker)).id();
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     assert_eq!(count::<&Marker>(&mut client), 1);
 
     server.world.despawn(marker);
 
-    server.update();
+    tick(&mut server);
     client.update();
 
     assert_eq!(count::<&Marker>(&mut client), 0);
 }
+
+fn send_interval(compression: Compression) {
+    let mut server = create_server_with(
+        ReplicationPlugin::new(0.01, TickStrategy::Manual)
+            .with_send_interval(2)
+            .with_compression(compression),
+    );
+    let mut client = create_client(&mut server);
+    for app in [&mut server, &mut client] {
+        app.replicate::<Num>();
+    }
+
+    let changed = server.world.spawn((Replicate, Num(0))).id();
+    let despawned = server.world.spawn((Replicate, Num(1))).id();
+
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(count::<&Num>(&mut client), 0);
+
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(count::<&Num>(&mut client), 2);
+    assert_eq!(
+        client.world.resource::<SyncedServerTick>().tick,
+        NetworkTick(2)
+    );
+
+    tick(&mut server);
+    tick(&mut server);
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(
+        client.world.resource::<SyncedServerTick>().tick,
+        NetworkTick(4)
+    );
+
+    // Removals on a tick that is not sent go out with the next one
+    tick(&mut server);
+    server.world.entity_mut(changed).remove::<Num>();
+    server.world.despawn(despawned);
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(count::<&Num>(&mut client), 2);
+
+    // Frames without a tick clear the removal events
+    server.update();
+    server.update();
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(count::<&Num>(&mut client), 0);
+}
+
+#[test]
+fn corrupt_packets_are_dropped() {
+    let mut server = server(Compression::Lz4);
+    let mut client = create_client(&mut server);
+    for app in [&mut server, &mut client] {
+        app.replicate::<Marker>();
+    }
+    server.world.spawn((Replicate, Marker));
+
+    let mut renet = server.world.resource_mut::<RenetServer>();
+    renet.broadcast_message(Channel::Replication, vec![Compression::Lz4 as u8, 1, 2, 3]);
+    tick(&mut server);
+    client.update();
+
+    assert_eq!(count::<&Marker>(&mut client), 1);
+}
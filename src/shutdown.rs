@@ -0,0 +1,214 @@
+use std::time::Duration;
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy_renet::renet::{RenetClient, RenetServer};
+use bevy_renet::RenetReceive;
+use serde::{Deserialize, Serialize};
+
+use crate::replicate::stats::NetworkStats;
+use crate::replicate::{is_client, Channel};
+
+/// How long clients get to disconnect on their own before the server drops them
+pub const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(1);
+
+/// Send on the server to shut it down. Clients are told the reason and the app exits once they
+/// are gone.
+#[derive(Debug, Event, Clone, PartialEq, Eq)]
+pub struct Shutdown {
+    pub reason: String,
+}
+
+/// Inserted on the client when the server says it is shutting down
+#[derive(Debug, Resource, Clone, PartialEq, Eq)]
+pub struct ServerShutdown {
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShutdownNotice {
+    reason: String,
+}
+
+#[derive(Debug, Resource)]
+struct ShuttingDown {
+    since: Duration,
+    dropped_clients: bool,
+}
+
+pub struct ShutdownPlugin;
+
+impl Plugin for ShutdownPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<Shutdown>()
+            .add_systems(
+                PreUpdate,
+                receive_shutdown_notice
+                    .after(RenetReceive)
+                    .run_if(is_client),
+            )
+            .add_systems(
+                Update,
+                (
+                    start_shutdown,
+                    finish_shutdown.run_if(resource_exists::<ShuttingDown>()),
+                )
+                    .chain(),
+            );
+    }
+}
+
+fn start_shutdown(
+    mut commands: Commands,
+    mut shutdowns: EventReader<Shutdown>,
+    server: Option<ResMut<RenetServer>>,
+    shutting_down: Option<Res<ShuttingDown>>,
+    mut stats: ResMut<NetworkStats>,
+    time: Res<Time>,
+) {
+    let Some(Shutdown { reason }) = shutdowns.read().last() else {
+        return;
+    };
+    if shutting_down.is_some() {
+        return;
+    }
+
+    println!("Shutting down: {reason}");
+    if let Some(mut server) = server {
+        let notice = ShutdownNotice {
+            reason: reason.clone(),
+        };
+        let message = bincode::serialize(&notice).unwrap();
+        stats.sent(Channel::Shutdown, message.len() * server.clients_id().len());
+        server.broadcast_message(Channel::Shutdown, message);
+    }
+
+    commands.insert_resource(ShuttingDown {
+        since: time.elapsed(),
+        dropped_clients: false,
+    });
+}
+
+/// Waits for the clients to leave, drops the ones that did not, and exits the frame after so that
+/// the transport gets to tell them
+fn finish_shutdown(
+    mut shutting_down: ResMut<ShuttingDown>,
+    server: Option<ResMut<RenetServer>>,
+    time: Res<Time>,
+    mut app_exit: EventWriter<AppExit>,
+) {
+    if shutting_down.dropped_clients {
+        app_exit.send(AppExit);
+        return;
+    }
+
+    let Some(mut server) = server else {
+        shutting_down.dropped_clients = true;
+        return;
+    };
+
+    let timed_out = time.elapsed() - shutting_down.since >= SHUTDOWN_GRACE_PERIOD;
+    if server.connected_clients() == 0 || timed_out {
+        server.disconnect_all();
+        shutting_down.dropped_clients = true;
+    }
+}
+
+fn receive_shutdown_notice(
+    mut commands: Commands,
+    mut client: ResMut<RenetClient>,
+    mut stats: ResMut<NetworkStats>,
+) {
+    while let Some(message) = client.receive_message(Channel::Shutdown) {
+        stats.received(Channel::Shutdown, message.len());
+        let ShutdownNotice { reason } = match bincode::deserialize(&message) {
+            Ok(notice) => notice,
+            Err(err) => {
+                println!("Dropped an unreadable shutdown notice: {err}");
+                continue;
+            }
+        };
+        println!("The server is shutting down: {reason}");
+
+        commands.insert_resource(ServerShutdown { reason });
+        client.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Instant;
+
+    use crate::test_utils::{create_client, create_server};
+    use crate::transport::memory::MemoryClientTransport;
+    use crate::transport::{ConnectionState, DisconnectReason};
+
+    use super::*;
+
+    #[test]
+    fn clients_hear_the_reason() {
+        let mut server = create_server();
+        server.add_plugins(ShutdownPlugin);
+        let mut client = create_client(&mut server);
+        client.add_plugins(ShutdownPlugin);
+
+        server.world.send_event(Shutdown {
+            reason: "Maintenance".to_string(),
+        });
+
+        for _ in 0..10 {
+            server.update();
+            client.update();
+        }
+
+        assert_eq!(
+            client.world.get_resource::<ServerShutdown>(),
+            Some(&ServerShutdown {
+                reason: "Maintenance".to_string()
+            })
+        );
+        assert_eq!(
+            client.world.resource::<MemoryClientTransport>().state(),
+            ConnectionState::Disconnected(DisconnectReason::DisconnectedByClient)
+        );
+        assert!(!server.world.resource::<Events<AppExit>>().is_empty());
+    }
+
+    #[test]
+    fn stragglers_are_dropped() {
+        let mut server = create_server();
+        server.add_plugins(ShutdownPlugin);
+        // This client never reads the notice
+        let mut client = create_client(&mut server);
+
+        server.world.send_event(Shutdown {
+            reason: "Maintenance".to_string(),
+        });
+        server.update();
+        client.update();
+        assert_eq!(
+            server.world.resource::<RenetServer>().connected_clients(),
+            1
+        );
+
+        let start = Instant::now();
+        while client
+            .world
+            .resource::<MemoryClientTransport>()
+            .is_connected()
+        {
+            assert!(start.elapsed() < 3 * SHUTDOWN_GRACE_PERIOD, "Timed out");
+            server.update();
+            client.update();
+            thread::sleep(Duration::from_millis(10));
+        }
+        server.update();
+
+        assert_eq!(
+            client.world.resource::<MemoryClientTransport>().state(),
+            ConnectionState::Disconnected(DisconnectReason::DisconnectedByServer)
+        );
+        assert!(!server.world.resource::<Events<AppExit>>().is_empty());
+    }
+}
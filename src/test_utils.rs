@@ -3,20 +3,24 @@ use bevy_renet::renet::{RenetClient, RenetServer};
 
 use crate::replicate::schedule::{DoTick, TickStrategy};
 use crate::replicate::{replication_connection_config, ReplicationPlugin};
-use crate::transport::{MemoryClientPlugin, MemoryServerPlugin, MemoryServerTransport};
+use crate::transport::memory::{
+    MemoryClientPlugin, MemoryClientTransport, MemoryServerPlugin, MemoryServerTransport,
+};
+
+pub mod session;
 
 pub fn create_server() -> App {
+    create_server_with(ReplicationPlugin::new(0.01, TickStrategy::Manual))
+}
+
+pub fn create_server_with(replication: ReplicationPlugin) -> App {
     let mut server = App::new();
 
     let server_transport = MemoryServerTransport::default();
     let renet_server = RenetServer::new(replication_connection_config());
 
     server
-        .add_plugins((
-            MinimalPlugins,
-            MemoryServerPlugin,
-            ReplicationPlugin::new(0.01, TickStrategy::Manual),
-        ))
+        .add_plugins((MinimalPlugins, MemoryServerPlugin, replication))
         .insert_resource(renet_server)
         .insert_resource(server_transport);
 
@@ -24,6 +28,10 @@ pub fn create_server() -> App {
 }
 
 pub fn create_client(server: &mut App) -> App {
+    create_client_with(server, ReplicationPlugin::new(0.01, TickStrategy::Manual))
+}
+
+pub fn create_client_with(server: &mut App, replication: ReplicationPlugin) -> App {
     let mut client = App::new();
 
     let client_transport = server
@@ -33,17 +41,32 @@ pub fn create_client(server: &mut App) -> App {
     let renet_client = RenetClient::new(replication_connection_config());
 
     client
-        .add_plugins((
-            MinimalPlugins,
-            MemoryClientPlugin,
-            ReplicationPlugin::new(0.01, TickStrategy::Manual),
-        ))
+        .add_plugins((MinimalPlugins, MemoryClientPlugin, replication))
         .insert_resource(renet_client)
         .insert_resource(client_transport);
 
+    connect(server, &mut client);
+
     client
 }
 
+/// Updates both apps until the client has finished connecting
+pub fn connect(server: &mut App, client: &mut App) {
+    for _ in 0..100 {
+        client.update();
+        if client
+            .world
+            .resource::<MemoryClientTransport>()
+            .is_connected()
+        {
+            return;
+        }
+        server.update();
+    }
+
+    panic!("The client never connected");
+}
+
 pub fn tick(app: &mut App) {
     app.insert_resource(DoTick);
     app.update();
@@ -0,0 +1,461 @@
+//! Plays a scripted session and records the client's input and checksums of the replicated state
+//! after every tick, then replays that input against the current game logic to find where it
+//! changed.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::env;
+use std::fmt::{self, Display, Write};
+use std::fs;
+use std::path::PathBuf;
+
+use bevy::input::InputPlugin;
+use bevy::prelude::*;
+use bevy_renet::renet::{RenetClient, RenetServer};
+use bevy_xpbd_2d::plugins::setup::Physics;
+use leafwing_input_manager::axislike::DualAxisData;
+use leafwing_input_manager::prelude::*;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+
+use crate::game::{AvatarRng, GamePlugin, DEFAULT_TICK_RATE};
+use crate::player::{Action, Control};
+use crate::prediction::{resimulating, ActionHistory, CommitActions};
+use crate::replicate::checksum::checksums;
+use crate::replicate::schedule::{NetworkPreUpdate, TickStrategy};
+use crate::replicate::{
+    replication_connection_config, NetworkEntities, NetworkTick, Owner, Replicate,
+};
+use crate::transport::memory::{MemoryClientPlugin, MemoryServerPlugin, MemoryServerTransport};
+
+use super::{connect, tick};
+
+/// Set to record the sessions again instead of checking them
+pub const UPDATE_SESSIONS_VAR: &str = "UPDATE_SESSIONS";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Session {
+    pub ticks: Vec<SessionTick>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionTick {
+    /// What the client committed as its player's input for this tick
+    pub actions: ActionState<Action>,
+    /// Checksums of the replicated components by their names, which unlike replication ids do not
+    /// change when another component is registered
+    pub server: BTreeMap<String, u64>,
+    pub client: BTreeMap<String, u64>,
+}
+
+/// Where a replayed session first stopped matching its recording
+#[derive(Debug, PartialEq, Eq)]
+pub struct Divergence {
+    pub tick: usize,
+    pub peer: &'static str,
+    pub component: String,
+}
+
+impl Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Tick {}: {} diverged on the {}",
+            self.tick, self.component, self.peer
+        )
+    }
+}
+
+#[derive(Resource, Default)]
+struct ScriptedActions(ActionState<Action>);
+
+/// A server and a client running the whole game without visuals, stepped one tick at a time
+struct Peers {
+    server: App,
+    client: App,
+}
+
+impl Peers {
+    fn new() -> Self {
+        let mut server = game_app();
+        let mut server_transport = MemoryServerTransport::default();
+        let client_transport = server_transport.create_client();
+        server
+            .add_plugins(MemoryServerPlugin)
+            .insert_resource(RenetServer::new(replication_connection_config()))
+            .insert_resource(server_transport);
+
+        let mut client = game_app();
+        client
+            .add_plugins(MemoryClientPlugin)
+            .insert_resource(Owner::Client(client_transport.client_id().raw()))
+            .insert_resource(RenetClient::new(replication_connection_config()))
+            .insert_resource(client_transport)
+            .init_resource::<ScriptedActions>()
+            .add_systems(
+                NetworkPreUpdate,
+                play_scripted_actions
+                    .run_if(not(resimulating))
+                    .before(CommitActions),
+            );
+
+        connect(&mut server, &mut client);
+        Peers { server, client }
+    }
+
+    fn play(
+        mut self,
+        ticks: usize,
+        mut script: impl FnMut(usize) -> ActionState<Action>,
+    ) -> Session {
+        Session {
+            ticks: (0..ticks).map(|tick| self.step(script(tick))).collect(),
+        }
+    }
+
+    fn step(&mut self, actions: ActionState<Action>) -> SessionTick {
+        self.client
+            .insert_resource(ScriptedActions(actions.clone()));
+        tick(&mut self.client);
+        tick(&mut self.server);
+
+        // What the script did only counts once the client controls a player
+        let client = &mut self.client.world;
+        let tick = *client.resource::<NetworkTick>();
+        let actions = client
+            .query_filtered::<&ActionHistory<Action>, With<Control>>()
+            .iter(client)
+            .find(|history| history.tick == tick)
+            .and_then(|history| history.history.front().cloned())
+            .unwrap_or_default();
+
+        let server = &mut self.server.world;
+        let entities = server
+            .query_filtered::<Entity, With<Replicate>>()
+            .iter(server)
+            .collect::<Vec<_>>();
+        let server = checksums(server, &entities);
+
+        // What the client got from the server and what it predicted itself
+        let client = &mut self.client.world;
+        let mut entities = client
+            .query_filtered::<Entity, With<Replicate>>()
+            .iter(client)
+            .collect::<Vec<_>>();
+        // Entities the client predicted a despawn for are still mapped
+        entities.extend(
+            client
+                .resource::<NetworkEntities>()
+                .values()
+                .filter(|&&entity| client.get_entity(entity).is_some()),
+        );
+        let client = checksums(client, &entities);
+
+        SessionTick {
+            actions,
+            server,
+            client,
+        }
+    }
+}
+
+fn game_app() -> App {
+    let mut app = App::new();
+    app.add_plugins((
+        MinimalPlugins,
+        TransformPlugin,
+        HierarchyPlugin,
+        InputPlugin,
+        GamePlugin::default().with_visuals(false),
+    ))
+    .insert_resource(TickStrategy::Manual)
+    // The same avatar in every run
+    .insert_resource(AvatarRng(StdRng::seed_from_u64(0)))
+    // One physics step per update, however long it took
+    .insert_resource(Time::new_with(Physics::fixed_once_hz(
+        DEFAULT_TICK_RATE as f64,
+    )));
+    app
+}
+
+/// Overwrites what the keyboard and mouse did, like a bot
+fn play_scripted_actions(
+    mut commands: Commands,
+    scripted: Res<ScriptedActions>,
+    mut players: Query<(Entity, &mut ActionState<Action>, Has<InputMap<Action>>), With<Control>>,
+) {
+    for (entity, mut actions, has_input_map) in &mut players {
+        if has_input_map {
+            commands.entity(entity).remove::<InputMap<Action>>();
+        }
+        *actions = scripted.0.clone();
+    }
+}
+
+/// Plays `ticks` ticks with the actions from `script`, which gets the number of the tick
+pub fn record(ticks: usize, script: impl FnMut(usize) -> ActionState<Action>) -> Session {
+    Peers::new().play(ticks, script)
+}
+
+pub fn replay(session: &Session) -> Result<(), Divergence> {
+    let mut peers = Peers::new();
+
+    for (tick, recorded) in session.ticks.iter().enumerate() {
+        let replayed = peers.step(recorded.actions.clone());
+
+        for (peer, recorded, replayed) in [
+            ("server", &recorded.server, &replayed.server),
+            ("client", &recorded.client, &replayed.client),
+        ] {
+            if let Some(&(component, _)) = changes(recorded, replayed).first() {
+                return Err(Divergence {
+                    tick,
+                    peer,
+                    component: component.to_string(),
+                });
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The checksums in `to` that differ from those in `from`, by component. Components missing from
+/// either count as 0.
+fn changes<'a>(
+    from: &'a BTreeMap<String, u64>,
+    to: &'a BTreeMap<String, u64>,
+) -> Vec<(&'a str, u64)> {
+    let components = from.keys().chain(to.keys()).collect::<BTreeSet<_>>();
+    components
+        .into_iter()
+        .map(|component| (component.as_str(), to.get(component).copied().unwrap_or(0)))
+        .filter(|&(component, checksum)| from.get(component).copied().unwrap_or(0) != checksum)
+        .collect()
+}
+
+/// An action state as words, `+Name` for a pressed action followed by `@x,y` for its axis pair and
+/// `=value` for its value. Actions with none of these are left out.
+fn actions_to_text(actions: &ActionState<Action>) -> String {
+    let mut words = Vec::new();
+    for action in Action::variants() {
+        let data = actions.action_data(action.clone());
+        if !data.state.pressed() && data.axis_pair.is_none() && data.value == 0.0 {
+            continue;
+        }
+
+        let mut word = String::new();
+        if data.state.pressed() {
+            word.push('+');
+        }
+        write!(word, "{action:?}").unwrap();
+        if let Some(axis_pair) = data.axis_pair {
+            write!(word, "@{},{}", axis_pair.x(), axis_pair.y()).unwrap();
+        }
+        if data.value != 0.0 {
+            write!(word, "={}", data.value).unwrap();
+        }
+        words.push(word);
+    }
+    words.join(" ")
+}
+
+fn actions_from_text<'a>(words: impl Iterator<Item = &'a str>) -> Option<ActionState<Action>> {
+    let mut actions = ActionState::default();
+    for word in words {
+        let (pressed, word) = match word.strip_prefix('+') {
+            Some(word) => (true, word),
+            None => (false, word),
+        };
+        let (word, value) = match word.split_once('=') {
+            Some((word, value)) => (word, value.parse().ok()?),
+            None => (word, 0.0),
+        };
+        let (name, axis_pair) = match word.split_once('@') {
+            Some((name, axis_pair)) => {
+                let (x, y) = axis_pair.split_once(',')?;
+                (
+                    name,
+                    Some(DualAxisData::new(x.parse().ok()?, y.parse().ok()?)),
+                )
+            }
+            None => (word, None),
+        };
+
+        let action = Action::variants().find(|action| format!("{action:?}") == name)?;
+        if pressed {
+            actions.press(action.clone());
+        }
+        let data = actions.action_data_mut(action);
+        data.axis_pair = axis_pair;
+        data.value = value;
+    }
+    Some(actions)
+}
+
+/// A session as text. The first line has the number of ticks, the others what changed on a tick:
+/// the client's input, or the checksums of a peer. Recording it again then shows the first tick
+/// that played differently.
+fn to_text(session: &Session) -> String {
+    let mut text = format!("# Recorded with {UPDATE_SESSIONS_VAR}=1 cargo test\n");
+    writeln!(text, "ticks {}", session.ticks.len()).unwrap();
+
+    let empty = BTreeMap::new();
+    let (mut server, mut client) = (&empty, &empty);
+    let mut actions = String::new();
+    for (tick, recorded) in session.ticks.iter().enumerate() {
+        let tick_actions = actions_to_text(&recorded.actions);
+        if tick_actions != actions {
+            writeln!(text, "{tick} input {tick_actions}").unwrap();
+            actions = tick_actions;
+        }
+        for (peer, last, checksums) in [
+            ("server", &mut server, &recorded.server),
+            ("client", &mut client, &recorded.client),
+        ] {
+            let changes = changes(last, checksums);
+            if !changes.is_empty() {
+                write!(text, "{tick} {peer}").unwrap();
+                for (component, checksum) in changes {
+                    write!(text, " {component}={checksum:016x}").unwrap();
+                }
+                text.push('\n');
+            }
+            *last = checksums;
+        }
+    }
+    text
+}
+
+/// Reads a session back from [`to_text`]
+fn from_text(text: &str) -> Result<Session, String> {
+    let mut lines = text.lines().filter(|line| !line.starts_with('#'));
+    let ticks = lines
+        .next()
+        .and_then(|line| line.strip_prefix("ticks "))
+        .and_then(|ticks| ticks.parse::<usize>().ok())
+        .ok_or("Expected the number of ticks first")?;
+    let mut lines = lines.peekable();
+
+    let mut actions = ActionState::default();
+    let (mut server, mut client) = (BTreeMap::new(), BTreeMap::new());
+    let mut session = Session { ticks: Vec::new() };
+    for tick in 0..ticks {
+        while let Some(line) =
+            lines.next_if(|line| line.split(' ').next() == Some(tick.to_string().as_str()))
+        {
+            let mut words = line.split(' ').skip(1);
+            let unreadable = || format!("Could not read the line {line:?}");
+            let checksums = match words.next() {
+                Some("input") => {
+                    actions = actions_from_text(words).ok_or_else(unreadable)?;
+                    continue;
+                }
+                Some("server") => &mut server,
+                Some("client") => &mut client,
+                _ => return Err(unreadable()),
+            };
+            for change in words {
+                let (component, checksum) = change
+                    .rsplit_once('=')
+                    .and_then(|(component, checksum)| {
+                        Some((component, u64::from_str_radix(checksum, 16).ok()?))
+                    })
+                    .ok_or_else(unreadable)?;
+                checksums.insert(component.to_string(), checksum);
+            }
+        }
+        session.ticks.push(SessionTick {
+            actions: actions.clone(),
+            server: server.clone(),
+            client: client.clone(),
+        });
+    }
+
+    match lines.next() {
+        Some(line) => Err(format!("Not a line of the {ticks} ticks: {line:?}")),
+        None => Ok(session),
+    }
+}
+
+/// Replays the session stored under `name`, or records it again with `script` for `ticks` ticks if
+/// [`UPDATE_SESSIONS_VAR`] is set
+pub fn check(name: &str, ticks: usize, script: impl FnMut(usize) -> ActionState<Action>) {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("src/test_utils/sessions")
+        .join(format!("{name}.session"));
+
+    if env::var_os(UPDATE_SESSIONS_VAR).is_some() {
+        let session = record(ticks, script);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, to_text(&session)).unwrap();
+        return;
+    }
+
+    let text = fs::read_to_string(&path).unwrap_or_else(|err| {
+        panic!(
+            "Could not read {}: {err}. Record it with {UPDATE_SESSIONS_VAR}=1 cargo test",
+            path.display()
+        )
+    });
+    let session =
+        from_text(&text).unwrap_or_else(|err| panic!("{} is not a session: {err}", path.display()));
+    if let Err(divergence) = replay(&session) {
+        panic!(
+            "The {name} session played differently. {divergence}. If that is intended, record it \
+             again with {UPDATE_SESSIONS_VAR}=1 cargo test"
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn walk_right(tick: usize) -> ActionState<Action> {
+        let mut actions = ActionState::default();
+        actions.press(Action::Right);
+        if tick == 5 {
+            actions.press(Action::Shoot);
+        }
+        actions
+    }
+
+    #[test]
+    fn recordings_replay() {
+        let session = record(30, walk_right);
+        let text = to_text(&session);
+        let read = from_text(&text).unwrap();
+        assert_eq!(read.ticks.len(), 30);
+        for (recorded, read) in session.ticks.iter().zip(&read.ticks) {
+            assert_eq!(
+                actions_to_text(&recorded.actions),
+                actions_to_text(&read.actions)
+            );
+        }
+        assert_eq!(replay(&read), Ok(()));
+    }
+
+    #[test]
+    fn only_committed_input_is_recorded() {
+        let session = record(30, walk_right);
+
+        // Nothing is committed before the client controls its player
+        assert_eq!(actions_to_text(&session.ticks[0].actions), "");
+        assert_eq!(actions_to_text(&session.ticks[29].actions), "+Right");
+    }
+
+    #[test]
+    fn find_the_first_divergence() {
+        let mut session = record(30, walk_right);
+        // As if the player used to walk left
+        for tick in &mut session.ticks[10..] {
+            tick.actions = ActionState::default();
+            tick.actions.press(Action::Left);
+        }
+
+        let divergence = replay(&session).unwrap_err();
+        assert_eq!(divergence.tick, 10);
+        assert_eq!(divergence.peer, "server");
+        assert!(divergence.component.ends_with("Transform"), "{divergence}");
+    }
+}
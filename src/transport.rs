@@ -1,283 +1,224 @@
-use std::mem;
-use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::marker::PhantomData;
 
-use bevy::app::AppExit;
 use bevy::prelude::*;
-use bevy::utils::synccell::SyncCell;
-use bevy::utils::HashMap;
-use bevy_renet::renet::{ClientId, RenetClient, RenetServer};
-use bevy_renet::{RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin};
-
-struct Connection {
-    sender: Sender<Vec<u8>>,
-    receiver: Receiver<Vec<u8>>,
-}
-
-impl Connection {
-    fn new(sender: Sender<Vec<u8>>, receiver: Receiver<Vec<u8>>) -> Self {
-        Self { sender, receiver }
+use bevy_renet::renet::transport::NETCODE_USER_DATA_BYTES;
+use bevy_renet::renet::{self, ClientId, RenetClient, ServerEvent};
+use bevy_renet::{RenetReceive, RenetSend};
+use serde::{Deserialize, Serialize};
+
+pub mod conditioner;
+pub mod memory;
+pub mod netcode;
+pub mod websocket;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DisconnectReason {
+    DisconnectedByClient,
+    DisconnectedByServer,
+    /// The peer did not send anything for longer than the timeout
+    TimedOut,
+    /// The peer went away without saying goodbye
+    ConnectionLost,
+    /// The server refused to let the client in
+    ConnectionDenied,
+    /// The same client connected again over a new connection
+    Reconnected,
+    /// Renet closed the connection because the peer broke the protocol
+    ProtocolError,
+}
+
+impl From<renet::DisconnectReason> for DisconnectReason {
+    fn from(reason: renet::DisconnectReason) -> Self {
+        match reason {
+            renet::DisconnectReason::DisconnectedByClient => DisconnectReason::DisconnectedByClient,
+            renet::DisconnectReason::DisconnectedByServer => DisconnectReason::DisconnectedByServer,
+            renet::DisconnectReason::Transport => DisconnectReason::ConnectionLost,
+            _ => DisconnectReason::ProtocolError,
+        }
     }
 }
 
-#[derive(Default, Resource)]
-pub struct MemoryServerTransport {
-    connections: HashMap<ClientId, SyncCell<Connection>>,
-    num_connected: u64,
-    new_connections: Vec<ClientId>,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionState {
+    Connecting,
+    Connected,
+    Disconnected(DisconnectReason),
 }
 
-impl MemoryServerTransport {
-    pub fn create_client(&mut self) -> MemoryClientTransport {
-        let (send_to_client, receive_from_server) = mpsc::channel::<Vec<u8>>();
-        let (send_to_server, receive_from_client) = mpsc::channel::<Vec<u8>>();
-
-        let client_id = ClientId::from_raw(self.num_connected);
-        self.num_connected += 1;
-
-        self.connections.insert(
-            client_id,
-            SyncCell::new(Connection::new(send_to_client, receive_from_client)),
-        );
-        self.new_connections.push(client_id);
-
-        MemoryClientTransport::new(receive_from_server, send_to_server)
-    }
-
-    fn update(&mut self, server: &mut RenetServer) {
-        for new_client_id in mem::take(&mut self.new_connections) {
-            server.add_connection(new_client_id);
-        }
-
-        let mut to_disconnect = vec![];
-
-        for (&client_id, connection) in self.connections.iter_mut() {
-            loop {
-                match connection.get().receiver.try_recv() {
-                    Ok(packet) => {
-                        server.process_packet_from(&packet, client_id).unwrap();
-                        continue;
-                    }
-                    Err(TryRecvError::Empty) => (),
-                    Err(TryRecvError::Disconnected) => {
-                        to_disconnect.push(client_id);
-                    }
-                }
-                break;
-            }
-        }
-
-        for client_id in to_disconnect {
-            self.disconnect_client(client_id, server);
-        }
-    }
-
-    fn send_packets(&mut self, server: &mut RenetServer) {
-        for client_id in server.clients_id() {
-            let connection = self.connections.get_mut(&client_id).unwrap();
-
-            let packets = server.get_packets_to_send(client_id).unwrap();
-
-            for packet in packets {
-                if connection.get().sender.send(packet).is_err() {
-                    self.disconnect_client(client_id, server);
-                    break;
-                }
-            }
-        }
-    }
+/// Implemented by the client half of every transport
+pub trait ClientTransport: Resource {
+    fn connection_state(&self, client: &RenetClient) -> ConnectionState;
+}
 
-    fn disconnect_client(&mut self, client_id: ClientId, server: &mut RenetServer) {
-        self.connections.remove(&client_id);
-        server.disconnect(client_id);
-    }
+/// Implemented by the server half of every transport
+pub trait ServerTransport: Resource {
+    /// Why the transport dropped the client, if it knows better than renet
+    fn disconnect_reason(&self, client_id: ClientId) -> Option<DisconnectReason>;
 
-    fn disconnect_all(&mut self, server: &mut RenetServer) {
-        mem::take(&mut self.connections);
-        server.disconnect_all();
+    /// Data the client was allowed to connect with, such as the user data of its connect token
+    fn user_data(&self, _client_id: ClientId) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
+        None
     }
 }
 
-#[derive(Resource)]
-pub struct MemoryClientTransport {
-    connection: Option<SyncCell<Connection>>,
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ConnectionStats {
+    /// Round trip time in seconds
+    pub rtt: f64,
+    pub packet_loss: f64,
+    pub bytes_sent_per_sec: f64,
+    pub bytes_received_per_sec: f64,
 }
 
-impl MemoryClientTransport {
-    fn new(receiver: Receiver<Vec<u8>>, sender: Sender<Vec<u8>>) -> Self {
-        Self {
-            connection: Some(SyncCell::new(Connection::new(sender, receiver))),
-        }
-    }
-
-    fn update(&mut self, client: &mut RenetClient) {
-        let Some(ref mut connection) = self.connection else {
-            return;
-        };
-
-        loop {
-            match connection.get().receiver.try_recv() {
-                Ok(packet) => {
-                    client.process_packet(&packet);
-                    continue;
-                }
-                Err(TryRecvError::Empty) => (),
-                Err(TryRecvError::Disconnected) => self.disconnect(client),
-            }
-            break;
-        }
-    }
+/// The connection of the client to the server, whichever transport is used.
+///
+/// Only exists while there is a client transport.
+#[derive(Debug, Resource, Clone, Copy, PartialEq)]
+pub struct ClientConnection {
+    pub state: ConnectionState,
+    pub stats: ConnectionStats,
+}
 
-    fn send_packets(&mut self, client: &mut RenetClient) {
-        let packets = client.get_packets_to_send();
+/// Like renet's [`ServerEvent`], but with a reason that the transport can fill in
+#[derive(Debug, Event, Clone, PartialEq, Eq)]
+pub enum ConnectionEvent {
+    ClientConnected {
+        client_id: ClientId,
+        user_data: Option<Box<[u8; NETCODE_USER_DATA_BYTES]>>,
+    },
+    ClientDisconnected {
+        client_id: ClientId,
+        reason: DisconnectReason,
+    },
+}
 
-        for packet in packets {
-            let Some(ref mut connection) = self.connection else {
-                continue;
-            };
+/// Keeps [`ClientConnection`] up to date from the transport `T`. Every transport plugin adds this
+/// for its client half.
+pub struct ClientTransportPlugin<T>(PhantomData<T>);
 
-            if connection.get().sender.send(packet).is_err() {
-                self.disconnect(client);
-                break;
-            }
-        }
-    }
+/// Sends [`ConnectionEvent`]s for clients of the transport `T`. Every transport plugin adds this
+/// for its server half.
+pub struct ServerTransportPlugin<T>(PhantomData<T>);
 
-    fn disconnect(&mut self, client: &mut RenetClient) {
-        client.disconnect();
-        self.connection.take();
+impl<T> Default for ClientTransportPlugin<T> {
+    fn default() -> Self {
+        ClientTransportPlugin(PhantomData)
     }
+}
 
-    pub fn is_connected(&self) -> bool {
-        self.connection.is_some()
+impl<T> Default for ServerTransportPlugin<T> {
+    fn default() -> Self {
+        ServerTransportPlugin(PhantomData)
     }
 }
 
-// Plugins
-
-pub struct MemoryServerPlugin;
-
-pub struct MemoryClientPlugin;
-
-impl Plugin for MemoryServerPlugin {
+impl<T: ClientTransport> Plugin for ClientTransportPlugin<T> {
     fn build(&self, app: &mut App) {
+        // Sending can also notice that the connection is gone
         app.add_systems(
             PreUpdate,
-            Self::update_system
-                .run_if(resource_exists::<RenetServer>())
-                .run_if(resource_exists::<MemoryServerTransport>())
-                .in_set(RenetReceive)
-                .after(RenetServerPlugin::update_system),
+            update_client_connection::<T>
+                .after(RenetReceive)
+                .run_if(resource_exists::<T>())
+                .run_if(resource_exists::<RenetClient>()),
         )
         .add_systems(
             PostUpdate,
-            (
-                Self::send_packets.in_set(RenetSend),
-                Self::disconnect_on_exit,
-            )
-                .run_if(resource_exists::<RenetServer>())
-                .run_if(resource_exists::<MemoryServerTransport>()),
+            update_client_connection::<T>
+                .after(RenetSend)
+                .run_if(resource_exists::<T>())
+                .run_if(resource_exists::<RenetClient>()),
         );
     }
 }
 
-impl MemoryServerPlugin {
-    pub fn update_system(
-        mut transport: ResMut<MemoryServerTransport>,
-        mut server: ResMut<RenetServer>,
-    ) {
-        transport.update(&mut server);
-    }
-
-    pub fn send_packets(
-        mut transport: ResMut<MemoryServerTransport>,
-        mut server: ResMut<RenetServer>,
-    ) {
-        transport.send_packets(&mut server);
-    }
-
-    fn disconnect_on_exit(
-        exit: EventReader<AppExit>,
-        mut transport: ResMut<MemoryServerTransport>,
-        mut server: ResMut<RenetServer>,
-    ) {
-        if !exit.is_empty() {
-            transport.disconnect_all(&mut server);
+impl<T: ServerTransport> Plugin for ServerTransportPlugin<T> {
+    fn build(&self, app: &mut App) {
+        if !app.world.contains_resource::<Events<ConnectionEvent>>() {
+            app.add_event::<ConnectionEvent>();
         }
-    }
-}
 
-impl Plugin for MemoryClientPlugin {
-    fn build(&self, app: &mut App) {
         app.add_systems(
             PreUpdate,
-            Self::update_system
-                .run_if(resource_exists::<RenetClient>())
-                .run_if(resource_exists::<MemoryClientTransport>())
-                .in_set(RenetReceive)
-                .after(RenetClientPlugin::update_system),
-        )
-        .add_systems(
-            PostUpdate,
-            (
-                Self::send_packets.in_set(RenetSend),
-                Self::disconnect_on_exit,
-            )
-                .run_if(resource_exists::<RenetClient>())
-                .run_if(resource_exists::<MemoryClientTransport>()),
+            send_connection_events::<T>
+                .after(RenetReceive)
+                .run_if(resource_exists::<T>()),
         );
     }
 }
 
-impl MemoryClientPlugin {
-    pub fn update_system(
-        mut transport: ResMut<MemoryClientTransport>,
-        mut client: ResMut<RenetClient>,
-    ) {
-        transport.update(&mut client);
-    }
-
-    pub fn send_packets(
-        mut transport: ResMut<MemoryClientTransport>,
-        mut client: ResMut<RenetClient>,
-    ) {
-        transport.send_packets(&mut client);
-    }
-
-    fn disconnect_on_exit(
-        mut transport: ResMut<MemoryClientTransport>,
-        mut client: ResMut<RenetClient>,
-        exit: EventReader<AppExit>,
-    ) {
-        if !exit.is_empty() {
-            transport.disconnect(&mut client);
+fn update_client_connection<T: ClientTransport>(
+    mut commands: Commands,
+    transport: Res<T>,
+    client: Res<RenetClient>,
+    connection: Option<ResMut<ClientConnection>>,
+) {
+    let info = client.network_info();
+    let new_connection = ClientConnection {
+        state: transport.connection_state(&client),
+        stats: ConnectionStats {
+            rtt: info.rtt,
+            packet_loss: info.packet_loss,
+            bytes_sent_per_sec: info.bytes_sent_per_second,
+            bytes_received_per_sec: info.bytes_received_per_second,
+        },
+    };
+
+    match connection {
+        Some(mut connection) => {
+            connection.set_if_neq(new_connection);
         }
+        None => commands.insert_resource(new_connection),
+    }
+}
+
+fn send_connection_events<T: ServerTransport>(
+    transport: Res<T>,
+    mut server_events: EventReader<ServerEvent>,
+    mut connection_events: EventWriter<ConnectionEvent>,
+) {
+    for event in server_events.read() {
+        connection_events.send(match *event {
+            ServerEvent::ClientConnected { client_id } => ConnectionEvent::ClientConnected {
+                client_id,
+                user_data: transport.user_data(client_id).map(Box::new),
+            },
+            ServerEvent::ClientDisconnected { client_id, reason } => {
+                ConnectionEvent::ClientDisconnected {
+                    client_id,
+                    reason: transport
+                        .disconnect_reason(client_id)
+                        .unwrap_or(reason.into()),
+                }
+            }
+        });
     }
 }
 
-pub fn client_connected() -> impl FnMut(Option<Res<MemoryClientTransport>>) -> bool {
-    |transport| match transport {
-        Some(transport) => transport.is_connected(),
-        None => false,
-    }
+fn connection_state(connection: Option<Res<ClientConnection>>) -> Option<ConnectionState> {
+    connection.map(|connection| connection.state)
+}
+
+pub fn client_connected() -> impl FnMut(Option<Res<ClientConnection>>) -> bool {
+    |connection| connection_state(connection) == Some(ConnectionState::Connected)
 }
 
-pub fn client_disconnected() -> impl FnMut(Option<Res<MemoryClientTransport>>) -> bool {
-    |transport| match transport {
-        Some(transport) => !transport.is_connected(),
-        None => true,
+pub fn client_disconnected() -> impl FnMut(Option<Res<ClientConnection>>) -> bool {
+    |connection| {
+        matches!(
+            connection_state(connection),
+            None | Some(ConnectionState::Disconnected(_))
+        )
     }
 }
 
-pub fn client_connecting() -> impl FnMut(Option<Res<MemoryClientTransport>>) -> bool {
-    |_transport| false
+pub fn client_connecting() -> impl FnMut(Option<Res<ClientConnection>>) -> bool {
+    |connection| connection_state(connection) == Some(ConnectionState::Connecting)
 }
 
-pub fn client_just_connected() -> impl FnMut(Local<bool>, Option<Res<MemoryClientTransport>>) -> bool
-{
-    |mut last_connected: Local<bool>, transport| {
-        let connected = transport
-            .map(|transport| transport.is_connected())
-            .unwrap_or(false);
+pub fn client_just_connected() -> impl FnMut(Local<bool>, Option<Res<ClientConnection>>) -> bool {
+    |mut last_connected: Local<bool>, connection| {
+        let connected = connection_state(connection) == Some(ConnectionState::Connected);
 
         let just_connected = !*last_connected && connected;
         *last_connected = connected;
@@ -285,279 +226,16 @@ pub fn client_just_connected() -> impl FnMut(Local<bool>, Option<Res<MemoryClien
     }
 }
 
-pub fn client_just_disconnected(
-) -> impl FnMut(Local<bool>, Option<Res<MemoryClientTransport>>) -> bool {
-    |mut last_connected: Local<bool>, transport| {
-        let disconnected = transport
-            .map(|transport| transport.is_connected())
-            .unwrap_or(true);
+pub fn client_just_disconnected() -> impl FnMut(Local<bool>, Option<Res<ClientConnection>>) -> bool
+{
+    |mut last_connected: Local<bool>, connection| {
+        let disconnected = matches!(
+            connection_state(connection),
+            None | Some(ConnectionState::Disconnected(_))
+        );
 
         let just_disconnected = *last_connected && disconnected;
         *last_connected = !disconnected;
         just_disconnected
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use bevy_renet::renet::{ConnectionConfig, DefaultChannel};
-
-    use super::*;
-
-    #[derive(Debug, Default, Resource, PartialEq, Eq, Deref, DerefMut)]
-    pub struct ServerReceived(Vec<(u64, Vec<u8>)>);
-
-    #[derive(Debug, Default, Resource, PartialEq, Eq, Deref, DerefMut)]
-    pub struct ClientReceived(Vec<Vec<u8>>);
-
-    fn client_received(client: &App) -> Vec<Vec<u8>> {
-        client.world.resource::<ClientReceived>().0.clone()
-    }
-
-    fn server_received(server: &App) -> Vec<(u64, Vec<u8>)> {
-        let mut received = server.world.resource::<ServerReceived>().0.clone();
-        received.sort_by_key(|&(client_id, _)| client_id);
-        received
-    }
-
-    fn create_server_app() -> App {
-        let server_transport = MemoryServerTransport::default();
-        let renet_server = RenetServer::new(ConnectionConfig::default());
-
-        let mut server = App::new();
-        server
-            .add_plugins((MinimalPlugins, RenetServerPlugin, MemoryServerPlugin))
-            .insert_resource(renet_server)
-            .insert_resource(server_transport)
-            .init_resource::<ServerReceived>()
-            .add_systems(
-                Update,
-                |mut server: ResMut<RenetServer>, mut received: ResMut<ServerReceived>| {
-                    for client_id in server.clients_id() {
-                        while let Some(packet) =
-                            server.receive_message(client_id, DefaultChannel::ReliableOrdered)
-                        {
-                            received.push((client_id.raw(), packet.to_vec()));
-                        }
-                    }
-                },
-            );
-
-        server
-    }
-
-    fn create_client_app(server: &mut App) -> App {
-        let mut server_transport = server.world.resource_mut::<MemoryServerTransport>();
-        let client_transport = server_transport.create_client();
-        let renet_client = RenetClient::new(ConnectionConfig::default());
-
-        let mut client = App::new();
-        client
-            .add_plugins((MinimalPlugins, RenetClientPlugin, MemoryClientPlugin))
-            .insert_resource(renet_client)
-            .insert_resource(client_transport)
-            .init_resource::<ClientReceived>()
-            .add_systems(
-                Update,
-                |mut client: ResMut<RenetClient>, mut received: ResMut<ClientReceived>| {
-                    while let Some(packet) = client.receive_message(DefaultChannel::ReliableOrdered)
-                    {
-                        received.push(packet.to_vec());
-                    }
-                },
-            );
-
-        client
-    }
-
-    #[test]
-    fn simple_transport() {
-        let mut server = create_server_app();
-        let mut client = create_client_app(&mut server);
-
-        assert!(client
-            .world
-            .resource::<MemoryClientTransport>()
-            .is_connected());
-
-        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
-            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
-        });
-        server.update();
-        client.update();
-
-        assert_eq!(client_received(&client), [[1]]);
-        assert_eq!(server_received(&server), []);
-    }
-
-    #[test]
-    fn multiple_messages() {
-        let mut server = create_server_app();
-        let mut client = create_client_app(&mut server);
-
-        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
-            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
-            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![2]);
-        });
-        server.update();
-        server.update();
-        client.update();
-
-        assert_eq!(client_received(&client), [[1], [2], [1], [2]]);
-        assert_eq!(server_received(&server), []);
-
-        server.update();
-        client.update();
-
-        assert_eq!(client_received(&client), [[1], [2], [1], [2], [1], [2]]);
-        assert_eq!(server_received(&server), []);
-    }
-
-    #[test]
-    fn both_directions() {
-        let mut server = create_server_app();
-        let mut client = create_client_app(&mut server);
-
-        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
-            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
-        });
-        client.add_systems(Update, |mut client: ResMut<RenetClient>| {
-            client.send_message(DefaultChannel::ReliableOrdered, vec![2]);
-        });
-        server.update();
-        client.update();
-
-        assert_eq!(client_received(&client), [[1]]);
-        assert_eq!(server_received(&server), []);
-
-        server.update();
-
-        assert_eq!(client_received(&client), [[1]]);
-        assert_eq!(server_received(&server), [(0, vec![2])]);
-    }
-
-    #[test]
-    fn multiple_clients() {
-        let mut server = create_server_app();
-        let mut client1 = create_client_app(&mut server);
-        let mut client2 = create_client_app(&mut server);
-        let mut client3 = create_client_app(&mut server);
-
-        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
-            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![0]);
-        });
-        client1.add_systems(Update, |mut client: ResMut<RenetClient>| {
-            client.send_message(DefaultChannel::ReliableOrdered, vec![1]);
-        });
-        client2.add_systems(Update, |mut client: ResMut<RenetClient>| {
-            client.send_message(DefaultChannel::ReliableOrdered, vec![2]);
-        });
-        client3.add_systems(Update, |mut client: ResMut<RenetClient>| {
-            client.send_message(DefaultChannel::ReliableOrdered, vec![3]);
-        });
-
-        server.update();
-        client1.update();
-        client2.update();
-        client3.update();
-
-        assert_eq!(client_received(&client1), [[0]]);
-        assert_eq!(client_received(&client2), [[0]]);
-        assert_eq!(client_received(&client3), [[0]]);
-        assert_eq!(server_received(&server), []);
-
-        server.update();
-
-        assert_eq!(client_received(&client1), [[0]]);
-        assert_eq!(client_received(&client2), [[0]]);
-        assert_eq!(client_received(&client3), [[0]]);
-        assert_eq!(
-            server_received(&server),
-            [(0, vec![1]), (1, vec![2]), (2, vec![3])]
-        );
-    }
-
-    #[test]
-    fn disconnect_client() {
-        let mut server = create_server_app();
-        let mut client = create_client_app(&mut server);
-
-        server.update();
-        assert_eq!(
-            server.world.resource::<RenetServer>().clients_id(),
-            vec![ClientId::from_raw(0)]
-        );
-        assert!(client
-            .world
-            .resource::<MemoryClientTransport>()
-            .is_connected());
-
-        client.world.send_event(AppExit);
-        client.update();
-        server.update();
-
-        assert!(client.world.resource::<RenetClient>().is_disconnected());
-        assert!(!client
-            .world
-            .resource::<MemoryClientTransport>()
-            .is_connected());
-
-        assert!(server
-            .world
-            .resource::<RenetServer>()
-            .clients_id()
-            .is_empty());
-    }
-
-    #[test]
-    fn disconnect_server() {
-        let mut server = create_server_app();
-        let mut client = create_client_app(&mut server);
-
-        fn send_msg(mut server: ResMut<RenetServer>) {
-            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![0]);
-        }
-        server.add_systems(Update, send_msg.run_if(run_once()));
-
-        server.update();
-
-        assert_eq!(
-            server.world.resource::<RenetServer>().clients_id(),
-            [ClientId::from_raw(0)]
-        );
-        assert!(client
-            .world
-            .resource::<MemoryClientTransport>()
-            .is_connected());
-
-        server.world.send_event(AppExit);
-        server.update();
-        client.update();
-
-        assert!(server
-            .world
-            .resource::<RenetServer>()
-            .clients_id()
-            .is_empty());
-
-        assert!(client_received(&client).is_empty());
-
-        assert!(client.world.resource::<RenetClient>().is_disconnected());
-        assert!(!client
-            .world
-            .resource::<MemoryClientTransport>()
-            .is_connected());
-    }
-
-    #[test]
-    fn no_transport() {
-        let mut server = create_server_app();
-        let mut client = create_client_app(&mut server);
-
-        server.world.remove_resource::<MemoryServerTransport>();
-        client.world.remove_resource::<MemoryClientTransport>();
-
-        server.update();
-        client.update();
-    }
-}
@@ -0,0 +1,411 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bevy::utils::hashbrown::hash_map::Entry;
+use bevy::utils::HashMap;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+/// Describes how bad a simulated network link is.
+///
+/// Can be parsed from a comma separated list such as
+/// `latency=100,jitter=20,loss=0.05,duplicate=0.01,reorder=0.01`, where times are in milliseconds
+/// and the rest are chances between 0 and 1.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkConditionerConfig {
+    /// Delay added to every packet
+    pub latency: Duration,
+    /// Random extra delay of up to this much for every packet
+    pub jitter: Duration,
+    pub packet_loss: f64,
+    pub duplication: f64,
+    /// Chance that a packet is held back and delivered after the packet sent after it, or
+    /// [`MAX_HOLD_BACK`] late if none is
+    pub reordering: f64,
+}
+
+impl FromStr for LinkConditionerConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut config = LinkConditionerConfig::default();
+
+        for setting in s.split(',').filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{setting}'"))?;
+
+            let millis = || {
+                value
+                    .parse::<u64>()
+                    .map(Duration::from_millis)
+                    .map_err(|e| format!("Invalid duration for {key}: {e}"))
+            };
+            let chance = || match value.parse::<f64>() {
+                Ok(chance) if (0.0..=1.0).contains(&chance) => Ok(chance),
+                _ => Err(format!("{key} must be a number between 0 and 1")),
+            };
+
+            match key {
+                "latency" => config.latency = millis()?,
+                "jitter" => config.jitter = millis()?,
+                "loss" => config.packet_loss = chance()?,
+                "duplicate" => config.duplication = chance()?,
+                "reorder" => config.reordering = chance()?,
+                _ => return Err(format!("Unknown link conditioner setting '{key}'")),
+            }
+        }
+
+        Ok(config)
+    }
+}
+
+/// The longest a reordered packet waits for the packet to deliver it after
+pub const MAX_HOLD_BACK: Duration = Duration::from_millis(50);
+
+/// One direction of a simulated link. Packets go in with [`LinkConditioner::send`] and come out
+/// of [`LinkConditioner::receive`] once they have arrived.
+///
+/// All randomness comes from a seeded RNG, so the same seed and the same sequence of calls always
+/// produce the same result.
+pub struct LinkConditioner {
+    config: LinkConditionerConfig,
+    rng: StdRng,
+    in_flight: Vec<(Duration, Vec<u8>)>,
+    /// A reordered packet and when it arrives if no packet comes after it
+    held_back: Option<(Duration, Vec<u8>)>,
+}
+
+impl LinkConditioner {
+    pub fn new(config: LinkConditionerConfig, seed: u64) -> Self {
+        LinkConditioner {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            in_flight: Vec::new(),
+            held_back: None,
+        }
+    }
+
+    pub fn send(&mut self, packet: Vec<u8>, now: Duration) {
+        if self.rng.gen_bool(self.config.packet_loss) {
+            return;
+        }
+
+        // A held back packet is only released by the next packet on the link
+        let held_back = self.held_back.take();
+        if held_back.is_none() && self.rng.gen_bool(self.config.reordering) {
+            let deadline = self.arrival(now) + MAX_HOLD_BACK;
+            self.held_back = Some((deadline, packet));
+            return;
+        }
+
+        let arrival = self.arrival(now);
+        if self.rng.gen_bool(self.config.duplication) {
+            let duplicate_arrival = self.arrival(now);
+            self.queue(duplicate_arrival, packet.clone());
+        }
+        self.queue(arrival, packet);
+
+        if let Some((_, held_back)) = held_back {
+            self.queue(arrival, held_back);
+        }
+    }
+
+    /// All packets that have arrived by `now`, in the order they arrived
+    pub fn receive(&mut self, now: Duration) -> impl Iterator<Item = Vec<u8>> + '_ {
+        // Otherwise the last packet of a burst could wait forever
+        if self
+            .held_back
+            .as_ref()
+            .is_some_and(|&(deadline, _)| deadline <= now)
+        {
+            let (deadline, packet) = self.held_back.take().unwrap();
+            self.queue(deadline, packet);
+        }
+
+        let arrived = self
+            .in_flight
+            .partition_point(|&(arrival, _)| arrival <= now);
+        self.in_flight.drain(..arrived).map(|(_, packet)| packet)
+    }
+
+    fn arrival(&mut self, now: Duration) -> Duration {
+        now + self.config.latency + self.config.jitter.mul_f64(self.rng.gen())
+    }
+
+    fn queue(&mut self, arrival: Duration, packet: Vec<u8>) {
+        let index = self.in_flight.partition_point(|&(t, _)| t <= arrival);
+        self.in_flight.insert(index, (arrival, packet));
+    }
+}
+
+struct ProxiedClient {
+    upstream: UdpSocket,
+    to_server: LinkConditioner,
+    to_client: LinkConditioner,
+}
+
+/// Starts a UDP proxy on a background thread that forwards everything received on `listen_addr`
+/// to `server_addr` and back again, sending both directions through a [`LinkConditioner`].
+///
+/// Clients connect to the returned address instead of the server, which needs to list it among its
+/// public addresses for netcode to accept the connection.
+pub fn spawn_udp_conditioner(
+    listen_addr: SocketAddr,
+    server_addr: SocketAddr,
+    config: LinkConditionerConfig,
+    seed: u64,
+) -> std::io::Result<SocketAddr> {
+    let socket = UdpSocket::bind(listen_addr)?;
+    socket.set_nonblocking(true)?;
+    let local_addr = socket.local_addr()?;
+
+    thread::spawn(move || {
+        let start = Instant::now();
+        let mut clients = HashMap::<SocketAddr, ProxiedClient>::default();
+        let mut buffer = [0; 1500];
+
+        loop {
+            let now = start.elapsed();
+
+            loop {
+                match socket.recv_from(&mut buffer) {
+                    Ok((len, client_addr)) => {
+                        let client = match clients.entry(client_addr) {
+                            Entry::Occupied(entry) => entry.into_mut(),
+                            Entry::Vacant(entry) => {
+                                let upstream = match connect_upstream(server_addr) {
+                                    Ok(upstream) => upstream,
+                                    Err(e) => {
+                                        println!(
+                                            "Could not proxy {client_addr} to {server_addr}: {e}"
+                                        );
+                                        continue;
+                                    }
+                                };
+                                let seed = seed.wrapping_add(2 * client_addr.port() as u64);
+                                entry.insert(ProxiedClient {
+                                    upstream,
+                                    to_server: LinkConditioner::new(config, seed),
+                                    to_client: LinkConditioner::new(config, seed.wrapping_add(1)),
+                                })
+                            }
+                        };
+                        client.to_server.send(buffer[..len].to_vec(), now);
+                    }
+                    Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                    Err(_) => continue,
+                }
+            }
+
+            for (&client_addr, client) in clients.iter_mut() {
+                while let Ok(len) = client.upstream.recv(&mut buffer) {
+                    client.to_client.send(buffer[..len].to_vec(), now);
+                }
+
+                for packet in client.to_server.receive(now) {
+                    let _ = client.upstream.send(&packet);
+                }
+                for packet in client.to_client.receive(now) {
+                    let _ = socket.send_to(&packet, client_addr);
+                }
+            }
+
+            thread::sleep(Duration::from_millis(1));
+        }
+    });
+
+    Ok(local_addr)
+}
+
+fn connect_upstream(server_addr: SocketAddr) -> std::io::Result<UdpSocket> {
+    let any: IpAddr = match server_addr {
+        SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+        SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+    };
+    let upstream = UdpSocket::bind((any, 0))?;
+    upstream.connect(server_addr)?;
+    upstream.set_nonblocking(true)?;
+    Ok(upstream)
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::prelude::default;
+
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    fn send_all(conditioner: &mut LinkConditioner, packets: u8) {
+        for i in 0..packets {
+            conditioner.send(vec![i], ms(0));
+        }
+    }
+
+    #[test]
+    fn perfect_link() {
+        let mut conditioner = LinkConditioner::new(default(), 0);
+        send_all(&mut conditioner, 3);
+
+        assert_eq!(
+            conditioner.receive(ms(0)).collect::<Vec<_>>(),
+            [[0], [1], [2]]
+        );
+    }
+
+    #[test]
+    fn latency() {
+        let config = LinkConditionerConfig {
+            latency: ms(100),
+            ..default()
+        };
+        let mut conditioner = LinkConditioner::new(config, 0);
+        send_all(&mut conditioner, 1);
+
+        assert_eq!(conditioner.receive(ms(99)).count(), 0);
+        assert_eq!(conditioner.receive(ms(100)).collect::<Vec<_>>(), [[0]]);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds() {
+        let config = LinkConditionerConfig {
+            latency: ms(50),
+            jitter: ms(50),
+            ..default()
+        };
+        let mut conditioner = LinkConditioner::new(config, 0);
+        send_all(&mut conditioner, 100);
+
+        assert_eq!(conditioner.receive(ms(49)).count(), 0);
+        assert_eq!(conditioner.receive(ms(100)).count(), 100);
+    }
+
+    #[test]
+    fn loss_and_duplication() {
+        let lossy = LinkConditionerConfig {
+            packet_loss: 1.0,
+            ..default()
+        };
+        let mut conditioner = LinkConditioner::new(lossy, 0);
+        send_all(&mut conditioner, 10);
+        assert_eq!(conditioner.receive(ms(1000)).count(), 0);
+
+        let duplicating = LinkConditionerConfig {
+            duplication: 1.0,
+            ..default()
+        };
+        let mut conditioner = LinkConditioner::new(duplicating, 0);
+        send_all(&mut conditioner, 2);
+        assert_eq!(
+            conditioner.receive(ms(0)).collect::<Vec<_>>(),
+            [[0], [0], [1], [1]]
+        );
+    }
+
+    #[test]
+    fn reordering() {
+        let config = LinkConditionerConfig {
+            reordering: 1.0,
+            ..default()
+        };
+        let mut conditioner = LinkConditioner::new(config, 0);
+        send_all(&mut conditioner, 4);
+
+        assert_eq!(
+            conditioner.receive(ms(0)).collect::<Vec<_>>(),
+            [[1], [0], [3], [2]]
+        );
+    }
+
+    #[test]
+    fn held_back_packets_arrive_late_on_their_own() {
+        let config = LinkConditionerConfig {
+            reordering: 1.0,
+            ..default()
+        };
+        let mut conditioner = LinkConditioner::new(config, 0);
+        send_all(&mut conditioner, 3);
+
+        assert_eq!(conditioner.receive(ms(0)).collect::<Vec<_>>(), [[1], [0]]);
+        assert_eq!(conditioner.receive(MAX_HOLD_BACK - ms(1)).count(), 0);
+        assert_eq!(
+            conditioner.receive(MAX_HOLD_BACK).collect::<Vec<_>>(),
+            [[2]]
+        );
+    }
+
+    #[test]
+    fn same_seed_same_result() {
+        let config = LinkConditionerConfig {
+            latency: ms(10),
+            jitter: ms(30),
+            packet_loss: 0.2,
+            duplication: 0.2,
+            reordering: 0.2,
+        };
+        let run = |seed| {
+            let mut conditioner = LinkConditioner::new(config, seed);
+            send_all(&mut conditioner, 100);
+            conditioner.receive(ms(1000)).collect::<Vec<_>>()
+        };
+
+        assert_eq!(run(7), run(7));
+        assert_ne!(run(7), run(8));
+    }
+
+    #[test]
+    fn parse_config() {
+        assert_eq!(
+            "latency=100,jitter=20,loss=0.05,duplicate=0.01,reorder=0.5".parse(),
+            Ok(LinkConditionerConfig {
+                latency: ms(100),
+                jitter: ms(20),
+                packet_loss: 0.05,
+                duplication: 0.01,
+                reordering: 0.5,
+            })
+        );
+        assert_eq!("".parse(), Ok(LinkConditionerConfig::default()));
+        assert!("loss=2".parse::<LinkConditionerConfig>().is_err());
+        assert!("speed=2".parse::<LinkConditionerConfig>().is_err());
+    }
+
+    #[test]
+    fn udp_proxy() {
+        let server = UdpSocket::bind("127.0.0.1:0").unwrap();
+        server.set_read_timeout(Some(ms(1000))).unwrap();
+        let config = LinkConditionerConfig {
+            latency: ms(20),
+            ..default()
+        };
+        let proxy = spawn_udp_conditioner(
+            "127.0.0.1:0".parse().unwrap(),
+            server.local_addr().unwrap(),
+            config,
+            0,
+        )
+        .unwrap();
+
+        let client = UdpSocket::bind("127.0.0.1:0").unwrap();
+        client.set_read_timeout(Some(ms(1000))).unwrap();
+        let sent_at = Instant::now();
+        client.send_to(&[1, 2, 3], proxy).unwrap();
+
+        let mut buffer = [0; 16];
+        let (len, upstream) = server.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], [1, 2, 3]);
+        assert!(sent_at.elapsed() >= ms(20));
+
+        server.send_to(&[4, 5], upstream).unwrap();
+        let (len, from) = client.recv_from(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], [4, 5]);
+        assert_eq!(from, proxy);
+        assert!(sent_at.elapsed() >= ms(40));
+    }
+}
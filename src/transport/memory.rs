@@ -0,0 +1,1350 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SendError, Sender, TryRecvError};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::synccell::SyncCell;
+use bevy::utils::HashMap;
+use bevy_renet::renet::{ClientId, RenetClient, RenetServer};
+use bevy_renet::{RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
+use super::conditioner::{LinkConditioner, LinkConditionerConfig};
+use super::{
+    ClientTransport, ClientTransportPlugin, ConnectionState, DisconnectReason, ServerTransport,
+    ServerTransportPlugin,
+};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum MemoryPacket {
+    ConnectionRequest,
+    ConnectionAccepted,
+    KeepAlive,
+    Payload(Vec<u8>),
+    Disconnect(DisconnectReason),
+}
+
+struct Connection {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Vec<u8>>,
+    conditioner: Option<LinkConditioner>,
+    last_received: Option<Duration>,
+}
+
+impl Connection {
+    fn new(
+        sender: Sender<Vec<u8>>,
+        receiver: Receiver<Vec<u8>>,
+        conditioner: Option<LinkConditioner>,
+    ) -> Self {
+        Self {
+            sender,
+            receiver,
+            conditioner,
+            last_received: None,
+        }
+    }
+
+    fn send(&mut self, packet: MemoryPacket, now: Duration) -> Result<(), SendError<Vec<u8>>> {
+        let packet = bincode::serialize(&packet).unwrap();
+
+        match self.conditioner {
+            Some(ref mut conditioner) => {
+                conditioner.send(packet, now);
+                Ok(())
+            }
+            None => self.sender.send(packet),
+        }
+    }
+
+    /// Hands over the packets the link conditioner has let through by now
+    fn flush(&mut self, now: Duration) -> Result<(), SendError<Vec<u8>>> {
+        let Some(ref mut conditioner) = self.conditioner else {
+            return Ok(());
+        };
+
+        for packet in conditioner.receive(now) {
+            self.sender.send(packet)?;
+        }
+
+        Ok(())
+    }
+
+    /// Everything received since the last call, or [`DisconnectReason::ConnectionLost`] if
+    /// the other end was dropped
+    fn receive(&mut self, now: Duration) -> Result<Vec<MemoryPacket>, DisconnectReason> {
+        let mut packets = vec![];
+        self.last_received.get_or_insert(now);
+
+        loop {
+            match self.receiver.try_recv() {
+                Ok(packet) => {
+                    self.last_received = Some(now);
+                    packets.push(bincode::deserialize(&packet).unwrap());
+                }
+                Err(TryRecvError::Empty) => return Ok(packets),
+                Err(TryRecvError::Disconnected) if packets.is_empty() => {
+                    return Err(DisconnectReason::ConnectionLost)
+                }
+                // Handle what arrived first, the loss is noticed on the next update
+                Err(TryRecvError::Disconnected) => return Ok(packets),
+            }
+        }
+    }
+
+    fn timed_out(&self, now: Duration, timeout: Duration) -> bool {
+        self.last_received
+            .is_some_and(|last_received| now.saturating_sub(last_received) > timeout)
+    }
+}
+
+/// Hands out new connections to a [`MemoryServerTransport`], which picks them up on its next
+/// update
+#[derive(Clone)]
+struct MemoryConnector {
+    new_connections: Sender<(ClientId, Connection)>,
+    next_client_id: Arc<AtomicU64>,
+    link_conditioner: Option<(LinkConditionerConfig, u64)>,
+    timeout: Duration,
+}
+
+impl MemoryConnector {
+    fn connect(&self, client_id: ClientId) -> Connection {
+        let (send_to_client, receive_from_server) = mpsc::channel::<Vec<u8>>();
+        let (send_to_server, receive_from_client) = mpsc::channel::<Vec<u8>>();
+
+        let (to_client, to_server) = match self.link_conditioner {
+            Some((config, seed)) => {
+                let seed = seed.wrapping_add(2 * client_id.raw());
+                (
+                    Some(LinkConditioner::new(config, seed)),
+                    Some(LinkConditioner::new(config, seed.wrapping_add(1))),
+                )
+            }
+            None => (None, None),
+        };
+
+        // If the server is gone its end of the connection is dropped right away, which the
+        // client notices as a lost connection
+        let _ = self.new_connections.send((
+            client_id,
+            Connection::new(send_to_client, receive_from_client, to_client),
+        ));
+
+        Connection::new(send_to_server, receive_from_server, to_server)
+    }
+}
+
+#[derive(Resource)]
+pub struct MemoryServerTransport {
+    connector: MemoryConnector,
+    new_connections: SyncCell<Receiver<(ClientId, Connection)>>,
+    pending: HashMap<ClientId, SyncCell<Connection>>,
+    connections: HashMap<ClientId, SyncCell<Connection>>,
+    disconnect_reasons: HashMap<ClientId, DisconnectReason>,
+}
+
+impl Default for MemoryServerTransport {
+    fn default() -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        MemoryServerTransport {
+            connector: MemoryConnector {
+                new_connections: sender,
+                next_client_id: default(),
+                link_conditioner: None,
+                timeout: DEFAULT_TIMEOUT,
+            },
+            new_connections: SyncCell::new(receiver),
+            pending: default(),
+            connections: default(),
+            disconnect_reasons: default(),
+        }
+    }
+}
+
+impl MemoryServerTransport {
+    /// Sends every packet of every connection, in both directions, through a link conditioner.
+    /// Each direction gets its own RNG derived from `seed` and the client id.
+    pub fn with_link_conditioner(mut self, config: LinkConditionerConfig, seed: u64) -> Self {
+        self.connector.link_conditioner = Some((config, seed));
+        self
+    }
+
+    /// Disconnect peers that have not sent anything for this long. Applies to both ends of every
+    /// connection created afterwards.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.connector.timeout = timeout;
+        self
+    }
+
+    /// Creates a client that starts connecting to this server on its first update
+    pub fn create_client(&mut self) -> MemoryClientTransport {
+        let client_id = ClientId::from_raw(
+            self.connector
+                .next_client_id
+                .fetch_add(1, Ordering::Relaxed),
+        );
+
+        MemoryClientTransport::new(client_id, self.connector.clone())
+    }
+
+    /// Why the client was last disconnected, as seen from the server
+    pub fn disconnect_reason(&self, client_id: ClientId) -> Option<DisconnectReason> {
+        self.disconnect_reasons.get(&client_id).copied()
+    }
+
+    fn update(&mut self, server: &mut RenetServer, now: Duration) {
+        for (client_id, connection) in self.new_connections.get().try_iter().collect_vec() {
+            if self.connections.contains_key(&client_id) {
+                self.remove_client(client_id, DisconnectReason::Reconnected, server);
+            }
+            self.pending.insert(client_id, SyncCell::new(connection));
+        }
+
+        let timeout = self.connector.timeout;
+        let mut accepted = vec![];
+        self.pending.retain(|&client_id, connection| {
+            let connection = connection.get();
+
+            match connection.receive(now) {
+                Ok(packets) => {
+                    if packets
+                        .iter()
+                        .any(|packet| matches!(packet, MemoryPacket::ConnectionRequest))
+                    {
+                        accepted.push(client_id);
+                    }
+                    !connection.timed_out(now, timeout)
+                }
+                Err(_) => false,
+            }
+        });
+
+        for client_id in accepted {
+            let connection = self.pending.remove(&client_id).unwrap();
+            // A reconnecting client was disconnected in this update, and the reason is read when the
+            // disconnection event is sent. Every later disconnection records its own reason.
+            self.connections.insert(client_id, connection);
+            server.add_connection(client_id);
+            self.send_accepted(client_id, now);
+        }
+
+        let mut to_disconnect = vec![];
+
+        for (&client_id, connection) in self.connections.iter_mut() {
+            let connection = connection.get();
+
+            match connection.receive(now) {
+                Ok(packets) => {
+                    for packet in packets {
+                        match packet {
+                            MemoryPacket::Payload(payload) => {
+                                // Fails only if the game already disconnected the client
+                                let _ = server.process_packet_from(&payload, client_id);
+                            }
+                            // The acceptance got lost on the way, so the client is still asking
+                            MemoryPacket::ConnectionRequest => {
+                                let _ = connection.send(MemoryPacket::ConnectionAccepted, now);
+                            }
+                            MemoryPacket::Disconnect(reason) => {
+                                to_disconnect.push((client_id, reason));
+                            }
+                            MemoryPacket::ConnectionAccepted | MemoryPacket::KeepAlive => (),
+                        }
+                    }
+
+                    if connection.timed_out(now, timeout) {
+                        to_disconnect.push((client_id, DisconnectReason::TimedOut));
+                    }
+                }
+                Err(reason) => to_disconnect.push((client_id, reason)),
+            }
+        }
+
+        for (client_id, reason) in to_disconnect {
+            self.remove_client(client_id, reason, server);
+        }
+    }
+
+    fn send_accepted(&mut self, client_id: ClientId, now: Duration) {
+        if let Some(connection) = self.connections.get_mut(&client_id) {
+            let _ = connection.get().send(MemoryPacket::ConnectionAccepted, now);
+        }
+    }
+
+    fn send_packets(&mut self, server: &mut RenetServer, now: Duration) {
+        for client_id in server.disconnections_id() {
+            if let Some(mut connection) = self.connections.remove(&client_id) {
+                let connection = connection.get();
+                let reason = DisconnectReason::DisconnectedByServer;
+                let _ = connection.send(MemoryPacket::Disconnect(reason), now);
+                let _ = connection.flush(now);
+                self.disconnect_reasons.insert(client_id, reason);
+            }
+            // Sends the disconnection event
+            server.remove_connection(client_id);
+        }
+
+        let mut lost = vec![];
+
+        for (&client_id, connection) in self.connections.iter_mut() {
+            let connection = connection.get();
+
+            let packets = server.get_packets_to_send(client_id).unwrap_or_default();
+            let sent = if packets.is_empty() {
+                connection.send(MemoryPacket::KeepAlive, now)
+            } else {
+                packets
+                    .into_iter()
+                    .try_for_each(|packet| connection.send(MemoryPacket::Payload(packet), now))
+            };
+
+            if sent.and_then(|_| connection.flush(now)).is_err() {
+                lost.push(client_id);
+            }
+        }
+
+        for connection in self.pending.values_mut() {
+            let _ = connection.get().flush(now);
+        }
+
+        for client_id in lost {
+            self.remove_client(client_id, DisconnectReason::ConnectionLost, server);
+        }
+    }
+
+    fn remove_client(
+        &mut self,
+        client_id: ClientId,
+        reason: DisconnectReason,
+        server: &mut RenetServer,
+    ) {
+        self.connections.remove(&client_id);
+        self.disconnect_reasons.insert(client_id, reason);
+        server.remove_connection(client_id);
+    }
+
+    fn disconnect_all(&mut self, server: &mut RenetServer, now: Duration) {
+        let reason = DisconnectReason::DisconnectedByServer;
+
+        for (client_id, mut connection) in self.connections.drain() {
+            let connection = connection.get();
+            let _ = connection.send(MemoryPacket::Disconnect(reason), now);
+            let _ = connection.flush(now);
+            self.disconnect_reasons.insert(client_id, reason);
+        }
+        self.pending.clear();
+
+        server.disconnect_all();
+    }
+}
+
+#[derive(Resource)]
+pub struct MemoryClientTransport {
+    client_id: ClientId,
+    connector: MemoryConnector,
+    connection: Option<SyncCell<Connection>>,
+    state: ConnectionState,
+}
+
+impl MemoryClientTransport {
+    fn new(client_id: ClientId, connector: MemoryConnector) -> Self {
+        Self {
+            client_id,
+            connection: Some(SyncCell::new(connector.connect(client_id))),
+            connector,
+            state: ConnectionState::Connecting,
+        }
+    }
+
+    fn update(&mut self, client: &mut RenetClient, now: Duration) {
+        let Some(ref mut connection) = self.connection else {
+            return;
+        };
+        let connection = connection.get();
+
+        let packets = match connection.receive(now) {
+            Ok(packets) => packets,
+            Err(reason) => return self.close(client, reason),
+        };
+        let timed_out = connection.timed_out(now, self.connector.timeout);
+
+        for packet in packets {
+            match packet {
+                MemoryPacket::ConnectionAccepted => {
+                    if self.state == ConnectionState::Connecting {
+                        self.state = ConnectionState::Connected;
+                        client.set_connected();
+                    }
+                }
+                MemoryPacket::Payload(payload) => {
+                    if self.state == ConnectionState::Connected {
+                        client.process_packet(&payload);
+                    }
+                }
+                MemoryPacket::Disconnect(reason) => return self.close(client, reason),
+                MemoryPacket::ConnectionRequest | MemoryPacket::KeepAlive => (),
+            }
+        }
+
+        if timed_out {
+            self.close(client, DisconnectReason::TimedOut);
+        }
+    }
+
+    fn send_packets(&mut self, client: &mut RenetClient, now: Duration) {
+        if client.is_disconnected() && self.connection.is_some() {
+            return self.disconnect(client, now);
+        }
+
+        let Some(ref mut connection) = self.connection else {
+            return;
+        };
+        let connection = connection.get();
+
+        let sent = match self.state {
+            ConnectionState::Connecting => connection.send(MemoryPacket::ConnectionRequest, now),
+            _ => {
+                let packets = client.get_packets_to_send();
+                if packets.is_empty() {
+                    connection.send(MemoryPacket::KeepAlive, now)
+                } else {
+                    packets
+                        .into_iter()
+                        .try_for_each(|packet| connection.send(MemoryPacket::Payload(packet), now))
+                }
+            }
+        };
+
+        if sent.and_then(|_| connection.flush(now)).is_err() {
+            self.close(client, DisconnectReason::ConnectionLost);
+        }
+    }
+
+    /// Tells the server we are leaving before closing the connection
+    fn disconnect(&mut self, client: &mut RenetClient, now: Duration) {
+        if let Some(ref mut connection) = self.connection {
+            let connection = connection.get();
+            let reason = DisconnectReason::DisconnectedByClient;
+            let _ = connection.send(MemoryPacket::Disconnect(reason), now);
+            let _ = connection.flush(now);
+        }
+
+        client.disconnect();
+        self.close(client, DisconnectReason::DisconnectedByClient);
+    }
+
+    fn close(&mut self, client: &mut RenetClient, reason: DisconnectReason) {
+        self.connection.take();
+        self.state = ConnectionState::Disconnected(reason);
+        client.disconnect_due_to_transport();
+    }
+
+    /// Connects to the same server again with the same client id, replacing the current
+    /// connection if there is one. A disconnected [`RenetClient`] can not be reused, so it has to
+    /// be replaced as well.
+    pub fn reconnect(&mut self) {
+        self.connection = Some(SyncCell::new(self.connector.connect(self.client_id)));
+        self.state = ConnectionState::Connecting;
+    }
+
+    pub fn client_id(&self) -> ClientId {
+        self.client_id
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+
+    pub fn disconnect_reason(&self) -> Option<DisconnectReason> {
+        match self.state {
+            ConnectionState::Disconnected(reason) => Some(reason),
+            _ => None,
+        }
+    }
+
+    pub fn is_connecting(&self) -> bool {
+        self.state == ConnectionState::Connecting
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.state == ConnectionState::Connected
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        matches!(self.state, ConnectionState::Disconnected(_))
+    }
+}
+
+impl ServerTransport for MemoryServerTransport {
+    fn disconnect_reason(&self, client_id: ClientId) -> Option<DisconnectReason> {
+        MemoryServerTransport::disconnect_reason(self, client_id)
+    }
+}
+
+impl ClientTransport for MemoryClientTransport {
+    fn connection_state(&self, _client: &RenetClient) -> ConnectionState {
+        self.state
+    }
+}
+
+// Plugins
+
+pub struct MemoryServerPlugin;
+
+pub struct MemoryClientPlugin;
+
+impl Plugin for MemoryServerPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ServerTransportPlugin::<MemoryServerTransport>::default())
+            .add_systems(
+                PreUpdate,
+                Self::update_system
+                    .run_if(resource_exists::<RenetServer>())
+                    .run_if(resource_exists::<MemoryServerTransport>())
+                    .in_set(RenetReceive)
+                    .after(RenetServerPlugin::update_system),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    Self::send_packets.in_set(RenetSend),
+                    Self::disconnect_on_exit,
+                )
+                    .run_if(resource_exists::<RenetServer>())
+                    .run_if(resource_exists::<MemoryServerTransport>()),
+            );
+    }
+}
+
+impl MemoryServerPlugin {
+    pub fn update_system(
+        mut transport: ResMut<MemoryServerTransport>,
+        mut server: ResMut<RenetServer>,
+        time: Res<Time>,
+    ) {
+        transport.update(&mut server, time.elapsed());
+    }
+
+    pub fn send_packets(
+        mut transport: ResMut<MemoryServerTransport>,
+        mut server: ResMut<RenetServer>,
+        time: Res<Time>,
+    ) {
+        transport.send_packets(&mut server, time.elapsed());
+    }
+
+    fn disconnect_on_exit(
+        exit: EventReader<AppExit>,
+        mut transport: ResMut<MemoryServerTransport>,
+        mut server: ResMut<RenetServer>,
+        time: Res<Time>,
+    ) {
+        if !exit.is_empty() {
+            transport.disconnect_all(&mut server, time.elapsed());
+        }
+    }
+}
+
+impl Plugin for MemoryClientPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ClientTransportPlugin::<MemoryClientTransport>::default())
+            .add_systems(
+                PreUpdate,
+                Self::update_system
+                    .run_if(resource_exists::<RenetClient>())
+                    .run_if(resource_exists::<MemoryClientTransport>())
+                    .in_set(RenetReceive)
+                    .after(RenetClientPlugin::update_system),
+            )
+            .add_systems(
+                PostUpdate,
+                (
+                    Self::send_packets.in_set(RenetSend),
+                    Self::disconnect_on_exit,
+                )
+                    .run_if(resource_exists::<RenetClient>())
+                    .run_if(resource_exists::<MemoryClientTransport>()),
+            );
+    }
+}
+
+impl MemoryClientPlugin {
+    pub fn update_system(
+        mut transport: ResMut<MemoryClientTransport>,
+        mut client: ResMut<RenetClient>,
+        time: Res<Time>,
+    ) {
+        transport.update(&mut client, time.elapsed());
+    }
+
+    pub fn send_packets(
+        mut transport: ResMut<MemoryClientTransport>,
+        mut client: ResMut<RenetClient>,
+        time: Res<Time>,
+    ) {
+        transport.send_packets(&mut client, time.elapsed());
+    }
+
+    fn disconnect_on_exit(
+        mut transport: ResMut<MemoryClientTransport>,
+        mut client: ResMut<RenetClient>,
+        exit: EventReader<AppExit>,
+        time: Res<Time>,
+    ) {
+        if !exit.is_empty() {
+            transport.disconnect(&mut client, time.elapsed());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::time::TimeUpdateStrategy;
+    use bevy_renet::renet::{ConnectionConfig, DefaultChannel};
+
+    use crate::test_utils::connect;
+    use crate::transport::{
+        client_connected, client_connecting, client_disconnected, client_just_connected,
+        client_just_disconnected, ClientConnection, ConnectionEvent,
+    };
+
+    use super::*;
+
+    #[derive(Debug, Default, Resource, PartialEq, Eq, Deref, DerefMut)]
+    pub struct ServerReceived(Vec<(u64, Vec<u8>)>);
+
+    #[derive(Debug, Default, Resource, PartialEq, Eq, Deref, DerefMut)]
+    pub struct ClientReceived(Vec<Vec<u8>>);
+
+    fn client_received(client: &App) -> Vec<Vec<u8>> {
+        client.world.resource::<ClientReceived>().0.clone()
+    }
+
+    fn server_received(server: &App) -> Vec<(u64, Vec<u8>)> {
+        let mut received = server.world.resource::<ServerReceived>().0.clone();
+        received.sort_by_key(|&(client_id, _)| client_id);
+        received
+    }
+
+    fn create_server_app() -> App {
+        create_server_app_with(MemoryServerTransport::default())
+    }
+
+    fn create_server_app_with(server_transport: MemoryServerTransport) -> App {
+        let renet_server = RenetServer::new(ConnectionConfig::default());
+
+        let mut server = App::new();
+        server
+            .add_plugins((MinimalPlugins, RenetServerPlugin, MemoryServerPlugin))
+            .insert_resource(renet_server)
+            .insert_resource(server_transport)
+            .init_resource::<ServerReceived>()
+            .add_systems(
+                Update,
+                |mut server: ResMut<RenetServer>, mut received: ResMut<ServerReceived>| {
+                    for client_id in server.clients_id() {
+                        while let Some(packet) =
+                            server.receive_message(client_id, DefaultChannel::ReliableOrdered)
+                        {
+                            received.push((client_id.raw(), packet.to_vec()));
+                        }
+                    }
+                },
+            );
+
+        server
+    }
+
+    fn create_client_app(server: &mut App) -> App {
+        let mut client = create_unconnected_client_app(server);
+        connect(server, &mut client);
+
+        client
+    }
+
+    fn create_unconnected_client_app(server: &mut App) -> App {
+        let mut server_transport = server.world.resource_mut::<MemoryServerTransport>();
+        let client_transport = server_transport.create_client();
+        let renet_client = RenetClient::new(ConnectionConfig::default());
+
+        let mut client = App::new();
+        client
+            .add_plugins((MinimalPlugins, RenetClientPlugin, MemoryClientPlugin))
+            .insert_resource(renet_client)
+            .insert_resource(client_transport)
+            .init_resource::<ClientReceived>()
+            .add_systems(
+                Update,
+                |mut client: ResMut<RenetClient>, mut received: ResMut<ClientReceived>| {
+                    while let Some(packet) = client.receive_message(DefaultChannel::ReliableOrdered)
+                    {
+                        received.push(packet.to_vec());
+                    }
+                },
+            );
+
+        client
+    }
+
+    #[test]
+    fn simple_transport() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        assert!(client
+            .world
+            .resource::<MemoryClientTransport>()
+            .is_connected());
+
+        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
+        });
+        server.update();
+        client.update();
+
+        assert_eq!(client_received(&client), [[1]]);
+        assert_eq!(server_received(&server), []);
+    }
+
+    #[test]
+    fn multiple_messages() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![2]);
+        });
+        server.update();
+        server.update();
+        client.update();
+
+        assert_eq!(client_received(&client), [[1], [2], [1], [2]]);
+        assert_eq!(server_received(&server), []);
+
+        server.update();
+        client.update();
+
+        assert_eq!(client_received(&client), [[1], [2], [1], [2], [1], [2]]);
+        assert_eq!(server_received(&server), []);
+    }
+
+    #[test]
+    fn both_directions() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
+        });
+        client.add_systems(Update, |mut client: ResMut<RenetClient>| {
+            client.send_message(DefaultChannel::ReliableOrdered, vec![2]);
+        });
+        server.update();
+        client.update();
+
+        assert_eq!(client_received(&client), [[1]]);
+        assert_eq!(server_received(&server), []);
+
+        server.update();
+
+        assert_eq!(client_received(&client), [[1]]);
+        assert_eq!(server_received(&server), [(0, vec![2])]);
+    }
+
+    #[test]
+    fn multiple_clients() {
+        let mut server = create_server_app();
+        let mut client1 = create_client_app(&mut server);
+        let mut client2 = create_client_app(&mut server);
+        let mut client3 = create_client_app(&mut server);
+
+        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![0]);
+        });
+        client1.add_systems(Update, |mut client: ResMut<RenetClient>| {
+            client.send_message(DefaultChannel::ReliableOrdered, vec![1]);
+        });
+        client2.add_systems(Update, |mut client: ResMut<RenetClient>| {
+            client.send_message(DefaultChannel::ReliableOrdered, vec![2]);
+        });
+        client3.add_systems(Update, |mut client: ResMut<RenetClient>| {
+            client.send_message(DefaultChannel::ReliableOrdered, vec![3]);
+        });
+
+        server.update();
+        client1.update();
+        client2.update();
+        client3.update();
+
+        assert_eq!(client_received(&client1), [[0]]);
+        assert_eq!(client_received(&client2), [[0]]);
+        assert_eq!(client_received(&client3), [[0]]);
+        assert_eq!(server_received(&server), []);
+
+        server.update();
+
+        assert_eq!(client_received(&client1), [[0]]);
+        assert_eq!(client_received(&client2), [[0]]);
+        assert_eq!(client_received(&client3), [[0]]);
+        assert_eq!(
+            server_received(&server),
+            [(0, vec![1]), (1, vec![2]), (2, vec![3])]
+        );
+    }
+
+    #[test]
+    fn disconnect_client() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        server.update();
+        assert_eq!(
+            server.world.resource::<RenetServer>().clients_id(),
+            vec![ClientId::from_raw(0)]
+        );
+        assert!(client
+            .world
+            .resource::<MemoryClientTransport>()
+            .is_connected());
+
+        client.world.send_event(AppExit);
+        client.update();
+        server.update();
+
+        assert!(client.world.resource::<RenetClient>().is_disconnected());
+        assert!(!client
+            .world
+            .resource::<MemoryClientTransport>()
+            .is_connected());
+
+        assert!(server
+            .world
+            .resource::<RenetServer>()
+            .clients_id()
+            .is_empty());
+    }
+
+    #[test]
+    fn disconnect_server() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        fn send_msg(mut server: ResMut<RenetServer>) {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![0]);
+        }
+        server.add_systems(Update, send_msg.run_if(run_once()));
+
+        server.update();
+
+        assert_eq!(
+            server.world.resource::<RenetServer>().clients_id(),
+            [ClientId::from_raw(0)]
+        );
+        assert!(client
+            .world
+            .resource::<MemoryClientTransport>()
+            .is_connected());
+
+        server.world.send_event(AppExit);
+        server.update();
+        client.update();
+
+        assert!(server
+            .world
+            .resource::<RenetServer>()
+            .clients_id()
+            .is_empty());
+
+        assert!(client_received(&client).is_empty());
+
+        assert!(client.world.resource::<RenetClient>().is_disconnected());
+        assert!(!client
+            .world
+            .resource::<MemoryClientTransport>()
+            .is_connected());
+    }
+
+    #[test]
+    fn no_transport() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        server.world.remove_resource::<MemoryServerTransport>();
+        client.world.remove_resource::<MemoryClientTransport>();
+
+        server.update();
+        client.update();
+    }
+
+    fn create_apps_with(server_transport: MemoryServerTransport) -> (App, App) {
+        let mut server = create_server_app_with(server_transport);
+        let mut client = create_unconnected_client_app(&mut server);
+        for app in [&mut server, &mut client] {
+            app.insert_resource(TimeUpdateStrategy::ManualDuration(Duration::from_millis(
+                10,
+            )));
+        }
+
+        (server, client)
+    }
+
+    fn create_conditioned_apps(config: LinkConditionerConfig) -> (App, App) {
+        let (mut server, mut client) =
+            create_apps_with(MemoryServerTransport::default().with_link_conditioner(config, 0));
+        connect(&mut server, &mut client);
+
+        (server, client)
+    }
+
+    fn client_transport(client: &App) -> &MemoryClientTransport {
+        client.world.resource::<MemoryClientTransport>()
+    }
+
+    #[test]
+    fn conditioned_latency() {
+        let (mut server, mut client) = create_conditioned_apps(LinkConditionerConfig {
+            latency: Duration::from_millis(50),
+            ..default()
+        });
+
+        fn send_msg(mut server: ResMut<RenetServer>) {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
+        }
+        server.add_systems(Update, send_msg.run_if(run_once()));
+
+        for _ in 0..4 {
+            server.update();
+            client.update();
+        }
+        assert!(client_received(&client).is_empty());
+
+        for _ in 0..2 {
+            server.update();
+            client.update();
+        }
+        assert_eq!(client_received(&client), [[1]]);
+    }
+
+    #[test]
+    fn conditioned_lossy_link_stays_reliable() {
+        let (mut server, mut client) = create_conditioned_apps(LinkConditionerConfig {
+            latency: Duration::from_millis(20),
+            jitter: Duration::from_millis(40),
+            packet_loss: 0.3,
+            duplication: 0.1,
+            reordering: 0.1,
+        });
+
+        fn send_msgs(mut server: ResMut<RenetServer>) {
+            for i in 0..10 {
+                server.broadcast_message(DefaultChannel::ReliableOrdered, vec![i]);
+            }
+        }
+        server.add_systems(Update, send_msgs.run_if(run_once()));
+        fn send_client_msgs(mut client: ResMut<RenetClient>) {
+            for i in 10..20 {
+                client.send_message(DefaultChannel::ReliableOrdered, vec![i]);
+            }
+        }
+        client.add_systems(Update, send_client_msgs.run_if(run_once()));
+
+        for _ in 0..300 {
+            server.update();
+            client.update();
+        }
+
+        assert_eq!(
+            client_received(&client),
+            (0..10).map(|i| vec![i]).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            server_received(&server),
+            (10..20).map(|i| (0, vec![i])).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn handshake() {
+        let mut server = create_server_app();
+        let mut client = create_unconnected_client_app(&mut server);
+
+        server.update();
+        assert!(client_transport(&client).is_connecting());
+        assert!(server
+            .world
+            .resource::<RenetServer>()
+            .clients_id()
+            .is_empty());
+
+        client.update();
+        server.update();
+        assert_eq!(
+            server.world.resource::<RenetServer>().clients_id(),
+            [ClientId::from_raw(0)]
+        );
+        assert!(client_transport(&client).is_connecting());
+
+        client.update();
+        assert!(client_transport(&client).is_connected());
+        assert!(client.world.resource::<RenetClient>().is_connected());
+    }
+
+    #[test]
+    fn connection_run_conditions() {
+        #[derive(Debug, Default, Resource, PartialEq, Eq)]
+        struct Ran {
+            connecting: u32,
+            connected: u32,
+            disconnected: u32,
+            just_connected: u32,
+            just_disconnected: u32,
+        }
+
+        let mut server = create_server_app();
+        let mut client = create_unconnected_client_app(&mut server);
+        client.init_resource::<Ran>().add_systems(
+            Update,
+            (
+                (|mut ran: ResMut<Ran>| ran.connecting += 1).run_if(client_connecting()),
+                (|mut ran: ResMut<Ran>| ran.connected += 1).run_if(client_connected()),
+                (|mut ran: ResMut<Ran>| ran.disconnected += 1).run_if(client_disconnected()),
+                (|mut ran: ResMut<Ran>| ran.just_connected += 1).run_if(client_just_connected()),
+                (|mut ran: ResMut<Ran>| ran.just_disconnected += 1)
+                    .run_if(client_just_disconnected()),
+            ),
+        );
+
+        connect(&mut server, &mut client);
+        client.update();
+        server.world.send_event(AppExit);
+        server.update();
+        client.update();
+        client.update();
+
+        assert_eq!(
+            *client.world.resource::<Ran>(),
+            Ran {
+                connecting: 1,
+                connected: 2,
+                disconnected: 2,
+                just_connected: 1,
+                just_disconnected: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn disconnect_reasons() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        client.world.send_event(AppExit);
+        client.update();
+        server.update();
+
+        assert_eq!(
+            client_transport(&client).disconnect_reason(),
+            Some(DisconnectReason::DisconnectedByClient)
+        );
+        assert_eq!(
+            server
+                .world
+                .resource::<MemoryServerTransport>()
+                .disconnect_reason(ClientId::from_raw(0)),
+            Some(DisconnectReason::DisconnectedByClient)
+        );
+
+        let mut client = create_client_app(&mut server);
+        server
+            .world
+            .resource_mut::<RenetServer>()
+            .disconnect(ClientId::from_raw(1));
+        server.update();
+        client.update();
+
+        assert_eq!(
+            client_transport(&client).disconnect_reason(),
+            Some(DisconnectReason::DisconnectedByServer)
+        );
+        assert!(client.world.resource::<RenetClient>().is_disconnected());
+    }
+
+    #[test]
+    fn timeout() {
+        let (mut server, mut client) = create_apps_with(
+            MemoryServerTransport::default().with_timeout(Duration::from_millis(100)),
+        );
+        connect(&mut server, &mut client);
+
+        // Only the server keeps running, so it stops hearing from the client
+        for _ in 0..20 {
+            server.update();
+        }
+        assert!(server
+            .world
+            .resource::<RenetServer>()
+            .clients_id()
+            .is_empty());
+        assert_eq!(
+            server
+                .world
+                .resource::<MemoryServerTransport>()
+                .disconnect_reason(ClientId::from_raw(0)),
+            Some(DisconnectReason::TimedOut)
+        );
+
+        client.update();
+        assert!(client_transport(&client).is_disconnected());
+    }
+
+    #[test]
+    fn connection_events() {
+        let (mut server, mut client) = create_apps_with(
+            MemoryServerTransport::default().with_timeout(Duration::from_millis(100)),
+        );
+
+        #[derive(Debug, Default, Resource, Deref, DerefMut)]
+        struct Received(Vec<ConnectionEvent>);
+
+        server.init_resource::<Received>().add_systems(
+            Update,
+            |mut events: EventReader<ConnectionEvent>, mut received: ResMut<Received>| {
+                received.extend(events.read().cloned());
+            },
+        );
+
+        connect(&mut server, &mut client);
+        client.update();
+        assert_eq!(
+            client.world.resource::<ClientConnection>().state,
+            ConnectionState::Connected
+        );
+
+        for _ in 0..20 {
+            server.update();
+        }
+        let client_id = ClientId::from_raw(0);
+        assert_eq!(
+            server.world.resource::<Received>().0,
+            [
+                ConnectionEvent::ClientConnected {
+                    client_id,
+                    user_data: None
+                },
+                ConnectionEvent::ClientDisconnected {
+                    client_id,
+                    reason: DisconnectReason::TimedOut
+                },
+            ]
+        );
+
+        client.update();
+        assert_eq!(
+            client.world.resource::<ClientConnection>().state,
+            ConnectionState::Disconnected(DisconnectReason::ConnectionLost)
+        );
+    }
+
+    #[test]
+    fn disconnected_by_server_event() {
+        #[derive(Debug, Default, Resource, Deref, DerefMut)]
+        struct Received(Vec<ConnectionEvent>);
+
+        let mut server = create_server_app();
+        server.init_resource::<Received>().add_systems(
+            Update,
+            |mut events: EventReader<ConnectionEvent>, mut received: ResMut<Received>| {
+                received.extend(events.read().cloned());
+            },
+        );
+        let mut client = create_client_app(&mut server);
+        server.update();
+        server.world.resource_mut::<Received>().clear();
+
+        let client_id = ClientId::from_raw(0);
+        server
+            .world
+            .resource_mut::<RenetServer>()
+            .disconnect(client_id);
+        server.update();
+        server.update();
+        client.update();
+
+        assert_eq!(
+            server.world.resource::<Received>().0,
+            [ConnectionEvent::ClientDisconnected {
+                client_id,
+                reason: DisconnectReason::DisconnectedByServer
+            }]
+        );
+        assert!(server
+            .world
+            .resource::<RenetServer>()
+            .disconnections_id()
+            .is_empty());
+        assert_eq!(
+            client_transport(&client).disconnect_reason(),
+            Some(DisconnectReason::DisconnectedByServer)
+        );
+    }
+
+    #[test]
+    fn client_timeout() {
+        let (mut server, mut client) = create_apps_with(
+            MemoryServerTransport::default().with_timeout(Duration::from_millis(100)),
+        );
+        connect(&mut server, &mut client);
+
+        for _ in 0..20 {
+            client.update();
+        }
+        assert_eq!(
+            client_transport(&client).disconnect_reason(),
+            Some(DisconnectReason::TimedOut)
+        );
+        assert!(client.world.resource::<RenetClient>().is_disconnected());
+    }
+
+    #[test]
+    fn connecting_timeout() {
+        let (_server, mut client) = create_apps_with(
+            MemoryServerTransport::default().with_timeout(Duration::from_millis(100)),
+        );
+
+        for _ in 0..20 {
+            client.update();
+        }
+        assert_eq!(
+            client_transport(&client).disconnect_reason(),
+            Some(DisconnectReason::TimedOut)
+        );
+    }
+
+    #[test]
+    fn server_gone() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        drop(server);
+        client.update();
+
+        assert_eq!(
+            client_transport(&client).disconnect_reason(),
+            Some(DisconnectReason::ConnectionLost)
+        );
+    }
+
+    #[test]
+    fn reconnect() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        client.world.send_event(AppExit);
+        client.update();
+        server.update();
+        assert!(client_transport(&client).is_disconnected());
+
+        client.world.resource_mut::<Events<AppExit>>().clear();
+        client
+            .world
+            .insert_resource(RenetClient::new(ConnectionConfig::default()));
+        client
+            .world
+            .resource_mut::<MemoryClientTransport>()
+            .reconnect();
+        connect(&mut server, &mut client);
+
+        assert_eq!(
+            server.world.resource::<RenetServer>().clients_id(),
+            [ClientId::from_raw(0)]
+        );
+
+        server.add_systems(Update, |mut server: ResMut<RenetServer>| {
+            server.broadcast_message(DefaultChannel::ReliableOrdered, vec![1]);
+        });
+        server.update();
+        client.update();
+
+        assert_eq!(client_received(&client), [[1]]);
+    }
+
+    #[test]
+    fn reconnect_while_connected() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&mut server);
+
+        client
+            .world
+            .insert_resource(RenetClient::new(ConnectionConfig::default()));
+        client
+            .world
+            .resource_mut::<MemoryClientTransport>()
+            .reconnect();
+        connect(&mut server, &mut client);
+
+        assert_eq!(
+            server.world.resource::<RenetServer>().clients_id(),
+            [ClientId::from_raw(0)]
+        );
+        assert_eq!(
+            server
+                .world
+                .resource::<MemoryServerTransport>()
+                .disconnect_reason(ClientId::from_raw(0)),
+            Some(DisconnectReason::Reconnected)
+        );
+    }
+
+    #[test]
+    fn reconnect_in_the_same_update() {
+        #[derive(Debug, Default, Resource, Deref, DerefMut)]
+        struct Received(Vec<ConnectionEvent>);
+
+        let mut server = create_server_app();
+        server.init_resource::<Received>().add_systems(
+            Update,
+            |mut events: EventReader<ConnectionEvent>, mut received: ResMut<Received>| {
+                received.extend(events.read().cloned());
+            },
+        );
+        let mut client = create_client_app(&mut server);
+        // The transport runs after renet, so its events come out in the next update
+        server.update();
+        server.world.resource_mut::<Received>().clear();
+
+        client
+            .world
+            .insert_resource(RenetClient::new(ConnectionConfig::default()));
+        client
+            .world
+            .resource_mut::<MemoryClientTransport>()
+            .reconnect();
+        // The old connection is dropped and the new one accepted in the same update
+        client.update();
+        server.update();
+        server.update();
+
+        let client_id = ClientId::from_raw(0);
+        assert_eq!(
+            server.world.resource::<Received>().0,
+            [
+                ConnectionEvent::ClientDisconnected {
+                    client_id,
+                    reason: DisconnectReason::Reconnected
+                },
+                ConnectionEvent::ClientConnected {
+                    client_id,
+                    user_data: None
+                },
+            ]
+        );
+    }
+}
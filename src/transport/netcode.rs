@@ -0,0 +1,191 @@
+use bevy::prelude::*;
+use bevy_renet::renet::transport::{
+    NetcodeClientTransport, NetcodeDisconnectReason, NetcodeError, NetcodeServerTransport,
+    NetcodeTransportError, NETCODE_USER_DATA_BYTES,
+};
+use bevy_renet::renet::{ClientId, RenetClient};
+use bevy_renet::transport::{NetcodeClientPlugin, NetcodeServerPlugin};
+
+use super::{
+    ClientTransport, ClientTransportPlugin, ConnectionState, DisconnectReason, ServerTransport,
+    ServerTransportPlugin,
+};
+
+/// Connects clients to the server over UDP using renet's netcode transport
+pub struct NetcodeTransportPlugin;
+
+impl Plugin for NetcodeTransportPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            NetcodeServerPlugin,
+            NetcodeClientPlugin,
+            ServerTransportPlugin::<NetcodeServerTransport>::default(),
+            ClientTransportPlugin::<NetcodeClientTransport>::default(),
+        ))
+        .add_systems(Update, log_transport_errors);
+    }
+}
+
+impl From<NetcodeDisconnectReason> for DisconnectReason {
+    fn from(reason: NetcodeDisconnectReason) -> Self {
+        match reason {
+            NetcodeDisconnectReason::ConnectTokenExpired
+            | NetcodeDisconnectReason::ConnectionDenied => DisconnectReason::ConnectionDenied,
+            NetcodeDisconnectReason::ConnectionTimedOut
+            | NetcodeDisconnectReason::ConnectionResponseTimedOut
+            | NetcodeDisconnectReason::ConnectionRequestTimedOut => DisconnectReason::TimedOut,
+            NetcodeDisconnectReason::DisconnectedByClient => DisconnectReason::DisconnectedByClient,
+            NetcodeDisconnectReason::DisconnectedByServer => DisconnectReason::DisconnectedByServer,
+        }
+    }
+}
+
+impl ClientTransport for NetcodeClientTransport {
+    fn connection_state(&self, client: &RenetClient) -> ConnectionState {
+        if let Some(reason) = self.disconnect_reason() {
+            ConnectionState::Disconnected(reason.into())
+        } else if let Some(reason) = client.disconnect_reason() {
+            ConnectionState::Disconnected(reason.into())
+        } else if client.is_connected() {
+            ConnectionState::Connected
+        } else {
+            ConnectionState::Connecting
+        }
+    }
+}
+
+impl ServerTransport for NetcodeServerTransport {
+    fn disconnect_reason(&self, _client_id: ClientId) -> Option<DisconnectReason> {
+        None
+    }
+
+    fn user_data(&self, client_id: ClientId) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
+        NetcodeServerTransport::user_data(self, client_id)
+    }
+}
+
+/// Disconnects already show up in the client's [`super::ClientConnection`] and the server's
+/// [`super::ConnectionEvent`]s, and the client keeps reporting them every frame after, so only the
+/// other errors are logged
+fn log_transport_errors(mut errors: EventReader<NetcodeTransportError>) {
+    for error in errors.read() {
+        match error {
+            NetcodeTransportError::Netcode(NetcodeError::Disconnected(_))
+            | NetcodeTransportError::Renet(_) => {}
+            error => println!("Transport error: {error}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+    use std::thread;
+    use std::time::{Duration, Instant, SystemTime};
+
+    use bevy_renet::renet::transport::{ClientAuthentication, ServerAuthentication, ServerConfig};
+    use bevy_renet::renet::{ConnectionConfig, RenetServer};
+    use bevy_renet::{RenetClientPlugin, RenetServerPlugin};
+
+    use crate::transport::ClientConnection;
+
+    use super::*;
+
+    const PROTOCOL_ID: u64 = 7;
+
+    fn create_server_app() -> App {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = ServerConfig {
+            current_time: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap(),
+            max_clients: 4,
+            protocol_id: PROTOCOL_ID,
+            public_addresses: vec![socket.local_addr().unwrap()],
+            authentication: ServerAuthentication::Unsecure,
+        };
+
+        let mut server = App::new();
+        server
+            .add_plugins((MinimalPlugins, RenetServerPlugin, NetcodeTransportPlugin))
+            .insert_resource(RenetServer::new(ConnectionConfig::default()))
+            .insert_resource(NetcodeServerTransport::new(config, socket).unwrap());
+        server
+    }
+
+    fn create_client_app(server: &App) -> App {
+        let authentication = ClientAuthentication::Unsecure {
+            protocol_id: PROTOCOL_ID,
+            client_id: 1,
+            server_addr: server
+                .world
+                .resource::<NetcodeServerTransport>()
+                .addresses()[0],
+            user_data: None,
+        };
+        let current_time = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap();
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+
+        let mut client = App::new();
+        client
+            .add_plugins((MinimalPlugins, RenetClientPlugin, NetcodeTransportPlugin))
+            .insert_resource(RenetClient::new(ConnectionConfig::default()))
+            .insert_resource(
+                NetcodeClientTransport::new(current_time, authentication, socket).unwrap(),
+            );
+        client
+    }
+
+    fn client_state(client: &App) -> Option<ConnectionState> {
+        client
+            .world
+            .get_resource::<ClientConnection>()
+            .map(|connection| connection.state)
+    }
+
+    /// Updates both apps until the client is in `state`, and a few frames more, since the sockets
+    /// are real
+    fn update_until(server: &mut App, client: &mut App, state: ConnectionState) {
+        let start = Instant::now();
+        while client_state(client) != Some(state) {
+            assert!(start.elapsed() < Duration::from_secs(1), "Timed out");
+            client.update();
+            server.update();
+            thread::sleep(Duration::from_millis(5));
+        }
+        for _ in 0..5 {
+            client.update();
+            server.update();
+        }
+    }
+
+    #[test]
+    fn leaving_is_not_an_error() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, ConnectionState::Connected);
+
+        client.world.resource_mut::<RenetClient>().disconnect();
+        update_until(
+            &mut server,
+            &mut client,
+            ConnectionState::Disconnected(DisconnectReason::DisconnectedByClient),
+        );
+    }
+
+    #[test]
+    fn being_dropped_is_not_an_error() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, ConnectionState::Connected);
+
+        server.world.resource_mut::<RenetServer>().disconnect_all();
+        update_until(
+            &mut server,
+            &mut client,
+            ConnectionState::Disconnected(DisconnectReason::DisconnectedByServer),
+        );
+    }
+}
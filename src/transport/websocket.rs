@@ -0,0 +1,947 @@
+use std::io::ErrorKind;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SendError, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+use bevy::app::AppExit;
+use bevy::prelude::*;
+use bevy::utils::synccell::SyncCell;
+use bevy::utils::HashMap;
+use bevy_renet::renet::transport::NETCODE_USER_DATA_BYTES;
+use bevy_renet::renet::{ClientId, RenetClient, RenetServer};
+use bevy_renet::{RenetClientPlugin, RenetReceive, RenetSend, RenetServerPlugin};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+use tungstenite::http::{HeaderValue, StatusCode};
+use tungstenite::protocol::Role;
+use tungstenite::{Message, WebSocket};
+
+use super::{
+    ClientTransport, ClientTransportPlugin, ConnectionState, DisconnectReason, ServerTransport,
+    ServerTransportPlugin,
+};
+
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many keepalives an idle connection sends within the timeout
+const KEEPALIVES_PER_TIMEOUT: u32 = 4;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+/// The handshake response header with the id the server gave the client
+const CLIENT_ID_HEADER: &str = "x-client-id";
+/// The handshake request header with the client's user data in hex, like netcode's user data
+const USER_DATA_HEADER: &str = "x-user-data";
+
+#[derive(Debug, Serialize, Deserialize)]
+enum WebSocketPacket {
+    Payload(Vec<u8>),
+    Disconnect(DisconnectReason),
+    KeepAlive,
+}
+
+/// The game's end of a WebSocket, which lives on its own threads
+struct Connection {
+    sender: Sender<Vec<u8>>,
+    receiver: Receiver<Result<Vec<u8>, DisconnectReason>>,
+}
+
+impl Connection {
+    fn send(&self, packet: WebSocketPacket) -> Result<(), SendError<Vec<u8>>> {
+        self.sender.send(bincode::serialize(&packet).unwrap())
+    }
+
+    /// Everything received since the last call, or why the connection ended: it timed out or the
+    /// socket was closed
+    fn receive(&self) -> Result<Vec<WebSocketPacket>, DisconnectReason> {
+        let mut packets = vec![];
+
+        loop {
+            match self.receiver.try_recv() {
+                Ok(Ok(packet)) => match bincode::deserialize(&packet) {
+                    Ok(packet) => packets.push(packet),
+                    Err(_) => return Err(DisconnectReason::ProtocolError),
+                },
+                Ok(Err(reason)) => return Err(reason),
+                Err(TryRecvError::Empty) => return Ok(packets),
+                Err(TryRecvError::Disconnected) if packets.is_empty() => {
+                    return Err(DisconnectReason::ConnectionLost)
+                }
+                // Handle what arrived first, the loss is noticed on the next update
+                Err(TryRecvError::Disconnected) => return Ok(packets),
+            }
+        }
+    }
+}
+
+/// Moves messages between the socket and the returned [`Connection`] on background threads, one
+/// blocked on reading and one on what the game sends. The connection times out when nothing, not
+/// even a keepalive, arrives for `timeout`. The socket is closed once the [`Connection`] is dropped.
+fn spawn_socket_threads(
+    mut socket: WebSocket<TcpStream>,
+    role: Role,
+    timeout: Duration,
+) -> std::io::Result<Connection> {
+    let (to_game, from_socket) = mpsc::channel();
+    let (to_socket, from_game) = mpsc::channel::<Vec<u8>>();
+
+    // The reader keeps the socket, which may hold data that came with the handshake
+    socket.get_ref().set_read_timeout(Some(timeout))?;
+    let mut writer = WebSocket::from_raw_socket(socket.get_ref().try_clone()?, role, None);
+
+    thread::spawn(move || loop {
+        let received = match socket.read() {
+            Ok(Message::Binary(packet)) => Ok(packet),
+            Ok(Message::Close(_)) => break,
+            Ok(_) => continue,
+            Err(tungstenite::Error::Io(e))
+                if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) =>
+            {
+                Err(DisconnectReason::TimedOut)
+            }
+            Err(_) => break,
+        };
+        let timed_out = received.is_err();
+        if to_game.send(received).is_err() || timed_out {
+            break;
+        }
+    });
+
+    thread::spawn(move || {
+        let keepalive = bincode::serialize(&WebSocketPacket::KeepAlive).unwrap();
+
+        loop {
+            let packet = match from_game.recv_timeout(timeout / KEEPALIVES_PER_TIMEOUT) {
+                Ok(packet) => packet,
+                Err(RecvTimeoutError::Timeout) => keepalive.clone(),
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = writer.close(None);
+                    let _ = writer.flush();
+                    break;
+                }
+            };
+            if writer.send(Message::Binary(packet)).is_err() {
+                break;
+            }
+        }
+
+        // Wakes up the reader
+        let _ = writer.get_ref().shutdown(Shutdown::Both);
+    });
+
+    Ok(Connection {
+        sender: to_socket,
+        receiver: from_socket,
+    })
+}
+
+/// Accepts WebSocket connections on `ws://<addr>/`. The server gives every connection a new client
+/// id, so that nobody can connect as someone else.
+#[derive(Resource)]
+pub struct WebSocketServerTransport {
+    addr: SocketAddr,
+    timeout: Duration,
+    new_connections: SyncCell<Receiver<Accepted>>,
+    connections: HashMap<ClientId, SyncCell<Connection>>,
+    user_data: HashMap<ClientId, [u8; NETCODE_USER_DATA_BYTES]>,
+    disconnect_reasons: HashMap<ClientId, DisconnectReason>,
+}
+
+/// A client that finished the handshake
+struct Accepted {
+    client_id: ClientId,
+    user_data: Option<[u8; NETCODE_USER_DATA_BYTES]>,
+    socket: WebSocket<TcpStream>,
+}
+
+impl WebSocketServerTransport {
+    pub fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        let addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel();
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                let sender = sender.clone();
+
+                // A slow handshake should not hold up everyone else
+                thread::spawn(move || {
+                    if let Some(accepted) = accept(stream) {
+                        let _ = sender.send(accepted);
+                    }
+                });
+            }
+        });
+
+        Ok(WebSocketServerTransport {
+            addr,
+            timeout: DEFAULT_TIMEOUT,
+            new_connections: SyncCell::new(receiver),
+            connections: default(),
+            user_data: default(),
+            disconnect_reasons: default(),
+        })
+    }
+
+    /// Disconnect clients that have not sent anything for this long. Applies to connections
+    /// accepted afterwards.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// What the client sent in its handshake, if it is connected
+    pub fn user_data(&self, client_id: ClientId) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
+        self.user_data.get(&client_id).copied()
+    }
+
+    /// Why the client was last disconnected, as seen from the server
+    pub fn disconnect_reason(&self, client_id: ClientId) -> Option<DisconnectReason> {
+        self.disconnect_reasons.get(&client_id).copied()
+    }
+
+    fn update(&mut self, server: &mut RenetServer) {
+        for accepted in self.new_connections.get().try_iter().collect_vec() {
+            let client_id = accepted.client_id;
+            // Dropping the socket closes it, whoever has the id keeps it
+            if self.connections.contains_key(&client_id) {
+                continue;
+            }
+            let Ok(connection) = spawn_socket_threads(accepted.socket, Role::Server, self.timeout)
+            else {
+                continue;
+            };
+            self.connections
+                .insert(client_id, SyncCell::new(connection));
+            if let Some(user_data) = accepted.user_data {
+                self.user_data.insert(client_id, user_data);
+            }
+            self.disconnect_reasons.remove(&client_id);
+            server.add_connection(client_id);
+        }
+
+        let mut to_disconnect = vec![];
+
+        for (&client_id, connection) in self.connections.iter_mut() {
+            match connection.get().receive() {
+                Ok(packets) => {
+                    for packet in packets {
+                        match packet {
+                            WebSocketPacket::Payload(payload) => {
+                                // Fails only if the game already disconnected the client
+                                let _ = server.process_packet_from(&payload, client_id);
+                            }
+                            WebSocketPacket::Disconnect(reason) => {
+                                to_disconnect.push((client_id, reason));
+                            }
+                            WebSocketPacket::KeepAlive => (),
+                        }
+                    }
+                }
+                Err(reason) => to_disconnect.push((client_id, reason)),
+            }
+        }
+
+        for (client_id, reason) in to_disconnect {
+            self.remove_client(client_id, reason, server);
+        }
+    }
+
+    fn send_packets(&mut self, server: &mut RenetServer) {
+        for client_id in server.disconnections_id() {
+            if let Some(mut connection) = self.connections.remove(&client_id) {
+                let reason = DisconnectReason::DisconnectedByServer;
+                let _ = connection.get().send(WebSocketPacket::Disconnect(reason));
+                self.user_data.remove(&client_id);
+                self.disconnect_reasons.insert(client_id, reason);
+            }
+            // Sends the disconnection event
+            server.remove_connection(client_id);
+        }
+
+        let mut lost = vec![];
+
+        for (&client_id, connection) in self.connections.iter_mut() {
+            let connection = connection.get();
+
+            let packets = server.get_packets_to_send(client_id).unwrap_or_default();
+            if packets
+                .into_iter()
+                .try_for_each(|packet| connection.send(WebSocketPacket::Payload(packet)))
+                .is_err()
+            {
+                lost.push(client_id);
+            }
+        }
+
+        for client_id in lost {
+            self.remove_client(client_id, DisconnectReason::ConnectionLost, server);
+        }
+    }
+
+    fn remove_client(
+        &mut self,
+        client_id: ClientId,
+        reason: DisconnectReason,
+        server: &mut RenetServer,
+    ) {
+        self.connections.remove(&client_id);
+        self.user_data.remove(&client_id);
+        self.disconnect_reasons.insert(client_id, reason);
+        server.remove_connection(client_id);
+    }
+
+    fn disconnect_all(&mut self, server: &mut RenetServer) {
+        let reason = DisconnectReason::DisconnectedByServer;
+
+        for (client_id, mut connection) in self.connections.drain() {
+            let _ = connection.get().send(WebSocketPacket::Disconnect(reason));
+            self.disconnect_reasons.insert(client_id, reason);
+        }
+        self.user_data.clear();
+
+        server.disconnect_all();
+    }
+}
+
+/// Does the server side of the handshake, telling the client the id it gets. Clients that send
+/// malformed user data are turned away.
+// The error type of the handshake callback is decided by tungstenite
+#[allow(clippy::result_large_err)]
+fn accept(stream: TcpStream) -> Option<Accepted> {
+    stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)).ok()?;
+    stream.set_nodelay(true).ok()?;
+
+    let client_id = ClientId::from_raw(rand::random());
+    let mut user_data = None;
+    let socket = tungstenite::accept_hdr(stream, |request: &Request, mut response: Response| {
+        if let Some(header) = request.headers().get(USER_DATA_HEADER) {
+            match header
+                .to_str()
+                .map_err(|e| e.to_string())
+                .and_then(parse_user_data)
+            {
+                Ok(sent) => user_data = Some(sent),
+                Err(e) => {
+                    let mut error = ErrorResponse::new(Some(format!("Invalid user data: {e}")));
+                    *error.status_mut() = StatusCode::BAD_REQUEST;
+                    return Err(error);
+                }
+            }
+        }
+        response
+            .headers_mut()
+            .insert(CLIENT_ID_HEADER, HeaderValue::from(client_id.raw()));
+        Ok(response)
+    })
+    .ok()?;
+
+    Some(Accepted {
+        client_id,
+        user_data,
+        socket,
+    })
+}
+
+fn user_data_to_hex(user_data: &[u8; NETCODE_USER_DATA_BYTES]) -> String {
+    user_data.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn parse_user_data(hex: &str) -> Result<[u8; NETCODE_USER_DATA_BYTES], String> {
+    if hex.len() != 2 * NETCODE_USER_DATA_BYTES || !hex.is_ascii() {
+        return Err(format!(
+            "Expected {} hex digits, got {}",
+            2 * NETCODE_USER_DATA_BYTES,
+            hex.len()
+        ));
+    }
+
+    let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+    for (byte, digits) in user_data.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let digits = std::str::from_utf8(digits).unwrap();
+        *byte =
+            u8::from_str_radix(digits, 16).map_err(|e| format!("Invalid hex '{digits}': {e}"))?;
+    }
+
+    Ok(user_data)
+}
+
+#[derive(Resource)]
+pub struct WebSocketClientTransport {
+    /// Given by the server once connected
+    client_id: Option<ClientId>,
+    /// Receives the socket once the handshake is done
+    connecting: Option<SyncCell<Receiver<(ClientId, WebSocket<TcpStream>)>>>,
+    connection: Option<SyncCell<Connection>>,
+    state: ConnectionState,
+    timeout: Duration,
+}
+
+impl WebSocketClientTransport {
+    /// Starts connecting to a [`WebSocketServerTransport`] in the background, sending
+    /// `user_data` like a netcode client would
+    pub fn connect(
+        server_addr: SocketAddr,
+        user_data: Option<[u8; NETCODE_USER_DATA_BYTES]>,
+    ) -> Self {
+        let (sender, receiver) = mpsc::channel();
+
+        let mut request = format!("ws://{server_addr}/")
+            .into_client_request()
+            .unwrap();
+        if let Some(user_data) = user_data {
+            // Hex digits are always a valid header value
+            let header = HeaderValue::from_str(&user_data_to_hex(&user_data)).unwrap();
+            request.headers_mut().insert(USER_DATA_HEADER, header);
+        }
+
+        thread::spawn(move || {
+            let Ok(stream) = TcpStream::connect_timeout(&server_addr, HANDSHAKE_TIMEOUT) else {
+                return;
+            };
+            let _ = stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT));
+            let _ = stream.set_nodelay(true);
+
+            let Ok((socket, response)) = tungstenite::client(request, stream) else {
+                return;
+            };
+            let client_id = response
+                .headers()
+                .get(CLIENT_ID_HEADER)
+                .and_then(|id| id.to_str().ok()?.parse().ok());
+            if let Some(client_id) = client_id {
+                let _ = sender.send((ClientId::from_raw(client_id), socket));
+            }
+        });
+
+        WebSocketClientTransport {
+            client_id: None,
+            connecting: Some(SyncCell::new(receiver)),
+            connection: None,
+            state: ConnectionState::Connecting,
+            timeout: DEFAULT_TIMEOUT,
+        }
+    }
+
+    /// Disconnect if the server has not sent anything for this long
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    fn update(&mut self, client: &mut RenetClient) {
+        if let Some(ref mut connecting) = self.connecting {
+            match connecting.get().try_recv() {
+                Ok((client_id, socket)) => {
+                    let Ok(connection) = spawn_socket_threads(socket, Role::Client, self.timeout)
+                    else {
+                        return self.close(client, DisconnectReason::ConnectionLost);
+                    };
+                    self.client_id = Some(client_id);
+                    self.connecting = None;
+                    self.connection = Some(SyncCell::new(connection));
+                    self.state = ConnectionState::Connected;
+                    client.set_connected();
+                    // What the server sent waits for the next update, so that the game can take
+                    // on the id before
+                    return;
+                }
+                Err(TryRecvError::Empty) => return,
+                Err(TryRecvError::Disconnected) => {
+                    return self.close(client, DisconnectReason::ConnectionLost)
+                }
+            }
+        }
+
+        let Some(ref mut connection) = self.connection else {
+            return;
+        };
+
+        let packets = match connection.get().receive() {
+            Ok(packets) => packets,
+            Err(reason) => return self.close(client, reason),
+        };
+
+        for packet in packets {
+            match packet {
+                WebSocketPacket::Payload(payload) => client.process_packet(&payload),
+                WebSocketPacket::Disconnect(reason) => return self.close(client, reason),
+                WebSocketPacket::KeepAlive => (),
+            }
+        }
+    }
+
+    fn send_packets(&mut self, client: &mut RenetClient) {
+        if client.is_disconnected() && self.connection.is_some() {
+            return self.disconnect(client);
+        }
+
+        let Some(ref mut connection) = self.connection else {
+            return;
+        };
+        let connection = connection.get();
+
+        if client
+            .get_packets_to_send()
+            .into_iter()
+            .try_for_each(|packet| connection.send(WebSocketPacket::Payload(packet)))
+            .is_err()
+        {
+            self.close(client, DisconnectReason::ConnectionLost);
+        }
+    }
+
+    /// Tells the server we are leaving before closing the connection
+    fn disconnect(&mut self, client: &mut RenetClient) {
+        if let Some(ref mut connection) = self.connection {
+            let reason = DisconnectReason::DisconnectedByClient;
+            let _ = connection.get().send(WebSocketPacket::Disconnect(reason));
+        }
+
+        client.disconnect();
+        self.close(client, DisconnectReason::DisconnectedByClient);
+    }
+
+    fn close(&mut self, client: &mut RenetClient, reason: DisconnectReason) {
+        self.connecting.take();
+        self.connection.take();
+        self.state = ConnectionState::Disconnected(reason);
+        client.disconnect_due_to_transport();
+    }
+
+    /// The id the server gave this client, once it is connected
+    pub fn client_id(&self) -> Option<ClientId> {
+        self.client_id
+    }
+
+    pub fn state(&self) -> ConnectionState {
+        self.state
+    }
+}
+
+impl ServerTransport for WebSocketServerTransport {
+    fn disconnect_reason(&self, client_id: ClientId) -> Option<DisconnectReason> {
+        WebSocketServerTransport::disconnect_reason(self, client_id)
+    }
+
+    fn user_data(&self, client_id: ClientId) -> Option<[u8; NETCODE_USER_DATA_BYTES]> {
+        WebSocketServerTransport::user_data(self, client_id)
+    }
+}
+
+impl ClientTransport for WebSocketClientTransport {
+    fn connection_state(&self, _client: &RenetClient) -> ConnectionState {
+        self.state
+    }
+}
+
+/// Connects clients to the server over WebSockets, which works through HTTP proxies
+pub struct WebSocketTransportPlugin;
+
+impl Plugin for WebSocketTransportPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            ServerTransportPlugin::<WebSocketServerTransport>::default(),
+            ClientTransportPlugin::<WebSocketClientTransport>::default(),
+        ))
+        .add_systems(
+            PreUpdate,
+            (
+                update_server
+                    .run_if(resource_exists::<RenetServer>())
+                    .run_if(resource_exists::<WebSocketServerTransport>())
+                    .after(RenetServerPlugin::update_system),
+                update_client
+                    .run_if(resource_exists::<RenetClient>())
+                    .run_if(resource_exists::<WebSocketClientTransport>())
+                    .after(RenetClientPlugin::update_system),
+            )
+                .in_set(RenetReceive),
+        )
+        .add_systems(
+            PostUpdate,
+            (
+                (
+                    send_server_packets.in_set(RenetSend),
+                    disconnect_server_on_exit,
+                )
+                    .run_if(resource_exists::<RenetServer>())
+                    .run_if(resource_exists::<WebSocketServerTransport>()),
+                (
+                    send_client_packets.in_set(RenetSend),
+                    disconnect_client_on_exit,
+                )
+                    .run_if(resource_exists::<RenetClient>())
+                    .run_if(resource_exists::<WebSocketClientTransport>()),
+            ),
+        );
+    }
+}
+
+fn update_server(mut transport: ResMut<WebSocketServerTransport>, mut server: ResMut<RenetServer>) {
+    transport.update(&mut server);
+}
+
+fn send_server_packets(
+    mut transport: ResMut<WebSocketServerTransport>,
+    mut server: ResMut<RenetServer>,
+) {
+    transport.send_packets(&mut server);
+}
+
+fn disconnect_server_on_exit(
+    exit: EventReader<AppExit>,
+    mut transport: ResMut<WebSocketServerTransport>,
+    mut server: ResMut<RenetServer>,
+) {
+    if !exit.is_empty() {
+        transport.disconnect_all(&mut server);
+    }
+}
+
+fn update_client(mut transport: ResMut<WebSocketClientTransport>, mut client: ResMut<RenetClient>) {
+    transport.update(&mut client);
+}
+
+fn send_client_packets(
+    mut transport: ResMut<WebSocketClientTransport>,
+    mut client: ResMut<RenetClient>,
+) {
+    transport.send_packets(&mut client);
+}
+
+fn disconnect_client_on_exit(
+    exit: EventReader<AppExit>,
+    mut transport: ResMut<WebSocketClientTransport>,
+    mut client: ResMut<RenetClient>,
+) {
+    if !exit.is_empty() {
+        transport.disconnect(&mut client);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+
+    use bevy_renet::renet::{ConnectionConfig, DefaultChannel};
+
+    use super::*;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(1);
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn create_server_app() -> App {
+        let transport = WebSocketServerTransport::bind("127.0.0.1:0".parse().unwrap())
+            .unwrap()
+            .with_timeout(TIMEOUT);
+
+        let mut server = App::new();
+        server
+            .add_plugins((MinimalPlugins, RenetServerPlugin, WebSocketTransportPlugin))
+            .insert_resource(RenetServer::new(ConnectionConfig::default()))
+            .insert_resource(transport);
+
+        server
+    }
+
+    fn create_client_app(server: &App) -> App {
+        create_client_app_with_user_data(server, None)
+    }
+
+    fn create_client_app_with_user_data(
+        server: &App,
+        user_data: Option<[u8; NETCODE_USER_DATA_BYTES]>,
+    ) -> App {
+        let addr = server.world.resource::<WebSocketServerTransport>().addr();
+        let transport = WebSocketClientTransport::connect(addr, user_data).with_timeout(TIMEOUT);
+
+        let mut client = App::new();
+        client
+            .add_plugins((MinimalPlugins, RenetClientPlugin, WebSocketTransportPlugin))
+            .insert_resource(RenetClient::new(ConnectionConfig::default()))
+            .insert_resource(transport);
+
+        client
+    }
+
+    /// Updates both apps until `done` or a second has passed, since the sockets are real
+    fn update_until(server: &mut App, client: &mut App, done: impl Fn(&App, &App) -> bool) {
+        let start = Instant::now();
+        while !done(server, client) {
+            assert!(start.elapsed() < Duration::from_secs(1), "Timed out");
+
+            client.update();
+            server.update();
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn connected_clients(server: &App) -> Vec<ClientId> {
+        server.world.resource::<RenetServer>().clients_id()
+    }
+
+    fn client_state(client: &App) -> ConnectionState {
+        client.world.resource::<WebSocketClientTransport>().state()
+    }
+
+    fn client_id(client: &App) -> ClientId {
+        client
+            .world
+            .resource::<WebSocketClientTransport>()
+            .client_id()
+            .unwrap()
+    }
+
+    #[test]
+    fn send_both_ways() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+
+        update_until(&mut server, &mut client, |server, client| {
+            client_state(client) == ConnectionState::Connected
+                && !connected_clients(server).is_empty()
+        });
+        let client_id = client_id(&client);
+        assert_eq!(connected_clients(&server), [client_id]);
+
+        client
+            .world
+            .resource_mut::<RenetClient>()
+            .send_message(DefaultChannel::ReliableOrdered, vec![1, 2, 3]);
+        server.world.resource_mut::<RenetServer>().send_message(
+            client_id,
+            DefaultChannel::ReliableOrdered,
+            vec![4, 5],
+        );
+
+        let mut from_client = None;
+        let mut from_server = None;
+        let start = Instant::now();
+        while from_client.is_none() || from_server.is_none() {
+            assert!(start.elapsed() < Duration::from_secs(1), "Timed out");
+
+            client.update();
+            server.update();
+            thread::sleep(POLL_INTERVAL);
+
+            from_client = from_client.or_else(|| {
+                server
+                    .world
+                    .resource_mut::<RenetServer>()
+                    .receive_message(client_id, DefaultChannel::ReliableOrdered)
+            });
+            from_server = from_server.or_else(|| {
+                client
+                    .world
+                    .resource_mut::<RenetClient>()
+                    .receive_message(DefaultChannel::ReliableOrdered)
+            });
+        }
+
+        assert_eq!(from_client.unwrap(), [1, 2, 3].as_slice());
+        assert_eq!(from_server.unwrap(), [4, 5].as_slice());
+    }
+
+    #[test]
+    fn disconnect_reasons() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, |server, client| {
+            client_state(client) == ConnectionState::Connected
+                && !connected_clients(server).is_empty()
+        });
+        let first_id = client_id(&client);
+
+        client.world.send_event(AppExit);
+        update_until(&mut server, &mut client, |server, _| {
+            connected_clients(server).is_empty()
+        });
+        assert_eq!(
+            client_state(&client),
+            ConnectionState::Disconnected(DisconnectReason::DisconnectedByClient)
+        );
+        assert_eq!(
+            server
+                .world
+                .resource::<WebSocketServerTransport>()
+                .disconnect_reason(first_id),
+            Some(DisconnectReason::DisconnectedByClient)
+        );
+
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, |server, client| {
+            client_state(client) == ConnectionState::Connected
+                && !connected_clients(server).is_empty()
+        });
+        let second_id = client_id(&client);
+        server
+            .world
+            .resource_mut::<RenetServer>()
+            .disconnect(second_id);
+        update_until(&mut server, &mut client, |_, client| {
+            client_state(client)
+                == ConnectionState::Disconnected(DisconnectReason::DisconnectedByServer)
+        });
+        assert!(server
+            .world
+            .resource::<RenetServer>()
+            .disconnections_id()
+            .is_empty());
+    }
+
+    #[test]
+    fn server_gone() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, |server, _| {
+            !connected_clients(server).is_empty()
+        });
+
+        drop(server);
+        let start = Instant::now();
+        while client_state(&client) == ConnectionState::Connected {
+            assert!(start.elapsed() < Duration::from_secs(1), "Timed out");
+            client.update();
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        assert_eq!(
+            client_state(&client),
+            ConnectionState::Disconnected(DisconnectReason::ConnectionLost)
+        );
+    }
+
+    #[test]
+    fn clients_cannot_pick_their_id() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, |server, client| {
+            client_state(client) == ConnectionState::Connected
+                && !connected_clients(server).is_empty()
+        });
+        let client_id = client_id(&client);
+
+        // Asking for the id of someone else, the way clients used to pick theirs
+        let addr = server.world.resource::<WebSocketServerTransport>().addr();
+        let stream = TcpStream::connect(addr).unwrap();
+        let (_socket, response) =
+            tungstenite::client(format!("ws://{addr}/{}", client_id.raw()), stream).unwrap();
+        assert_ne!(
+            response.headers()[CLIENT_ID_HEADER],
+            client_id.raw().to_string()
+        );
+
+        update_until(&mut server, &mut client, |server, _| {
+            connected_clients(server).len() == 2
+        });
+        assert_eq!(client_state(&client), ConnectionState::Connected);
+        assert!(connected_clients(&server).contains(&client_id));
+    }
+
+    #[test]
+    fn silent_clients_time_out() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, |server, _| {
+            !connected_clients(server).is_empty()
+        });
+        let client_id = client_id(&client);
+
+        // Like a half-open connection, the socket stays open without anything coming through
+        let addr = server.world.resource::<WebSocketServerTransport>().addr();
+        let stream = TcpStream::connect(addr).unwrap();
+        let (socket, response) = tungstenite::client(format!("ws://{addr}/"), stream).unwrap();
+        let silent_id = ClientId::from_raw(
+            response.headers()[CLIENT_ID_HEADER]
+                .to_str()
+                .unwrap()
+                .parse()
+                .unwrap(),
+        );
+        update_until(&mut server, &mut client, |server, _| {
+            connected_clients(server).len() == 2
+        });
+
+        let start = Instant::now();
+        while connected_clients(&server).len() == 2 {
+            assert!(start.elapsed() < TIMEOUT * 3, "Never timed out");
+            client.update();
+            server.update();
+            thread::sleep(POLL_INTERVAL);
+        }
+        drop(socket);
+
+        assert_eq!(connected_clients(&server), [client_id]);
+        assert_eq!(
+            server
+                .world
+                .resource::<WebSocketServerTransport>()
+                .disconnect_reason(silent_id),
+            Some(DisconnectReason::TimedOut)
+        );
+    }
+
+    #[test]
+    fn idle_clients_stay_connected() {
+        let mut server = create_server_app();
+        let mut client = create_client_app(&server);
+        update_until(&mut server, &mut client, |server, client| {
+            client_state(client) == ConnectionState::Connected
+                && !connected_clients(server).is_empty()
+        });
+
+        let start = Instant::now();
+        while start.elapsed() < TIMEOUT * 3 {
+            client.update();
+            server.update();
+            thread::sleep(POLL_INTERVAL);
+        }
+
+        assert_eq!(client_state(&client), ConnectionState::Connected);
+        assert_eq!(connected_clients(&server), [client_id(&client)]);
+    }
+
+    #[test]
+    fn user_data_comes_with_the_handshake() {
+        let mut user_data = [0; NETCODE_USER_DATA_BYTES];
+        user_data[0] = 1;
+        user_data[NETCODE_USER_DATA_BYTES - 1] = 0xff;
+
+        let mut server = create_server_app();
+        let mut client = create_client_app_with_user_data(&server, Some(user_data));
+        let mut anonymous = create_client_app(&server);
+        update_until(&mut server, &mut client, |_, client| {
+            client_state(client) == ConnectionState::Connected
+        });
+        update_until(&mut server, &mut anonymous, |server, anonymous| {
+            client_state(anonymous) == ConnectionState::Connected
+                && connected_clients(server).len() == 2
+        });
+
+        let transport = server.world.resource::<WebSocketServerTransport>();
+        assert_eq!(transport.user_data(client_id(&client)), Some(user_data));
+        assert_eq!(transport.user_data(client_id(&anonymous)), None);
+    }
+
+    #[test]
+    fn malformed_user_data_is_rejected() {
+        let server = create_server_app();
+        let addr = server.world.resource::<WebSocketServerTransport>().addr();
+
+        let mut request = format!("ws://{addr}/").into_client_request().unwrap();
+        request
+            .headers_mut()
+            .insert(USER_DATA_HEADER, HeaderValue::from_static("not hex"));
+        let stream = TcpStream::connect(addr).unwrap();
+
+        assert!(tungstenite::client(request, stream).is_err());
+    }
+}